@@ -1,8 +1,102 @@
+use burrows_wheeler::config::{self, Options};
 use burrows_wheeler::utils;
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Read, Result as IoResult, Write};
 
 fn main() {
-    utils::launch(|output_file_path, read| {
-        let encoded = burrows_wheeler::encode(Box::new(read));
-        utils::write_iter(output_file_path, encoded)
-    });
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut options = Options {
+        workers: utils::available_workers(),
+        ..Options::default()
+    };
+
+    if let Some(block_size) = utils::take_option(&mut args, "--block-size") {
+        match block_size.parse() {
+            Ok(block_size) => options.block_size = block_size,
+            Err(err) => {
+                println!("Failed: {}", err.message);
+                return;
+            }
+        }
+    }
+
+    if let Some(stage) = utils::take_option(&mut args, "--stage") {
+        match stage.parse() {
+            Ok(stage) => options.stage = stage,
+            Err(err) => {
+                println!("Failed: {}", err.message);
+                return;
+            }
+        }
+    }
+
+    if let Some(max_code_len) = utils::take_option(&mut args, "--max-code-len") {
+        match config::parse_max_code_len(&max_code_len) {
+            Ok(max_code_len) => options.max_code_len = Some(max_code_len),
+            Err(err) => {
+                println!("Failed: {}", err.message);
+                return;
+            }
+        }
+    }
+
+    if let Some(coder) = utils::take_option(&mut args, "--coder") {
+        match coder.parse() {
+            Ok(coder) => options.coder = coder,
+            Err(err) => {
+                println!("Failed: {}", err.message);
+                return;
+            }
+        }
+    }
+
+    if let Some(streams) = utils::take_option(&mut args, "--streams") {
+        match config::parse_streams(&streams) {
+            Ok(streams) => options.streams = streams,
+            Err(err) => {
+                println!("Failed: {}", err.message);
+                return;
+            }
+        }
+    }
+
+    options.zero_runs = utils::take_flag(&mut args, "--zero-runs");
+
+    if let Err(err) = options.validate() {
+        println!("Failed: {}", err.message);
+        return;
+    }
+
+    let dot_file_path = utils::take_option(&mut args, "--dump-codes");
+
+    utils::launch_with(
+        args,
+        "[--block-size <bytes, e.g. 100K, 64M, whole, auto or auto:64K-4M>] \
+         [--stage <mtf, mtf1, mtf2, sticky[:k], wfc, if or dc>] \
+         [--max-code-len <8 to 63>] [--coder <static or adaptive>] [--zero-runs] \
+         [--streams <1 to 64>] [--dump-codes <dot file path>] ",
+        |output_file_path, mut read| {
+            if let Some(dot_file_path) = dot_file_path {
+                let mut input = Vec::new();
+                read.read_to_end(&mut input)?;
+                dump_codes(&dot_file_path, &input, options.clone())?;
+                read = Box::new(Cursor::new(input));
+            }
+            let encoded = burrows_wheeler::encode_with(read, options);
+            utils::write_iter_result(output_file_path, encoded)
+        },
+    );
+}
+
+// Every block's code tables go to the standard output, their trees to the DOT file.
+fn dump_codes(dot_file_path: &str, input: &[u8], options: Options) -> IoResult<()> {
+    let mut dot_writer = BufWriter::new(File::create(dot_file_path)?);
+    let read = Box::new(Cursor::new(input.to_vec()));
+    for (i, report) in burrows_wheeler::describe_with(read, options).enumerate() {
+        let report = report?;
+        let name = format!("block {}", i + 1);
+        print!("{}\n{}", name, report.to_table());
+        dot_writer.write_all(report.to_dot(&name).as_bytes())?;
+    }
+    dot_writer.flush()
 }
@@ -2,7 +2,7 @@ use burrows_wheeler::utils;
 
 fn main() {
     utils::launch(|output_file_path, read| {
-        let decoded = burrows_wheeler::decode(Box::new(read));
+        let decoded = burrows_wheeler::decode_parallel(Box::new(read), utils::available_workers());
         utils::write_iter_result(output_file_path, decoded)
     });
 }
@@ -0,0 +1,466 @@
+use crate::result::{Error, Result};
+use std::convert::TryInto;
+use std::io::{Read, Result as IoResult, Write};
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+// Bits are packed into a byte either from its lowest bit, as deflate does, or from its highest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitOrder {
+    LsbFirst,
+    MsbFirst,
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits == u64::BITS {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+pub struct BitWriter<W: Write> {
+    write: W,
+    order: BitOrder,
+    buffer: u64,
+    buffered: u32,
+    bytes: Vec<u8>,
+    bits_written: u64,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(write: W, order: BitOrder) -> BitWriter<W> {
+        BitWriter {
+            write,
+            order,
+            buffer: 0,
+            buffered: 0,
+            bytes: Vec::with_capacity(BUFFER_SIZE),
+            bits_written: 0,
+        }
+    }
+
+    pub fn bits_written(&self) -> u64 {
+        self.bits_written
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> IoResult<()> {
+        self.write_bits(bit as u64, 1)
+    }
+
+    // The lowest `bits` bits of `value` in the order of the stream: the lowest of them goes first
+    // when the bytes are filled from the lowest bit, the highest one otherwise.
+    pub fn write_bits(&mut self, value: u64, bits: u32) -> IoResult<()> {
+        if bits > u32::BITS {
+            let rest = bits - u32::BITS;
+            return match self.order {
+                BitOrder::LsbFirst => {
+                    self.write_bits(value, u32::BITS)?;
+                    self.write_bits(value >> u32::BITS, rest)
+                }
+                BitOrder::MsbFirst => {
+                    self.write_bits(value >> u32::BITS, rest)?;
+                    self.write_bits(value, u32::BITS)
+                }
+            };
+        }
+
+        let value = value & mask(bits);
+        match self.order {
+            BitOrder::LsbFirst => {
+                self.buffer |= value << self.buffered;
+                self.buffered += bits;
+                while self.buffered >= u8::BITS {
+                    self.bytes.push(self.buffer as u8);
+                    self.buffer >>= u8::BITS;
+                    self.buffered -= u8::BITS;
+                }
+            }
+            BitOrder::MsbFirst => {
+                self.buffer = self.buffer << bits | value;
+                self.buffered += bits;
+                while self.buffered >= u8::BITS {
+                    self.buffered -= u8::BITS;
+                    self.bytes.push((self.buffer >> self.buffered) as u8);
+                }
+                self.buffer &= mask(self.buffered);
+            }
+        }
+        self.bits_written += bits as u64;
+
+        if self.bytes.len() >= BUFFER_SIZE {
+            self.write.write_all(&self.bytes)?;
+            self.bytes.clear();
+        }
+        Ok(())
+    }
+
+    // Pads the last byte with zeros.
+    pub fn align(&mut self) -> IoResult<()> {
+        match self.buffered {
+            0 => Ok(()),
+            buffered => self.write_bits(0, u8::BITS - buffered),
+        }
+    }
+
+    pub fn flush(&mut self) -> IoResult<()> {
+        self.write.write_all(&self.bytes)?;
+        self.bytes.clear();
+        self.write.flush()
+    }
+
+    pub fn finish(mut self) -> IoResult<W> {
+        self.align()?;
+        self.flush()?;
+        Ok(self.write)
+    }
+}
+
+// Reads the bytes an iterator yields, for bit streams that are embedded in a byte iterator.
+pub struct IterRead<I>(pub I);
+
+impl<I: Iterator<Item = u8>> Read for IterRead<I> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        // the slots go first, so no byte is taken that does not fit
+        Ok(buf
+            .iter_mut()
+            .zip(&mut self.0)
+            .map(|(slot, byte)| *slot = byte)
+            .count())
+    }
+}
+
+pub struct BitReader<R: Read> {
+    read: R,
+    order: BitOrder,
+    buffer: u64,
+    buffered: u32,
+    bytes: Vec<u8>,
+    position: usize,
+    bits_read: u64,
+    // the bits left in the stream once they are known
+    limit: Option<u64>,
+    // a bounded reader never takes bytes from `read` past the ones it needs
+    bounded: bool,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(read: R, order: BitOrder) -> BitReader<R> {
+        BitReader {
+            read,
+            order,
+            buffer: 0,
+            buffered: 0,
+            bytes: Vec::new(),
+            position: 0,
+            bits_read: 0,
+            limit: None,
+            bounded: false,
+        }
+    }
+
+    // A reader for a stream that others follow in `read`: until its length is given to `limit`
+    // only the bytes holding the bits asked for are read, and after it never the ones past its
+    // last bit.
+    pub fn bounded(read: R, order: BitOrder) -> BitReader<R> {
+        BitReader {
+            bounded: true,
+            ..BitReader::new(read, order)
+        }
+    }
+
+    // The stream ends `bits` bits from here.
+    pub fn limit(&mut self, bits: u64) {
+        self.limit = Some(bits);
+    }
+
+    // Whether the limit has been reached.
+    pub fn is_empty(&self) -> bool {
+        self.limit == Some(0)
+    }
+
+    pub fn bits_read(&self) -> u64 {
+        self.bits_read
+    }
+
+    fn next_byte(&mut self, wanted: usize) -> Result<Option<u8>> {
+        if self.position == self.bytes.len() {
+            let len = match (self.bounded, self.limit) {
+                (false, _) => BUFFER_SIZE,
+                (true, None) => wanted,
+                (true, Some(limit)) => {
+                    let left = limit.saturating_sub(self.buffered as u64).div_ceil(8);
+                    left.min(BUFFER_SIZE as u64) as usize
+                }
+            };
+            if len == 0 {
+                return Ok(None);
+            }
+            self.bytes.resize(len, 0);
+            let len = loop {
+                match self.read.read(&mut self.bytes) {
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    len => break len,
+                }
+            };
+            // nothing is left buffered after a failed read
+            self.bytes.truncate(*len.as_ref().unwrap_or(&0));
+            let len = len?;
+            self.position = 0;
+            if len == 0 {
+                return Ok(None);
+            }
+        }
+
+        self.position += 1;
+        Ok(Some(self.bytes[self.position - 1]))
+    }
+
+    // Buffers at least `bits` bits as far as the input goes, and as many as fit once it runs short
+    // of them, unless the reader must not read ahead.
+    fn fill(&mut self, bits: u32) -> Result<()> {
+        if self.buffered >= bits {
+            return Ok(());
+        }
+        let target = match (self.bounded, self.limit) {
+            (true, None) => bits,
+            _ => u64::BITS - u8::BITS + 1,
+        };
+        // the bytes are taken from a whole word while `bytes` holds one
+        let count = target.saturating_sub(self.buffered).div_ceil(u8::BITS);
+        if self.order == BitOrder::LsbFirst && self.position + 8 <= self.bytes.len() {
+            let word = &self.bytes[self.position..self.position + 8];
+            let word = u64::from_le_bytes(word.try_into().unwrap());
+            self.buffer |= (word & mask(count * u8::BITS)) << self.buffered;
+            self.buffered += count * u8::BITS;
+            self.position += count as usize;
+            return Ok(());
+        }
+        while self.buffered < target {
+            let wanted = bits.saturating_sub(self.buffered).div_ceil(u8::BITS) as usize;
+            let byte = match self.next_byte(wanted)? {
+                Some(byte) => byte as u64,
+                None => break,
+            };
+            match self.order {
+                BitOrder::LsbFirst => self.buffer |= byte << self.buffered,
+                BitOrder::MsbFirst => self.buffer = self.buffer << u8::BITS | byte,
+            }
+            self.buffered += u8::BITS;
+        }
+        Ok(())
+    }
+
+    fn available(&self, bits: u32) -> u32 {
+        let available = self.buffered.min(bits);
+        match self.limit {
+            Some(limit) => available.min(limit.min(u32::MAX as u64) as u32),
+            None => available,
+        }
+    }
+
+    // Takes `bits` buffered bits.
+    fn extract(&mut self, bits: u32) -> u64 {
+        let value = match self.order {
+            BitOrder::LsbFirst => {
+                let value = self.buffer & mask(bits);
+                self.buffer = self.buffer.checked_shr(bits).unwrap_or(0);
+                value
+            }
+            BitOrder::MsbFirst => {
+                let value = self.buffer >> (self.buffered - bits);
+                self.buffer &= mask(self.buffered - bits);
+                value
+            }
+        };
+        self.buffered -= bits;
+        self.bits_read += bits as u64;
+        if let Some(limit) = self.limit.as_mut() {
+            *limit -= bits as u64;
+        }
+        value
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool> {
+        self.read_bits(1).map(|bit| bit == 1)
+    }
+
+    // The bits come back the way `BitWriter::write_bits` takes them.
+    pub fn read_bits(&mut self, bits: u32) -> Result<u64> {
+        if bits > u32::BITS {
+            let rest = bits - u32::BITS;
+            return match self.order {
+                BitOrder::LsbFirst => {
+                    let low = self.read_bits(u32::BITS)?;
+                    Ok(low | self.read_bits(rest)? << u32::BITS)
+                }
+                BitOrder::MsbFirst => {
+                    let high = self.read_bits(rest)?;
+                    Ok(high << u32::BITS | self.read_bits(u32::BITS)?)
+                }
+            };
+        }
+
+        self.fill(bits)?;
+        if self.available(bits) < bits {
+            return Err(Error::new("Unexpected end of bits"));
+        }
+        Ok(self.extract(bits))
+    }
+
+    // Up to the next `bits` bits without taking them, along with how many there are, as
+    // `read_bits` would return that many.
+    pub fn peek(&mut self, bits: u32) -> Result<(u64, u32)> {
+        assert!(bits <= u32::BITS, "Peeked too many bits");
+        self.fill(bits)?;
+        let available = self.available(bits);
+        let value = match self.order {
+            BitOrder::LsbFirst => self.buffer & mask(available),
+            BitOrder::MsbFirst => self.buffer >> (self.buffered - available),
+        };
+        Ok((value, available))
+    }
+
+    // Takes bits that `peek` has shown to be there.
+    pub fn consume(&mut self, bits: u32) {
+        assert!(bits <= self.available(bits), "Consumed bits not peeked");
+        self.extract(bits);
+    }
+
+    // Skips the rest of the current byte.
+    pub fn align(&mut self) {
+        let skipped = self.buffered % u8::BITS;
+        self.extract(skipped);
+    }
+}
+
+// Bits one by one until the input ends, or fails with the error.
+impl<R: Read> Iterator for BitReader<R> {
+    type Item = Result<bool>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.peek(1) {
+            Ok((_, 0)) => None,
+            Ok((bit, _)) => {
+                self.consume(1);
+                Some(Ok(bit == 1))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(order: BitOrder) -> Vec<u8> {
+        let mut writer = BitWriter::new(Vec::new(), order);
+        writer.write_bit(true).unwrap();
+        writer.write_bits(0b0110, 4).unwrap();
+        writer.align().unwrap();
+        writer.write_bits(0x0123_4567_89AB_CDEF, 64).unwrap();
+        writer.write_bits(0b101, 3).unwrap();
+        assert_eq!(writer.bits_written(), 8 + 64 + 3);
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn write_test() {
+        assert_eq!(
+            write(BitOrder::LsbFirst),
+            vec![
+                0b0000_1101,
+                0xEF,
+                0xCD,
+                0xAB,
+                0x89,
+                0x67,
+                0x45,
+                0x23,
+                0x01,
+                0b101
+            ]
+        );
+        assert_eq!(
+            write(BitOrder::MsbFirst),
+            vec![
+                0b1011_0000,
+                0x01,
+                0x23,
+                0x45,
+                0x67,
+                0x89,
+                0xAB,
+                0xCD,
+                0xEF,
+                0b1010_0000
+            ]
+        );
+    }
+
+    #[test]
+    fn read_written_test() {
+        for order in [BitOrder::LsbFirst, BitOrder::MsbFirst] {
+            let written = write(order);
+            let mut reader = BitReader::new(written.as_slice(), order);
+            assert_eq!(reader.read_bit(), Ok(true));
+            assert_eq!(reader.read_bits(4), Ok(0b0110));
+            reader.align();
+            assert_eq!(reader.read_bits(64), Ok(0x0123_4567_89AB_CDEF));
+            assert_eq!(reader.read_bits(3), Ok(0b101));
+            assert_eq!(reader.bits_read(), 8 + 64 + 3);
+            assert!(reader.by_ref().take(5).all(|bit| bit == Ok(false)));
+            assert_eq!(reader.next(), None);
+            assert!(reader.read_bit().is_err());
+        }
+    }
+
+    #[test]
+    fn long_stream_test() {
+        for order in [BitOrder::LsbFirst, BitOrder::MsbFirst] {
+            let mut writer = BitWriter::new(Vec::new(), order);
+            for i in 0..10000 {
+                writer.write_bits(i, 14).unwrap();
+            }
+            let written = writer.finish().unwrap();
+            assert_eq!(written.len(), (10000 * 14usize).div_ceil(8));
+
+            let mut reader = BitReader::new(written.as_slice(), order);
+            assert!((0..10000).all(|i| reader.read_bits(14) == Ok(i)));
+        }
+    }
+
+    #[test]
+    fn bounded_test() {
+        let mut input_iter = vec![0b1010_0110, 0b0000_0011, 0xFF].into_iter();
+        let mut reader = BitReader::bounded(IterRead(&mut input_iter), BitOrder::LsbFirst);
+        assert_eq!(reader.next(), Some(Ok(false)));
+        reader.limit(9);
+
+        assert_eq!(reader.peek(4), Ok((0b0011, 4)));
+        reader.consume(4);
+        assert_eq!(reader.peek(8), Ok((0b11101, 5)));
+        reader.consume(5);
+        assert!(reader.is_empty());
+        assert_eq!(reader.next(), None);
+        assert!(reader.read_bit().is_err());
+        assert_eq!(reader.bits_read(), 10);
+        assert_eq!(input_iter.next(), Some(0xFF));
+    }
+
+    struct FailingRead;
+
+    impl Read for FailingRead {
+        fn read(&mut self, _: &mut [u8]) -> IoResult<usize> {
+            Err(std::io::Error::other("Broken input"))
+        }
+    }
+
+    #[test]
+    fn read_error_test() {
+        let mut reader = BitReader::new(FailingRead, BitOrder::MsbFirst);
+        assert_eq!(reader.next(), Some(Err(Error::new("Broken input"))));
+        assert!(reader.read_bits(3).is_err());
+    }
+}
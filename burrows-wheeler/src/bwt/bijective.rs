@@ -0,0 +1,167 @@
+use crate::config::Index;
+use std::cmp::max;
+
+// Duval's algorithm, factors come out as (start, length) in non-increasing order.
+fn lyndon_factors(buffer: &[u8]) -> Vec<(usize, usize)> {
+    let len = buffer.len();
+    let mut factors = Vec::new();
+    let mut i = 0;
+
+    while i < len {
+        let mut j = i + 1;
+        let mut k = i;
+        while j < len && buffer[k] <= buffer[j] {
+            if buffer[k] < buffer[j] {
+                k = i;
+            } else {
+                k += 1;
+            }
+            j += 1;
+        }
+
+        while i <= k {
+            factors.push((i, j - k));
+            i += j - k;
+        }
+    }
+
+    factors
+}
+
+pub fn apply(buffer: &[u8]) -> Vec<u8> {
+    let len = buffer.len();
+    let factors = lyndon_factors(buffer);
+
+    let mut next = vec![0; len];
+    let mut before = vec![0; len];
+    for (start, factor_len) in factors.iter() {
+        for i in *start..start + factor_len {
+            next[i] = (i + 1 - start) % factor_len + start;
+            before[next[i]] = buffer[i];
+        }
+    }
+
+    let longest = factors.iter().map(|(_, len)| *len).max().unwrap_or(0);
+    sort_conjugates(buffer, next, longest)
+        .into_iter()
+        .map(|i| before[i as usize])
+        .collect()
+}
+
+// Prefix doubling over the rotations of every factor, compared as infinitely repeated words. By
+// the Fine-Wilf theorem two of them agreeing on twice the longest factor are equal.
+fn sort_conjugates(buffer: &[u8], mut jump: Vec<usize>, longest: usize) -> Vec<Index> {
+    let len = buffer.len();
+    let mut classes: Vec<usize> = buffer.iter().map(|x| *x as usize).collect();
+    let mut order: Vec<Index> = (0..len as Index).collect();
+    let mut buffered = vec![0; len];
+    let mut count = vec![0; max(256, len) + 1];
+
+    counting_sort(&mut order, &mut buffered, &mut count, |i| classes[i]);
+
+    let mut shift = 1;
+    while shift < 2 * longest {
+        let shifted: Vec<usize> = jump.iter().map(|j| classes[*j]).collect();
+        counting_sort(&mut order, &mut buffered, &mut count, |i| shifted[i]);
+        counting_sort(&mut order, &mut buffered, &mut count, |i| classes[i]);
+
+        let mut new_classes = vec![0; len];
+        let mut current = 0;
+        for i in 1..len {
+            let (previous, next) = (order[i - 1] as usize, order[i] as usize);
+            if (classes[previous], shifted[previous]) != (classes[next], shifted[next]) {
+                current += 1;
+            }
+            new_classes[next] = current;
+        }
+        classes = new_classes;
+
+        if current + 1 == len {
+            break;
+        }
+
+        jump = jump.iter().map(|j| jump[*j]).collect();
+        shift *= 2;
+    }
+
+    order
+}
+
+fn counting_sort<F>(order: &mut Vec<Index>, buffered: &mut Vec<Index>, count: &mut [usize], key: F)
+where
+    F: Fn(usize) -> usize,
+{
+    count.iter_mut().for_each(|x| *x = 0);
+    for i in order.iter() {
+        count[key(*i as usize) + 1] += 1;
+    }
+    for i in 1..count.len() {
+        count[i] += count[i - 1];
+    }
+    for i in order.iter() {
+        let key = key(*i as usize);
+        buffered[count[key]] = *i;
+        count[key] += 1;
+    }
+    std::mem::swap(order, buffered);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lyndon_factors_test() {
+        assert_eq!(
+            lyndon_factors("banana".as_bytes()),
+            vec![(0, 1), (1, 2), (3, 2), (5, 1)]
+        );
+        assert_eq!(lyndon_factors("aabab".as_bytes()), vec![(0, 5)]);
+        assert_eq!(lyndon_factors("abab".as_bytes()), vec![(0, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn apply_test() {
+        assert_eq!(apply("banana".as_bytes()), "annbaa".as_bytes());
+    }
+
+    fn apply_naive(buffer: &[u8]) -> Vec<u8> {
+        let factors = lyndon_factors(buffer);
+        let longest = factors.iter().map(|(_, len)| *len).max().unwrap_or(0);
+
+        let mut conjugates: Vec<Vec<u8>> = factors
+            .iter()
+            .flat_map(|(start, len)| {
+                let factor = &buffer[*start..start + len];
+                (0..*len).map(move |i| [&factor[i..], &factor[..i]].concat())
+            })
+            .collect();
+        conjugates.sort_by_key(|conjugate| {
+            conjugate
+                .iter()
+                .cycle()
+                .take(2 * longest)
+                .copied()
+                .collect::<Vec<_>>()
+        });
+
+        conjugates.iter().map(|x| *x.last().unwrap()).collect()
+    }
+
+    #[test]
+    fn apply_exhaustive_test() {
+        for len in 0..=7 {
+            for mut code in 0..3usize.pow(len) {
+                let buffer: Vec<u8> = (0..len)
+                    .map(|_| {
+                        let letter = b'a' + (code % 3) as u8;
+                        code /= 3;
+                        letter
+                    })
+                    .collect();
+                assert_eq!(apply(&buffer), apply_naive(&buffer));
+                assert_eq!(crate::bwt::reverse_bijective(&apply(&buffer)), buffer);
+            }
+        }
+    }
+}
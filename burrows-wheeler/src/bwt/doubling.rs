@@ -1,21 +1,7 @@
 use crate::config::Index;
-use std::collections::HashMap;
 use std::mem::swap;
 
-pub fn apply(buffer: &[u8]) -> (Vec<u8>, Index) {
-    let indices = sort_cyclic_shifts(buffer);
-
-    let last_symbols = indices
-        .iter()
-        .map(|i| buffer[(buffer.len() + i - 1) % buffer.len()])
-        .collect();
-
-    let initial = indices.iter().position(|x| *x == 0).unwrap();
-
-    (last_symbols, initial as Index)
-}
-
-fn sort_cyclic_shifts(buffer: &[u8]) -> Vec<usize> {
+pub fn sort_cyclic_shifts(buffer: &[u8]) -> Vec<Index> {
     let len = buffer.len();
     let alphabet = 256;
     let mut p = vec![0; len];
@@ -88,56 +74,5 @@ fn sort_cyclic_shifts(buffer: &[u8]) -> Vec<usize> {
         h += 1;
     }
 
-    p
-}
-
-pub fn reverse(buffer: &[u8], initial: Index) -> Vec<u8> {
-    let shift = shift_vector(buffer);
-    let mut output = Vec::with_capacity(buffer.len());
-    let mut current = initial;
-
-    for _ in 0..buffer.len() {
-        current = shift[current as usize];
-        output.push(buffer[current as usize]);
-    }
-
-    output
-}
-
-fn shift_vector(buffer: &[u8]) -> Vec<Index> {
-    let mut sorted = buffer.to_vec();
-    sorted.sort_unstable();
-    let mut used = HashMap::new();
-    let mut reverse = Vec::with_capacity(buffer.len());
-
-    for i in buffer {
-        let used_byte = used.entry(i).or_insert(0);
-        reverse.push(*used_byte + sorted.partition_point(|x| x < i));
-        *used_byte += 1;
-    }
-
-    let mut shift = vec![0; buffer.len()];
-    for (index, i) in reverse.iter().enumerate() {
-        shift[*i] = index as Index;
-    }
-
-    shift
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn apply_test() {
-        let string = "DRDOBBS";
-        assert_eq!(apply(string.as_bytes()), ("OBRSDDB".bytes().collect(), 3))
-    }
-
-    #[test]
-    fn reverse_test() {
-        let string = "DRDOBBS".as_bytes();
-        let (buffer, initial) = apply(string);
-        assert_eq!(reverse(&buffer, initial), string)
-    }
+    p.into_iter().map(|i| i as Index).collect()
 }
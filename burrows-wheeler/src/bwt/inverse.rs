@@ -0,0 +1,149 @@
+use crate::config::Index;
+use bit_vec::BitVec;
+
+const MAX_NARROW_LEN: usize = 1 << 24;
+
+// Each entry holds the row that follows in text order together with the byte it yields, so
+// walking a chain costs a single memory access per byte.
+trait Packed: Copy + Default {
+    fn pack(row: usize, byte: u8) -> Self;
+    fn row(self) -> usize;
+    fn byte(self) -> u8;
+}
+
+impl Packed for u32 {
+    fn pack(row: usize, byte: u8) -> Self {
+        ((row as u32) << 8) | byte as u32
+    }
+
+    fn row(self) -> usize {
+        (self >> 8) as usize
+    }
+
+    fn byte(self) -> u8 {
+        self as u8
+    }
+}
+
+impl Packed for u64 {
+    fn pack(row: usize, byte: u8) -> Self {
+        ((row as u64) << 8) | byte as u64
+    }
+
+    fn row(self) -> usize {
+        (self >> 8) as usize
+    }
+
+    fn byte(self) -> u8 {
+        self as u8
+    }
+}
+
+pub fn stream_start(len: usize, streams: usize, stream: usize) -> usize {
+    stream * len / streams
+}
+
+pub fn reverse(buffer: &[u8], initials: &[Index]) -> Vec<u8> {
+    if buffer.len() <= MAX_NARROW_LEN {
+        decode::<u32>(buffer, initials)
+    } else {
+        decode::<u64>(buffer, initials)
+    }
+}
+
+pub fn reverse_bijective(buffer: &[u8]) -> Vec<u8> {
+    if buffer.len() <= MAX_NARROW_LEN {
+        decode_bijective::<u32>(buffer)
+    } else {
+        decode_bijective::<u64>(buffer)
+    }
+}
+
+fn packed_table<P: Packed>(buffer: &[u8]) -> Vec<P> {
+    let mut count = [0usize; 256];
+    for byte in buffer {
+        count[*byte as usize] += 1;
+    }
+
+    let mut sum = 0;
+    for c in count.iter_mut() {
+        sum += *c;
+        *c = sum - *c;
+    }
+
+    let mut table = vec![P::default(); buffer.len()];
+    for (row, byte) in buffer.iter().enumerate() {
+        table[count[*byte as usize]] = P::pack(row, *byte);
+        count[*byte as usize] += 1;
+    }
+
+    table
+}
+
+fn decode<P: Packed>(buffer: &[u8], initials: &[Index]) -> Vec<u8> {
+    let len = buffer.len();
+    let streams = initials.len();
+    let table = packed_table::<P>(buffer);
+    let mut output = vec![0; len];
+
+    let starts: Vec<usize> = (0..=streams)
+        .map(|stream| stream_start(len, streams, stream))
+        .collect();
+    let mut rows: Vec<usize> = initials.iter().map(|row| *row as usize).collect();
+    let shortest = starts.windows(2).map(|x| x[1] - x[0]).min().unwrap_or(0);
+
+    for step in 0..shortest {
+        for (stream, row) in rows.iter_mut().enumerate() {
+            let entry = table[*row];
+            output[starts[stream] + step] = entry.byte();
+            *row = entry.row();
+        }
+    }
+
+    for (stream, row) in rows.iter_mut().enumerate() {
+        for byte in &mut output[starts[stream] + shortest..starts[stream + 1]] {
+            let entry = table[*row];
+            *byte = entry.byte();
+            *row = entry.row();
+        }
+    }
+
+    output
+}
+
+// Every cycle of the permutation spells one Lyndon factor, first reached from its smallest row.
+// Factors are therefore found in increasing order and have to be written out in reverse.
+fn decode_bijective<P: Packed>(buffer: &[u8]) -> Vec<u8> {
+    let len = buffer.len();
+    let table = packed_table::<P>(buffer);
+    let mut visited = BitVec::from_elem(len, false);
+    let mut factors = Vec::with_capacity(len);
+    let mut ends = Vec::new();
+
+    for first in 0..len {
+        if visited[first] {
+            continue;
+        }
+
+        let mut row = first;
+        loop {
+            visited.set(row, true);
+            let entry = table[row];
+            factors.push(entry.byte());
+            row = entry.row();
+            if row == first {
+                break;
+            }
+        }
+        ends.push(factors.len());
+    }
+
+    let mut output = Vec::with_capacity(len);
+    let mut end = len;
+    for start in ends.into_iter().rev().skip(1).chain(std::iter::once(0)) {
+        output.extend_from_slice(&factors[start..end]);
+        end = start;
+    }
+
+    output
+}
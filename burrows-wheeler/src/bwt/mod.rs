@@ -0,0 +1,172 @@
+mod bijective;
+mod doubling;
+mod inverse;
+mod sais;
+
+use crate::config::Index;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transform {
+    #[default]
+    Cyclic,
+    Bijective,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SuffixSorting {
+    PrefixDoubling,
+    #[default]
+    InducedSorting,
+}
+
+pub fn apply(buffer: &[u8]) -> (Vec<u8>, Index) {
+    apply_with(buffer, SuffixSorting::default())
+}
+
+pub fn apply_with(buffer: &[u8], suffix_sorting: SuffixSorting) -> (Vec<u8>, Index) {
+    let indices = sort_cyclic_shifts(buffer, suffix_sorting);
+    let initial = indices.iter().position(|x| *x == 0).unwrap();
+    (last_symbols(buffer, &indices), initial as Index)
+}
+
+pub fn apply_interleaved(buffer: &[u8], streams: usize) -> (Vec<u8>, Vec<Index>) {
+    let indices = sort_cyclic_shifts(buffer, SuffixSorting::default());
+    let streams = std::cmp::max(std::cmp::min(streams, buffer.len()), 1);
+
+    let starts: Vec<usize> = (0..streams)
+        .map(|stream| inverse::stream_start(buffer.len(), streams, stream))
+        .collect();
+
+    let mut initials = vec![0; streams];
+    for (row, i) in indices.iter().enumerate() {
+        if let Ok(stream) = starts.binary_search(&(*i as usize)) {
+            initials[stream] = row as Index;
+        }
+    }
+
+    (last_symbols(buffer, &indices), initials)
+}
+
+pub fn apply_bijective(buffer: &[u8]) -> Vec<u8> {
+    bijective::apply(buffer)
+}
+
+// Transforms the buffer followed by a unique end-of-text symbol that sorts before every byte.
+// The symbol itself is left out of the last column and its row is returned instead.
+pub fn apply_terminated(buffer: &[u8]) -> (Vec<u8>, Index) {
+    let mut last_symbols = Vec::with_capacity(buffer.len());
+    let mut initial = 0;
+
+    if let Some(last) = buffer.last() {
+        last_symbols.push(*last);
+    }
+    for (row, i) in sais::suffix_array(buffer).into_iter().enumerate() {
+        match i {
+            0 => initial = row + 1,
+            i => last_symbols.push(buffer[i as usize - 1]),
+        }
+    }
+
+    (last_symbols, initial as Index)
+}
+
+fn sort_cyclic_shifts(buffer: &[u8], suffix_sorting: SuffixSorting) -> Vec<Index> {
+    match suffix_sorting {
+        SuffixSorting::PrefixDoubling => doubling::sort_cyclic_shifts(buffer),
+        SuffixSorting::InducedSorting => sais::sort_cyclic_shifts(buffer),
+    }
+}
+
+fn last_symbols(buffer: &[u8], indices: &[Index]) -> Vec<u8> {
+    indices
+        .iter()
+        .map(|i| buffer[(buffer.len() + *i as usize - 1) % buffer.len()])
+        .collect()
+}
+
+pub fn reverse(buffer: &[u8], initial: Index) -> Vec<u8> {
+    inverse::reverse(buffer, &[initial])
+}
+
+pub fn reverse_interleaved(buffer: &[u8], initials: &[Index]) -> Vec<u8> {
+    inverse::reverse(buffer, initials)
+}
+
+pub fn reverse_bijective(buffer: &[u8]) -> Vec<u8> {
+    inverse::reverse_bijective(buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply_test() {
+        let string = "DRDOBBS";
+        assert_eq!(apply(string.as_bytes()), ("OBRSDDB".bytes().collect(), 3))
+    }
+
+    #[test]
+    fn apply_prefix_doubling_test() {
+        let string = "DRDOBBS";
+        assert_eq!(
+            apply_with(string.as_bytes(), SuffixSorting::PrefixDoubling),
+            ("OBRSDDB".bytes().collect(), 3)
+        )
+    }
+
+    #[test]
+    fn apply_periodic_test() {
+        for string in ["abab", "aaaa", "abcabcabc", "cabcab", "aaaaaaaaaaaaa"] {
+            let (buffer, initial) = apply(string.as_bytes());
+            assert_eq!(
+                (buffer.clone(), initial),
+                apply_with(string.as_bytes(), SuffixSorting::PrefixDoubling)
+            );
+            assert_eq!(reverse(&buffer, initial), string.as_bytes())
+        }
+    }
+
+    #[test]
+    fn apply_terminated_test() {
+        let string = "banana";
+        assert_eq!(
+            apply_terminated(string.as_bytes()),
+            ("annbaa".bytes().collect(), 4)
+        )
+    }
+
+    #[test]
+    fn reverse_test() {
+        let string = "DRDOBBS".as_bytes();
+        let (buffer, initial) = apply(string);
+        assert_eq!(reverse(&buffer, initial), string)
+    }
+
+    #[test]
+    fn reverse_bijective_test() {
+        for string in ["banana", "abab", "aaaa", "cba", "DRDOBBS", "a", ""] {
+            let bytes = string.as_bytes();
+            assert_eq!(reverse_bijective(&apply_bijective(bytes)), bytes)
+        }
+    }
+
+    #[test]
+    fn reverse_interleaved_test() {
+        let string = "mississippi river banks were flooded in the spring".as_bytes();
+        for streams in 1..=8 {
+            let (buffer, initials) = apply_interleaved(string, streams);
+            assert_eq!(initials.len(), streams);
+            assert_eq!(initials[0], apply(string).1);
+            assert_eq!(reverse_interleaved(&buffer, &initials), string)
+        }
+    }
+
+    #[test]
+    fn reverse_interleaved_short_test() {
+        let string = "ab".as_bytes();
+        let (buffer, initials) = apply_interleaved(string, 4);
+        assert_eq!(initials.len(), 2);
+        assert_eq!(reverse_interleaved(&buffer, &initials), string)
+    }
+}
@@ -0,0 +1,335 @@
+use crate::config::Index;
+use bit_vec::BitVec;
+
+const EMPTY: Index = Index::MAX;
+
+// Rotations of a Lyndon word are ordered exactly like its suffixes, so the block is rotated to its
+// least rotation and suffix sorted. A periodic block is a power of its primitive root, whose least
+// rotation is a Lyndon word: its rotations are sorted the same way and every one of them stands
+// for the equal rotations of the block one period apart. Those are taken in the order prefix
+// doubling leaves them in, so both backends agree on the primary index: it shifts every rotation
+// back by one less than the next power of two of the length and keeps equal rotations in
+// descending order of where they started.
+pub fn sort_cyclic_shifts(buffer: &[u8]) -> Vec<Index> {
+    let len = buffer.len();
+    if len == 0 {
+        return Vec::new();
+    }
+    let (start, period) = least_rotation(buffer);
+    let start = start % period;
+    let copies = len / period;
+
+    let rotated;
+    let root = if start == 0 {
+        &buffer[..period]
+    } else {
+        rotated = [&buffer[start..period], &buffer[..start]].concat();
+        &rotated[..]
+    };
+    let mut indices = vec![EMPTY; len];
+    sais(root, &mut indices[..period], u8::MAX as usize + 1);
+
+    // Rows are expanded from the last one, so each writes over rows it has already read.
+    let shift = (len.next_power_of_two() - 1) % len;
+    for row in (0..period).rev() {
+        let first = (indices[row] as usize + start) % period;
+        let residue = (first + shift) % period;
+        let equal = &mut indices[row * copies..(row + 1) * copies];
+        for (copy, index) in equal.iter_mut().rev().enumerate() {
+            *index = ((residue + copy * period + len - shift) % len) as Index;
+        }
+    }
+
+    indices
+}
+
+fn least_rotation(buffer: &[u8]) -> (usize, usize) {
+    let len = buffer.len();
+    let mut start = 0;
+    let mut period = len;
+    let mut i = 0;
+
+    while i < len {
+        start = i;
+        let mut j = i + 1;
+        let mut k = i;
+
+        while j < 2 * len && buffer[k % len] <= buffer[j % len] {
+            if buffer[k % len] < buffer[j % len] {
+                k = i;
+            } else {
+                k += 1;
+            }
+            j += 1;
+        }
+
+        period = j - k;
+        while i <= k {
+            i += j - k;
+        }
+    }
+
+    (start, period)
+}
+
+pub fn suffix_array(text: &[u8]) -> Vec<Index> {
+    let mut suffixes = vec![EMPTY; text.len()];
+    sais(text, &mut suffixes, u8::MAX as usize + 1);
+    suffixes
+}
+
+fn sais<T: Copy + Into<Index>>(text: &[T], suffixes: &mut [Index], alphabet: usize) {
+    let len = text.len();
+    match len {
+        0 => return,
+        1 => {
+            suffixes[0] = 0;
+            return;
+        }
+        _ => {}
+    }
+
+    let symbol = |i: usize| text[i].into() as usize;
+    let types = classify(text);
+    let is_lms = |i: usize| i > 0 && types[i] && !types[i - 1];
+
+    let mut buckets = vec![0; alphabet];
+    for i in 0..len {
+        buckets[symbol(i)] += 1;
+    }
+
+    suffixes.fill(EMPTY);
+    let mut tails = bucket_tails(&buckets);
+    for i in (1..len).rev() {
+        if is_lms(i) {
+            tails[symbol(i)] -= 1;
+            suffixes[tails[symbol(i)]] = i as Index;
+        }
+    }
+    induce(text, suffixes, &types, &buckets);
+
+    let mut lms_count = 0;
+    for i in 0..len {
+        let position = suffixes[i] as usize;
+        if is_lms(position) {
+            suffixes[lms_count] = position as Index;
+            lms_count += 1;
+        }
+    }
+
+    suffixes[lms_count..].fill(EMPTY);
+    let mut names = 0;
+    let mut previous = None;
+    for i in 0..lms_count {
+        let position = suffixes[i] as usize;
+        if previous.is_none_or(|previous| !lms_substrings_equal(text, &types, previous, position)) {
+            names += 1;
+        }
+        previous = Some(position);
+        suffixes[lms_count + position / 2] = (names - 1) as Index;
+    }
+
+    let mut j = len;
+    for i in (lms_count..len).rev() {
+        if suffixes[i] != EMPTY {
+            j -= 1;
+            suffixes[j] = suffixes[i];
+        }
+    }
+
+    let (head, tail) = suffixes.split_at_mut(len - lms_count);
+    let reduced_suffixes = &mut head[..lms_count];
+    if names < lms_count {
+        sais(&*tail, reduced_suffixes, names);
+    } else {
+        for (i, name) in tail.iter().enumerate() {
+            reduced_suffixes[*name as usize] = i as Index;
+        }
+    }
+
+    let mut j = 0;
+    for i in 1..len {
+        if is_lms(i) {
+            tail[j] = i as Index;
+            j += 1;
+        }
+    }
+    for suffix in reduced_suffixes.iter_mut() {
+        *suffix = tail[*suffix as usize];
+    }
+
+    suffixes[lms_count..].fill(EMPTY);
+    let mut tails = bucket_tails(&buckets);
+    for i in (0..lms_count).rev() {
+        let position = suffixes[i];
+        suffixes[i] = EMPTY;
+        tails[symbol(position as usize)] -= 1;
+        suffixes[tails[symbol(position as usize)]] = position;
+    }
+    induce(text, suffixes, &types, &buckets);
+}
+
+// S-type positions are set, L-type ones are not. The virtual sentinel past the end is the
+// smallest symbol, which makes the last position L-type.
+fn classify<T: Copy + Into<Index>>(text: &[T]) -> BitVec {
+    let len = text.len();
+    let mut types = BitVec::from_elem(len, false);
+
+    for i in (0..len - 1).rev() {
+        let (current, next) = (text[i].into(), text[i + 1].into());
+        types.set(i, current < next || (current == next && types[i + 1]));
+    }
+
+    types
+}
+
+fn lms_substrings_equal<T: Copy + Into<Index>>(
+    text: &[T],
+    types: &BitVec,
+    first: usize,
+    second: usize,
+) -> bool {
+    let is_lms = |i: usize| i > 0 && types[i] && !types[i - 1];
+
+    for shift in 0.. {
+        let (first, second) = (first + shift, second + shift);
+        if first == text.len() || second == text.len() {
+            return false;
+        }
+        if text[first].into() != text[second].into() || types[first] != types[second] {
+            return false;
+        }
+        if shift > 0 && (is_lms(first) || is_lms(second)) {
+            return is_lms(first) && is_lms(second);
+        }
+    }
+
+    unreachable!()
+}
+
+fn bucket_heads(buckets: &[usize]) -> Vec<usize> {
+    let mut sum = 0;
+    buckets
+        .iter()
+        .map(|size| {
+            sum += size;
+            sum - size
+        })
+        .collect()
+}
+
+fn bucket_tails(buckets: &[usize]) -> Vec<usize> {
+    let mut sum = 0;
+    buckets
+        .iter()
+        .map(|size| {
+            sum += size;
+            sum
+        })
+        .collect()
+}
+
+fn induce<T: Copy + Into<Index>>(
+    text: &[T],
+    suffixes: &mut [Index],
+    types: &BitVec,
+    buckets: &[usize],
+) {
+    let len = text.len();
+    let symbol = |i: usize| text[i].into() as usize;
+
+    let mut heads = bucket_heads(buckets);
+    suffixes[heads[symbol(len - 1)]] = (len - 1) as Index;
+    heads[symbol(len - 1)] += 1;
+
+    for i in 0..len {
+        let position = suffixes[i] as usize;
+        if suffixes[i] != EMPTY && position > 0 && !types[position - 1] {
+            suffixes[heads[symbol(position - 1)]] = (position - 1) as Index;
+            heads[symbol(position - 1)] += 1;
+        }
+    }
+
+    let mut tails = bucket_tails(buckets);
+    for i in (0..len).rev() {
+        let position = suffixes[i] as usize;
+        if suffixes[i] != EMPTY && position > 0 && types[position - 1] {
+            tails[symbol(position - 1)] -= 1;
+            suffixes[tails[symbol(position - 1)]] = (position - 1) as Index;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bwt::doubling;
+
+    fn pseudo_random(len: usize, alphabet: u8, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                ((state >> 33) % alphabet as u64) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn suffix_array_test() {
+        let text = "mmiissiissiippii".as_bytes();
+        let mut expected: Vec<Index> = (0..text.len() as Index).collect();
+        expected.sort_by_key(|i| &text[*i as usize..]);
+        assert_eq!(suffix_array(text), expected);
+    }
+
+    #[test]
+    fn suffix_array_random_test() {
+        for (seed, alphabet) in [(1, 2), (2, 3), (3, 4), (4, 255)] {
+            let text = pseudo_random(2000, alphabet, seed);
+            let mut expected: Vec<Index> = (0..text.len() as Index).collect();
+            expected.sort_by_key(|i| &text[*i as usize..]);
+            assert_eq!(suffix_array(&text), expected);
+        }
+    }
+
+    #[test]
+    fn least_rotation_test() {
+        assert_eq!(least_rotation("DRDOBBS".as_bytes()), (4, 7));
+        assert_eq!(least_rotation("babab".as_bytes()), (1, 5));
+        assert_eq!(least_rotation("bcabca".as_bytes()).1, 3);
+        assert_eq!(least_rotation("aaaa".as_bytes()).1, 1);
+    }
+
+    #[test]
+    fn same_as_doubling_test() {
+        for (seed, alphabet) in [(5, 2), (6, 3), (7, 26)] {
+            for len in [1, 2, 3, 10, 100, 1000] {
+                let buffer = pseudo_random(len, alphabet, seed);
+                assert_eq!(
+                    sort_cyclic_shifts(&buffer),
+                    doubling::sort_cyclic_shifts(&buffer)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn periodic_test() {
+        let root = pseudo_random(7, 3, 8);
+        for buffer in [
+            vec![0; 1000],
+            b"abc".repeat(333),
+            root.repeat(50),
+            b"ab".repeat(8),
+        ] {
+            let mut indices = sort_cyclic_shifts(&buffer);
+            assert_eq!(indices, doubling::sort_cyclic_shifts(&buffer));
+            indices.sort_unstable();
+            assert!(indices.iter().enumerate().all(|(i, x)| i == *x as usize));
+        }
+        assert_eq!(sort_cyclic_shifts(b"abab"), vec![0, 2, 3, 1]);
+    }
+}
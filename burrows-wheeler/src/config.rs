@@ -1,2 +1,279 @@
+use crate::bwt::Transform;
+use crate::gst::Stage;
+use crate::result::Error;
+use crate::zero_runs;
+use std::str::FromStr;
+
 pub const WINDOW_SIZE: usize = 1024 * 1024;
+pub const MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+pub const MAX_CODE_LEN: u8 = 63;
+pub const MAX_STREAMS: usize = 64;
+const MIN_ZERO_RUNS_CODE_LEN: u8 = zero_runs::ALPHABET_SIZE
+    .next_power_of_two()
+    .trailing_zeros() as u8;
 pub type Index = u32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockSize {
+    Fixed(usize),
+    WholeInput,
+    Adaptive { min: usize, max: usize },
+}
+
+impl Default for BlockSize {
+    fn default() -> Self {
+        BlockSize::Fixed(WINDOW_SIZE)
+    }
+}
+
+impl FromStr for BlockSize {
+    type Err = Error;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        if str == "whole" {
+            return Ok(BlockSize::WholeInput);
+        }
+        if str == "auto" {
+            return Ok(BlockSize::Adaptive {
+                min: WINDOW_SIZE / 8,
+                max: 4 * WINDOW_SIZE,
+            });
+        }
+
+        match str.strip_prefix("auto:") {
+            Some(bounds) => match bounds.split_once('-') {
+                Some((min, max)) => match (parse_size(min)?, parse_size(max)?) {
+                    (min, max) if min <= max => Ok(BlockSize::Adaptive { min, max }),
+                    _ => Err(Error::new("Minimum block size exceeds maximum")),
+                },
+                None => Err(Error::new("Invalid block size")),
+            },
+            None => parse_size(str).map(BlockSize::Fixed),
+        }
+    }
+}
+
+impl BlockSize {
+    // Sizes built by hand rather than parsed may be out of range.
+    pub fn validate(&self) -> Result<(), Error> {
+        match *self {
+            BlockSize::Fixed(size) if size == 0 || size > MAX_BLOCK_SIZE => {
+                Err(Error::new("Block size out of range"))
+            }
+            BlockSize::Adaptive { min, max } if min == 0 || max > MAX_BLOCK_SIZE => {
+                Err(Error::new("Block size out of range"))
+            }
+            BlockSize::Adaptive { min, max } if min > max => {
+                Err(Error::new("Minimum block size exceeds maximum"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn parse_size(str: &str) -> Result<usize, Error> {
+    let (digits, multiplier) = match str.chars().last() {
+        Some('k') | Some('K') => (&str[..str.len() - 1], 1024),
+        Some('m') | Some('M') => (&str[..str.len() - 1], 1024 * 1024),
+        _ => (str, 1),
+    };
+
+    match digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|x| x.checked_mul(multiplier))
+    {
+        Some(size) if size > 0 && size <= MAX_BLOCK_SIZE => Ok(size),
+        Some(_) => Err(Error::new("Block size out of range")),
+        None => Err(Error::new("Invalid block size")),
+    }
+}
+
+pub fn parse_streams(str: &str) -> Result<usize, Error> {
+    match str.parse::<usize>() {
+        Ok(streams) if (1..=MAX_STREAMS).contains(&streams) => Ok(streams),
+        Ok(_) => Err(Error::new("Stream count out of range")),
+        Err(_) => Err(Error::new("Invalid stream count")),
+    }
+}
+
+// The limit has to leave room for every byte value.
+pub fn parse_max_code_len(str: &str) -> Result<u8, Error> {
+    match str.parse::<u8>() {
+        Ok(len) if (8..=MAX_CODE_LEN).contains(&len) => Ok(len),
+        Ok(_) => Err(Error::new("Code length limit out of range")),
+        Err(_) => Err(Error::new("Invalid code length limit")),
+    }
+}
+
+// The static coder fits code tables to each block, the adaptive one learns the codes as it goes in
+// a single pass.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Coder {
+    #[default]
+    Static,
+    Adaptive,
+}
+
+impl FromStr for Coder {
+    type Err = Error;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "static" => Ok(Coder::Static),
+            "adaptive" => Ok(Coder::Adaptive),
+            _ => Err(Error::new("Unknown coder")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    pub transform: Transform,
+    pub block_size: BlockSize,
+    pub stage: Stage,
+    pub max_code_len: Option<u8>,
+    pub coder: Coder,
+    pub zero_runs: bool,
+    // the number of starting points blocks are decoded from at once
+    pub streams: usize,
+    pub workers: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            transform: Transform::default(),
+            block_size: BlockSize::default(),
+            stage: Stage::default(),
+            max_code_len: None,
+            coder: Coder::default(),
+            zero_runs: false,
+            streams: 1,
+            workers: 1,
+        }
+    }
+}
+
+impl Options {
+    pub fn validate(&self) -> Result<(), Error> {
+        self.block_size.validate()?;
+        if self.streams == 0 || self.streams > MAX_STREAMS {
+            return Err(Error::new("Stream count out of range"));
+        }
+        if self.zero_runs && self.coder != Coder::Static {
+            return Err(Error::new("Zero runs need the static coder"));
+        }
+        // every symbol of the zero run alphabet needs a code within the limit
+        if self.zero_runs
+            && self
+                .max_code_len
+                .is_some_and(|len| len < MIN_ZERO_RUNS_CODE_LEN)
+        {
+            return Err(Error::new("Code length limit too small for the alphabet"));
+        }
+        // the adaptive coder's codes grow as it goes
+        if self.max_code_len.is_some() && self.coder == Coder::Adaptive {
+            return Err(Error::new("The adaptive coder takes no code length limit"));
+        }
+        // the bijective transform has no rows to start from
+        if self.streams > 1 && self.transform != Transform::Cyclic {
+            return Err(Error::new("Interleaved streams need the cyclic transform"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn block_size_from_str_test() {
+        assert_eq!("whole".parse(), Ok(BlockSize::WholeInput));
+        assert_eq!("900".parse(), Ok(BlockSize::Fixed(900)));
+        assert_eq!("100K".parse(), Ok(BlockSize::Fixed(100 * 1024)));
+        assert_eq!("64m".parse(), Ok(BlockSize::Fixed(MAX_BLOCK_SIZE)));
+        assert!("65M".parse::<BlockSize>().is_err());
+        assert!("0".parse::<BlockSize>().is_err());
+        assert!("1G".parse::<BlockSize>().is_err());
+        assert!("".parse::<BlockSize>().is_err());
+    }
+
+    #[test]
+    fn adaptive_block_size_from_str_test() {
+        assert_eq!(
+            "auto".parse(),
+            Ok(BlockSize::Adaptive {
+                min: 128 * 1024,
+                max: 4 * 1024 * 1024
+            })
+        );
+        assert_eq!(
+            "auto:64K-2M".parse(),
+            Ok(BlockSize::Adaptive {
+                min: 64 * 1024,
+                max: 2 * 1024 * 1024
+            })
+        );
+        assert!("auto:2M-64K".parse::<BlockSize>().is_err());
+        assert!("auto:64K".parse::<BlockSize>().is_err());
+        assert!("auto:0-1M".parse::<BlockSize>().is_err());
+    }
+
+    #[test]
+    fn block_size_validate_test() {
+        assert!(BlockSize::default().validate().is_ok());
+        assert!(BlockSize::WholeInput.validate().is_ok());
+        assert!(BlockSize::Fixed(0).validate().is_err());
+        assert!(BlockSize::Fixed(MAX_BLOCK_SIZE + 1).validate().is_err());
+        assert!(BlockSize::Adaptive { min: 0, max: 10 }.validate().is_err());
+        assert!(BlockSize::Adaptive { min: 10, max: 5 }.validate().is_err());
+    }
+
+    #[test]
+    fn parse_max_code_len_test() {
+        assert_eq!(parse_max_code_len("15"), Ok(15));
+        assert_eq!(parse_max_code_len("63"), Ok(63));
+        assert!(parse_max_code_len("7").is_err());
+        assert!(parse_max_code_len("64").is_err());
+        assert!(parse_max_code_len("x").is_err());
+    }
+
+    #[test]
+    fn coder_from_str_test() {
+        assert_eq!("static".parse(), Ok(Coder::Static));
+        assert_eq!("adaptive".parse(), Ok(Coder::Adaptive));
+        assert!("vitter".parse::<Coder>().is_err());
+    }
+
+    #[test]
+    fn options_validate_test() {
+        assert!(Options::default().validate().is_ok());
+        let options = Options {
+            coder: Coder::Adaptive,
+            max_code_len: Some(12),
+            ..Options::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = Options {
+            zero_runs: true,
+            max_code_len: Some(8),
+            ..Options::default()
+        };
+        assert!(options.validate().is_err());
+        assert_eq!(MIN_ZERO_RUNS_CODE_LEN, 9);
+        let options = Options {
+            max_code_len: Some(9),
+            ..options
+        };
+        assert!(options.validate().is_ok());
+        let options = Options {
+            coder: Coder::Adaptive,
+            max_code_len: None,
+            ..options
+        };
+        assert!(options.validate().is_err());
+    }
+}
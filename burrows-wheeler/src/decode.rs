@@ -1,21 +1,225 @@
-use crate::result::Result;
-use crate::{bwt, huffman, mtf};
-use std::io::Read;
+use crate::bwt::{self, Transform};
+use crate::config::{BlockSize, Coder, Index, Options, MAX_BLOCK_SIZE};
+use crate::parallel::ParallelMap;
+use crate::result::{Error, Result};
+use crate::{gst, huffman, stream, zero_runs};
+use std::io::{BufReader, Read};
 use std::iter::once;
 
 pub fn decode(read: Box<dyn Read>) -> Box<dyn Iterator<Item = Result<u8>>> {
-    let input_iter = read.bytes().take_while(|x| x.is_ok()).map(|x| x.unwrap());
-    let input_iter = Box::new(input_iter);
-    Box::new(DecodeIterator::new(input_iter).flatten())
+    let mut input_iter = byte_iter(read);
+    match stream::decode_header(&mut input_iter) {
+        Ok(options) => Box::new(DecodeIterator::new(input_iter, options).flatten()),
+        Err(err) => Box::new(once(Err(err))),
+    }
+}
+
+pub fn decode_parallel(
+    read: Box<dyn Read>,
+    workers: usize,
+) -> Box<dyn Iterator<Item = Result<u8>>> {
+    let mut input_iter = byte_iter(read);
+    let options = match stream::decode_header(&mut input_iter) {
+        Ok(options) => options,
+        Err(err) => return Box::new(once(Err(err))),
+    };
+
+    let with_initial = options.transform == Transform::Cyclic;
+    let with_length = matches!(options.block_size, BlockSize::Adaptive { .. });
+    let with_starts = options.streams > 1;
+    let coder = options.coder;
+    let alphabet_size = if options.zero_runs {
+        zero_runs::ALPHABET_SIZE
+    } else {
+        256
+    };
+    let blocks = std::iter::from_fn(move || {
+        let length: Vec<u8> = if with_length {
+            (&mut input_iter).take(4).collect()
+        } else {
+            Vec::new()
+        };
+        let starts: Vec<u8> = match with_starts.then(|| input_iter.next()).flatten() {
+            Some(count) => once(count)
+                .chain((&mut input_iter).take(4 * count as usize))
+                .collect(),
+            None => Vec::new(),
+        };
+        let block = match coder {
+            Coder::Static => {
+                huffman::read_symbols_block(&mut input_iter, with_initial, alphabet_size)
+            }
+            Coder::Adaptive => huffman::adaptive::read_block(&mut input_iter, with_initial),
+        };
+        match block {
+            Some(block) => Some([length, starts, block].concat()),
+            None if length.is_empty() && starts.is_empty() => None,
+            None => Some([length, starts].concat()),
+        }
+    });
+
+    let iter = ParallelMap::new(blocks, workers, move |block: Vec<u8>| {
+        let mut block_iter: Box<dyn Iterator<Item = u8>> = Box::new(block.into_iter());
+        decode_block(&mut block_iter, &options)
+            .unwrap_or_else(|| Err(Error::new("Unexpected end of block")))
+    })
+    .flat_map(|block| flatten_block(block.and_then(|block| block)));
+    Box::new(iter)
+}
+
+fn byte_iter(read: Box<dyn Read>) -> Box<dyn Iterator<Item = u8>> {
+    let input_iter = BufReader::new(read)
+        .bytes()
+        .take_while(|x| x.is_ok())
+        .map(|x| x.unwrap());
+    Box::new(input_iter)
+}
+
+fn decode_block(
+    input_iter: &mut Box<dyn Iterator<Item = u8>>,
+    options: &Options,
+) -> Option<Result<Vec<u8>>> {
+    let with_initial = options.transform == Transform::Cyclic;
+    let (block_size, length) = match options.block_size {
+        BlockSize::Fixed(block_size) => (block_size, None),
+        BlockSize::WholeInput => (MAX_BLOCK_SIZE, None),
+        BlockSize::Adaptive { max, .. } => {
+            match stream::decode_block_length(input_iter.as_mut())? {
+                Ok(length) if length <= max => (length, Some(length)),
+                Ok(_) => return Some(Err(Error::new("Block exceeds block size"))),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    };
+
+    let starts = if options.streams > 1 {
+        match stream::decode_starts(input_iter.as_mut()) {
+            Some(Ok(starts)) if starts.len() < options.streams => starts,
+            Some(Ok(_)) => return Some(Err(Error::new("Too many block starts"))),
+            Some(Err(err)) => return Some(Err(err)),
+            None if length.is_some() => return Some(Err(Error::new("Unexpected end of block"))),
+            None => return None,
+        }
+    } else {
+        Vec::new()
+    };
+
+    match options.coder {
+        Coder::Static if options.zero_runs => {
+            let decoded =
+                huffman::decode_symbols(input_iter, with_initial, zero_runs::ALPHABET_SIZE);
+            let reversed = |(iter, initial)| (zero_runs::reverse(iter), initial);
+            finish_block(
+                decoded.map(|decoded| decoded.map(reversed)),
+                &starts,
+                options,
+                block_size,
+                length,
+            )
+        }
+        Coder::Static => finish_block(
+            huffman::decode(input_iter, with_initial),
+            &starts,
+            options,
+            block_size,
+            length,
+        ),
+        Coder::Adaptive => finish_block(
+            huffman::adaptive::decode(input_iter.as_mut(), with_initial),
+            &starts,
+            options,
+            block_size,
+            length,
+        ),
+    }
+}
+
+// Takes the coder's own iterator rather than a boxed one, so that the symbols of the block are
+// read without a virtual call each.
+fn finish_block<I: Iterator<Item = Result<u8>>>(
+    decoded: Option<Result<(I, Option<Index>)>>,
+    starts: &[Index],
+    options: &Options,
+    block_size: usize,
+    length: Option<usize>,
+) -> Option<Result<Vec<u8>>> {
+    let decoded = match decoded {
+        Some(decoded) => decoded,
+        None if length.is_some() || !starts.is_empty() => {
+            return Some(Err(Error::new("Unexpected end of block")))
+        }
+        None => return None,
+    };
+
+    Some(decoded.and_then(|(iter, initial)| {
+        let initials = initial.map(|initial| [&[initial], starts].concat());
+        reverse_block(iter, initials, &options.stage, block_size, length)
+    }))
+}
+
+fn reverse_block(
+    iter: impl Iterator<Item = Result<u8>>,
+    initials: Option<Vec<Index>>,
+    stage: &gst::Stage,
+    block_size: usize,
+    length: Option<usize>,
+) -> Result<Vec<u8>> {
+    let vec = collect_block(iter, gst::bound(stage, block_size), block_size)?;
+    let reversed = gst::reverse(&vec, stage)?;
+    if reversed.len() > block_size {
+        return Err(Error::new("Block exceeds block size"));
+    }
+    if length.is_some_and(|length| length != reversed.len()) {
+        return Err(Error::new("Block length mismatch"));
+    }
+
+    Ok(match initials {
+        Some(initials) => {
+            if initials.iter().any(|row| *row as usize >= reversed.len()) {
+                return Err(Error::new("Start row out of block"));
+            }
+            bwt::reverse_interleaved(&reversed, &initials)
+        }
+        None => bwt::reverse_bijective(&reversed),
+    })
+}
+
+// Kept out of line so that the coder's `next` is inlined into the loop, which runs once for
+// every symbol of the block.
+#[inline(never)]
+fn collect_block(
+    iter: impl Iterator<Item = Result<u8>>,
+    limit: usize,
+    block_size: usize,
+) -> Result<Vec<u8>> {
+    let mut vec = Vec::with_capacity(block_size);
+    for byte in iter {
+        if vec.len() == limit {
+            return Err(Error::new("Block exceeds block size"));
+        }
+        vec.push(byte?);
+    }
+    Ok(vec)
+}
+
+pub(crate) fn flatten_block(block: Result<Vec<u8>>) -> Box<dyn Iterator<Item = Result<u8>>> {
+    match block {
+        Ok(block) => Box::new(block.into_iter().map(Ok)),
+        Err(err) => Box::new(once(Err(err))),
+    }
 }
 
 struct DecodeIterator {
     input_iter: Box<dyn Iterator<Item = u8>>,
+    options: Options,
 }
 
 impl DecodeIterator {
-    fn new(input_iter: Box<dyn Iterator<Item = u8>>) -> DecodeIterator {
-        DecodeIterator { input_iter }
+    fn new(input_iter: Box<dyn Iterator<Item = u8>>, options: Options) -> DecodeIterator {
+        DecodeIterator {
+            input_iter,
+            options,
+        }
     }
 }
 
@@ -23,22 +227,7 @@ impl Iterator for DecodeIterator {
     type Item = Box<dyn Iterator<Item = Result<u8>>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match huffman::decode(&mut self.input_iter) {
-            None => None,
-            Some(iter) => match iter {
-                Ok((iter, initial)) => {
-                    let vec = match iter.collect::<Result<Vec<u8>>>() {
-                        Ok(vec) => vec,
-                        Err(err) => return Some(Box::new(once(Err(err)))),
-                    };
-
-                    let demtfed = mtf::reverse(&vec);
-                    let debwted = bwt::reverse(&demtfed, initial);
-                    Some(Box::new(debwted.into_iter().map(Ok)))
-                }
-                Err(err) => Some(Box::new(once(Err(err)))),
-            },
-        }
+        decode_block(&mut self.input_iter, &self.options).map(flatten_block)
     }
 }
 
@@ -47,13 +236,15 @@ mod test {
     use std::io::Cursor;
 
     use super::*;
-    use crate::encode;
+    use crate::{encode, encode_parallel, encode_with};
 
     #[test]
     fn decode_encoded() {
         let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
 
-        let encoded = encode(Box::new(string)).collect::<Vec<_>>();
+        let encoded = encode(Box::new(string))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
         let encoded = Cursor::new(encoded);
         let decoded = decode(Box::new(encoded))
             .collect::<Result<Vec<_>>>()
@@ -61,4 +252,338 @@ mod test {
 
         assert_eq!(&decoded, string)
     }
+
+    #[test]
+    fn decode_parallel_encoded() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+
+        let encoded = encode_parallel(Box::new(string), 4)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let encoded = Cursor::new(encoded);
+        let decoded = decode_parallel(Box::new(encoded), 4)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(&decoded, string)
+    }
+
+    #[test]
+    fn decode_bijective_encoded() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        let options = Options {
+            transform: Transform::Bijective,
+            ..Options::default()
+        };
+
+        let encoded = encode_with(Box::new(string), options)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let encoded = Cursor::new(encoded);
+        let decoded = decode(Box::new(encoded))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(&decoded, string)
+    }
+
+    #[test]
+    fn decode_small_blocks_encoded() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        for workers in [1, 3] {
+            let options = Options {
+                block_size: BlockSize::Fixed(7),
+                workers,
+                ..Options::default()
+            };
+
+            let encoded = encode_with(Box::new(string), options)
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            let decoded = decode_parallel(Box::new(Cursor::new(encoded)), workers)
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+
+            assert_eq!(&decoded, string)
+        }
+    }
+
+    #[test]
+    fn decode_whole_input_encoded() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        let options = Options {
+            block_size: BlockSize::WholeInput,
+            ..Options::default()
+        };
+
+        let encoded = encode_with(Box::new(string), options)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(&encoded[5..9], &(string.len() as u32).to_be_bytes());
+
+        let decoded = decode(Box::new(Cursor::new(encoded)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(&decoded, string)
+    }
+
+    #[test]
+    fn decode_adaptive_encoded() {
+        let text = "it was the best of times, it was the worst of times. ".bytes();
+        let mut state: u32 = 7;
+        let string: Vec<u8> = text
+            .cycle()
+            .take(6000)
+            .chain((0..3000).map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 24) as u8
+            }))
+            .collect();
+
+        for workers in [1, 3] {
+            let options = Options {
+                block_size: BlockSize::Adaptive {
+                    min: 1024,
+                    max: 4096,
+                },
+                workers,
+                ..Options::default()
+            };
+
+            let encoded = encode_with(Box::new(Cursor::new(string.clone())), options)
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            let decoded = decode_parallel(Box::new(Cursor::new(encoded.clone())), workers)
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(decoded, string);
+
+            let decoded = decode(Box::new(Cursor::new(encoded)))
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(decoded, string);
+        }
+    }
+
+    #[test]
+    fn decode_adaptive_coder_encoded() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        for transform in [Transform::Cyclic, Transform::Bijective] {
+            for workers in [1, 3] {
+                let options = Options {
+                    transform,
+                    block_size: BlockSize::Fixed(20),
+                    coder: Coder::Adaptive,
+                    workers,
+                    ..Options::default()
+                };
+
+                let encoded = encode_with(Box::new(string), options)
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap();
+                let decoded = decode_parallel(Box::new(Cursor::new(encoded.clone())), workers)
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap();
+                assert_eq!(&decoded, string);
+
+                let decoded = decode(Box::new(Cursor::new(encoded)))
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap();
+                assert_eq!(&decoded, string);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_zero_runs_encoded() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        for transform in [Transform::Cyclic, Transform::Bijective] {
+            for workers in [1, 3] {
+                let options = Options {
+                    transform,
+                    block_size: BlockSize::Fixed(20),
+                    zero_runs: true,
+                    workers,
+                    ..Options::default()
+                };
+
+                let encoded = encode_with(Box::new(string), options)
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap();
+                let decoded = decode_parallel(Box::new(Cursor::new(encoded.clone())), workers)
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap();
+                assert_eq!(&decoded, string);
+
+                let decoded = decode(Box::new(Cursor::new(encoded)))
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap();
+                assert_eq!(&decoded, string);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_adaptive_length_mismatch() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        let options = Options {
+            block_size: BlockSize::Adaptive { min: 16, max: 64 },
+            ..Options::default()
+        };
+
+        let mut encoded = encode_with(Box::new(string), options)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        encoded[13..17].copy_from_slice(&50u32.to_be_bytes());
+
+        let decoded = decode(Box::new(Cursor::new(encoded))).collect::<Result<Vec<_>>>();
+        assert!(decoded.is_err())
+    }
+
+    #[test]
+    fn decode_stages_encoded() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        for stage in ["wfc", "if", "dc"] {
+            for workers in [1, 3] {
+                let options = Options {
+                    block_size: BlockSize::Fixed(20),
+                    stage: stage.parse().unwrap(),
+                    workers,
+                    ..Options::default()
+                };
+
+                let encoded = encode_with(Box::new(string), options)
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap();
+                let decoded = decode_parallel(Box::new(Cursor::new(encoded)), workers)
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap();
+                assert_eq!(&decoded, string)
+            }
+        }
+    }
+
+    #[test]
+    fn decode_oversized_block() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        let mut encoded = encode(Box::new(string))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        encoded[5..9].copy_from_slice(&16u32.to_be_bytes());
+
+        let decoded = decode(Box::new(Cursor::new(encoded))).collect::<Result<Vec<_>>>();
+        assert!(decoded.is_err())
+    }
+
+    #[test]
+    fn decode_unknown_format() {
+        let decoded = decode(Box::new("not a stream".as_bytes())).collect::<Result<Vec<_>>>();
+        assert!(decoded.is_err())
+    }
+
+    // Gives the first bytes of the string, then fails.
+    struct FailingRead(&'static [u8]);
+
+    impl Read for FailingRead {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.len().min(buf.len()) {
+                0 => Err(std::io::Error::other("disk on fire")),
+                len => {
+                    buf[..len].copy_from_slice(&self.0[..len]);
+                    self.0 = &self.0[len..];
+                    Ok(len)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_read_error() {
+        for block_size in [BlockSize::WholeInput, BlockSize::Fixed(20)] {
+            for workers in [1, 3] {
+                let options = Options {
+                    block_size,
+                    workers,
+                    ..Options::default()
+                };
+                let read = Box::new(FailingRead(b"qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm"));
+                let encoded = encode_with(read, options).collect::<Result<Vec<_>>>();
+                assert_eq!(encoded, Err(Error::new("disk on fire")));
+            }
+        }
+    }
+
+    #[test]
+    fn encode_invalid_options() {
+        for block_size in [
+            BlockSize::Fixed(0),
+            BlockSize::Fixed(MAX_BLOCK_SIZE + 1),
+            BlockSize::Adaptive { min: 64, max: 16 },
+        ] {
+            let options = Options {
+                block_size,
+                ..Options::default()
+            };
+            let encoded = encode_with(Box::new("abc".as_bytes()), options);
+            assert!(encoded.collect::<Result<Vec<_>>>().is_err());
+        }
+
+        let options = Options {
+            zero_runs: true,
+            max_code_len: Some(8),
+            ..Options::default()
+        };
+        let encoded = encode_with(Box::new("abc".as_bytes()), options);
+        assert_eq!(
+            encoded.collect::<Result<Vec<_>>>(),
+            Err(Error::new("Code length limit too small for the alphabet"))
+        );
+    }
+
+    #[test]
+    fn decode_streams_encoded() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        for block_size in [
+            BlockSize::Fixed(20),
+            BlockSize::Fixed(1024),
+            BlockSize::Adaptive { min: 8, max: 32 },
+        ] {
+            for streams in [2, 5, 30] {
+                for workers in [1, 3] {
+                    let options = Options {
+                        block_size,
+                        streams,
+                        workers,
+                        ..Options::default()
+                    };
+
+                    let encoded = encode_with(Box::new(string), options)
+                        .collect::<Result<Vec<_>>>()
+                        .unwrap();
+                    let decoded = decode_parallel(Box::new(Cursor::new(encoded)), workers)
+                        .collect::<Result<Vec<_>>>()
+                        .unwrap();
+                    assert_eq!(&decoded, string)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_streams_invalid_start() {
+        let string = "qwertyuiopasdfghjkfwjeyyyyyyyqowoooolzxcvbnm,wwert6y7u89".as_bytes();
+        let options = Options {
+            streams: 2,
+            ..Options::default()
+        };
+
+        let mut encoded = encode_with(Box::new(string), options)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        // the header takes ten bytes, the block's second stream starts from the row after them
+        encoded[11..15].copy_from_slice(&1000u32.to_be_bytes());
+
+        let decoded = decode(Box::new(Cursor::new(encoded))).collect::<Result<Vec<_>>>();
+        assert_eq!(decoded, Err(Error::new("Start row out of block")))
+    }
 }
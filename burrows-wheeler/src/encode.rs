@@ -1,19 +1,164 @@
-use crate::{bwt, config::WINDOW_SIZE, huffman, mtf};
-use std::io::Read;
+use crate::bwt::{self, Transform};
+use crate::config::{BlockSize, Coder, Index, Options, MAX_BLOCK_SIZE};
+use crate::decode::flatten_block;
+use crate::huffman::BlockReport;
+use crate::parallel::ParallelMap;
+use crate::result::{Error, Result};
+use crate::segmentation::SegmentingIterator;
+use crate::{gst, huffman, stream, zero_runs};
 use std::io::Result as IoResult;
+use std::io::{Cursor, Read};
+use std::iter::once;
 
-pub fn encode(read: Box<dyn Read>) -> Box<impl Iterator<Item = u8>> {
-    let iter = WindowedIterator::from_read(WINDOW_SIZE, read)
-        .take_while(|x| x.is_ok())
-        .map(|x| x.unwrap())
-        .flat_map(encode_block);
-    Box::new(iter)
+type Blocks = Box<dyn Iterator<Item = Result<Vec<u8>>>>;
+
+pub fn encode(read: Box<dyn Read>) -> Box<dyn Iterator<Item = Result<u8>>> {
+    encode_with(read, Options::default())
+}
+
+pub fn encode_parallel(
+    read: Box<dyn Read>,
+    workers: usize,
+) -> Box<dyn Iterator<Item = Result<u8>>> {
+    encode_with(
+        read,
+        Options {
+            workers,
+            ..Options::default()
+        },
+    )
+}
+
+pub fn encode_with(read: Box<dyn Read>, options: Options) -> Box<dyn Iterator<Item = Result<u8>>> {
+    let (block_size, blocks) = match options.validate().and_then(|_| blocks(read, &options)) {
+        Ok(blocks) => blocks,
+        Err(err) => return Box::new(once(Err(err))),
+    };
+    let header = stream::encode_header(&options, block_size);
+
+    let encoded: Box<dyn Iterator<Item = Result<u8>>> = if options.workers > 1 {
+        let workers = options.workers;
+        let iter = ParallelMap::new(blocks, workers, move |block: Result<Vec<u8>>| {
+            block.and_then(|block| encode_block(block, &options).map(|encoded| encoded.collect()))
+        });
+        Box::new(iter.flat_map(|block| flatten_block(block.and_then(|block| block))))
+    } else {
+        Box::new(blocks.flat_map(move |block| {
+            match block.and_then(|block| encode_block(block, &options)) {
+                Ok(encoded) => Box::new(encoded.map(Ok)),
+                Err(err) => Box::new(once(Err(err))) as Box<dyn Iterator<Item = Result<u8>>>,
+            }
+        }))
+    };
+
+    Box::new(header.into_iter().map(Ok).chain(encoded))
 }
 
-fn encode_block(block: Vec<u8>) -> Box<(dyn Iterator<Item = u8> + 'static)> {
-    let (bwted, initial) = bwt::apply(&block);
-    let mtfed = mtf::apply(&bwted);
-    huffman::encode(|| Box::new(mtfed.clone().into_iter()), initial)
+// The codes the static coder picks for every block, over the zero runs when they are on. The
+// adaptive coder has no codes to show.
+pub fn describe_with(
+    read: Box<dyn Read>,
+    options: Options,
+) -> Box<dyn Iterator<Item = Result<BlockReport>>> {
+    let blocks = match options
+        .validate()
+        .and_then(|_| describable(&options))
+        .and_then(|_| blocks(read, &options))
+    {
+        Ok((_, blocks)) => blocks,
+        Err(err) => return Box::new(once(Err(err))),
+    };
+    Box::new(blocks.map(move |block| {
+        let (transformed, _) = transform_block(&block?, &options);
+        if options.zero_runs {
+            let runs = zero_runs::apply(&transformed);
+            BlockReport::new(&runs, zero_runs::ALPHABET_SIZE, options.max_code_len)
+        } else {
+            BlockReport::new(&transformed, 256, options.max_code_len)
+        }
+    }))
+}
+
+fn describable(options: &Options) -> Result<()> {
+    match options.coder {
+        Coder::Static => Ok(()),
+        Coder::Adaptive => Err(Error::new(
+            "The adaptive coder has no code tables to describe",
+        )),
+    }
+}
+
+// The options have to be valid already.
+fn blocks(mut read: Box<dyn Read>, options: &Options) -> Result<(usize, Blocks)> {
+    let block_size = match options.block_size {
+        BlockSize::Fixed(block_size) => block_size,
+        BlockSize::WholeInput => {
+            let mut buffer = Vec::new();
+            read.take(MAX_BLOCK_SIZE as u64 + 1)
+                .read_to_end(&mut buffer)?;
+            if buffer.len() > MAX_BLOCK_SIZE {
+                return Err(Error::new("Input too large for a single block"));
+            }
+            let block_size = buffer.len().max(1);
+            read = Box::new(Cursor::new(buffer));
+            block_size
+        }
+        BlockSize::Adaptive { max, .. } => max,
+    };
+
+    let blocks: Box<dyn Iterator<Item = IoResult<Vec<u8>>>> = match options.block_size {
+        BlockSize::Adaptive { min, max } => Box::new(SegmentingIterator::from_read(min, max, read)),
+        _ => Box::new(WindowedIterator::from_read(block_size, read)),
+    };
+    // a failed read ends the blocks
+    let blocks = blocks.scan(false, |failed, block| {
+        if *failed {
+            return None;
+        }
+        *failed = block.is_err();
+        Some(block.map_err(Error::from))
+    });
+    Ok((block_size, Box::new(blocks)))
+}
+
+fn encode_block(block: Vec<u8>, options: &Options) -> Result<Box<dyn Iterator<Item = u8>>> {
+    let length = if let BlockSize::Adaptive { .. } = options.block_size {
+        stream::encode_block_length(block.len()).to_vec()
+    } else {
+        Vec::new()
+    };
+    let (transformed, initials) = transform_block(&block, options);
+    let starts = if options.streams > 1 {
+        stream::encode_starts(&initials[1..])
+    } else {
+        Vec::new()
+    };
+    let initial = initials.first().copied();
+    let encoded = match options.coder {
+        Coder::Static if options.zero_runs => huffman::encode_symbols(
+            zero_runs::apply(&transformed),
+            zero_runs::ALPHABET_SIZE,
+            initial,
+            options.max_code_len,
+        )?,
+        Coder::Static => huffman::encode(
+            || Box::new(transformed.clone().into_iter()),
+            initial,
+            options.max_code_len,
+        )?,
+        Coder::Adaptive => huffman::adaptive::encode(Box::new(transformed.into_iter()), initial),
+    };
+    Ok(Box::new(length.into_iter().chain(starts).chain(encoded)))
+}
+
+// The rows every stream starts from, the first one being the primary index, are left out by the
+// bijective transform.
+fn transform_block(block: &[u8], options: &Options) -> (Vec<u8>, Vec<Index>) {
+    let (bwted, initials) = match options.transform {
+        Transform::Cyclic => bwt::apply_interleaved(block, options.streams),
+        Transform::Bijective => (bwt::apply_bijective(block), Vec::new()),
+    };
+    (gst::apply(&bwted, &options.stage), initials)
 }
 
 pub struct WindowedIterator {
@@ -31,12 +176,64 @@ impl Iterator for WindowedIterator {
     type Item = IoResult<Vec<u8>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut buffer = vec![0u8; self.window];
+        let mut buffer = Vec::with_capacity(self.window);
 
-        return match self.read.read(buffer.as_mut_slice()) {
-            Ok(size) if size == 0 => None,
-            Ok(size) => Some(Ok(buffer[0..size].to_vec())),
+        match (&mut self.read)
+            .take(self.window as u64)
+            .read_to_end(&mut buffer)
+        {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(buffer)),
             Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn describe(options: Options) -> Result<Vec<BlockReport>> {
+        let input = "mississippi river banks were flooded in the spring".repeat(20);
+        describe_with(Box::new(Cursor::new(input.into_bytes())), options).collect()
+    }
+
+    #[test]
+    fn whole_input_too_large_test() {
+        let options = Options {
+            block_size: BlockSize::WholeInput,
+            ..Options::default()
+        };
+        let input = Cursor::new(vec![0; MAX_BLOCK_SIZE + 1]);
+        assert!(blocks(Box::new(input), &options).is_err());
+        let input = Cursor::new(vec![0; 1000]);
+        assert_eq!(blocks(Box::new(input), &options).unwrap().0, 1000);
+    }
+
+    #[test]
+    fn describe_zero_runs_test() {
+        let options = Options {
+            zero_runs: true,
+            ..Options::default()
+        };
+        let reports = describe(options).unwrap();
+        let tables = &reports[0].tables;
+        assert!(tables
+            .iter()
+            .all(|table| table.frequencies.len() == zero_runs::ALPHABET_SIZE));
+        let end_of_block: u64 = tables
+            .iter()
+            .map(|table| table.frequencies[zero_runs::END_OF_BLOCK as usize])
+            .sum();
+        assert_eq!(end_of_block, 1);
+    }
+
+    #[test]
+    fn describe_adaptive_test() {
+        let options = Options {
+            coder: Coder::Adaptive,
+            ..Options::default()
         };
+        assert!(describe(options).is_err());
     }
 }
@@ -0,0 +1,323 @@
+// The index is built on the transform of the text followed by an end-of-text symbol rather than
+// on `bwt::apply`. Backward search narrows down rows that start with the pattern, and the rows of
+// the cyclic transform are rotations: a pattern running over the end of the text into its start
+// would be counted, and equal rotations of a periodic text have no order that matches their
+// positions. With the unique symbol the rotations are ordered exactly like the suffixes.
+use crate::bwt;
+use crate::config::Index;
+use crate::result::{Error, Result};
+use std::ops::Range;
+
+const BLOCK_SIZE: usize = 1 << 8;
+const SUPERBLOCK_SIZE: usize = 1 << 16;
+
+// Occurrence counts of every byte before each block, relative to the enclosing superblock, so a
+// rank query scans at most one block of the last column.
+struct Rank {
+    superblocks: Vec<[Index; 256]>,
+    blocks: Vec<[u16; 256]>,
+}
+
+impl Rank {
+    fn new(last: &[u8]) -> Rank {
+        let mut superblocks = Vec::with_capacity(last.len() / SUPERBLOCK_SIZE + 1);
+        let mut blocks = Vec::with_capacity(last.len() / BLOCK_SIZE + 1);
+        let mut total = [0; 256];
+        let mut relative = [0; 256];
+
+        for i in 0..=last.len() {
+            if i % SUPERBLOCK_SIZE == 0 {
+                superblocks.push(total);
+                relative = [0; 256];
+            }
+            if i % BLOCK_SIZE == 0 {
+                blocks.push(relative);
+            }
+            if let Some(byte) = last.get(i) {
+                total[*byte as usize] += 1;
+                relative[*byte as usize] += 1;
+            }
+        }
+
+        Rank {
+            superblocks,
+            blocks,
+        }
+    }
+
+    fn rank(&self, last: &[u8], byte: u8, position: usize) -> usize {
+        let block_start = position - position % BLOCK_SIZE;
+        self.superblocks[position / SUPERBLOCK_SIZE][byte as usize] as usize
+            + self.blocks[position / BLOCK_SIZE][byte as usize] as usize
+            + last[block_start..position]
+                .iter()
+                .filter(|x| **x == byte)
+                .count()
+    }
+}
+
+struct Marks {
+    words: Vec<u64>,
+    ranks: Vec<Index>,
+}
+
+impl Marks {
+    fn new(len: usize, marked: impl Iterator<Item = usize>) -> Marks {
+        let mut words = vec![0u64; len / 64 + 1];
+        for i in marked {
+            words[i / 64] |= 1 << (i % 64);
+        }
+
+        let mut sum = 0;
+        let ranks = words
+            .iter()
+            .map(|word| {
+                sum += word.count_ones();
+                (sum - word.count_ones()) as Index
+            })
+            .collect();
+
+        Marks { words, ranks }
+    }
+
+    fn get(&self, i: usize) -> bool {
+        (self.words[i / 64] >> (i % 64)) & 1 == 1
+    }
+
+    fn rank(&self, i: usize) -> usize {
+        let mask = (1u64 << (i % 64)) - 1;
+        self.ranks[i / 64] as usize + (self.words[i / 64] & mask).count_ones() as usize
+    }
+}
+
+// Rows are the sorted suffixes of the text followed by the end-of-text symbol, row 0 being the
+// lone end-of-text suffix. The last column skips the end-of-text symbol, found at row `primary`.
+pub struct FmIndex {
+    last: Vec<u8>,
+    primary: usize,
+    counts: [usize; 256],
+    rank: Rank,
+    sample_rate: usize,
+    sampled_rows: Marks,
+    suffix_samples: Vec<Index>,
+    row_samples: Vec<Index>,
+}
+
+impl FmIndex {
+    pub fn new(text: &[u8], sample_rate: usize) -> Result<FmIndex> {
+        let (last, primary) = bwt::apply_terminated(text);
+        FmIndex::from_bwt(last, primary, sample_rate)
+    }
+
+    pub fn from_bwt(last: Vec<u8>, primary: Index, sample_rate: usize) -> Result<FmIndex> {
+        if sample_rate == 0 {
+            return Err(Error::new("Sample rate must be positive"));
+        }
+        if primary as usize > last.len() {
+            return Err(Error::new("Primary index out of range"));
+        }
+        let len = last.len();
+
+        let mut counts = [0; 256];
+        for byte in &last {
+            counts[*byte as usize] += 1;
+        }
+        let mut sum = 1;
+        for count in counts.iter_mut() {
+            sum += *count;
+            *count = sum - *count;
+        }
+
+        let mut index = FmIndex {
+            rank: Rank::new(&last),
+            last,
+            primary: primary as usize,
+            counts,
+            sample_rate,
+            sampled_rows: Marks::new(0, std::iter::empty()),
+            suffix_samples: Vec::new(),
+            row_samples: vec![0; len / sample_rate + 1],
+        };
+
+        let mut samples = Vec::with_capacity(len / sample_rate + 1);
+        let mut row = 0;
+        for suffix in (0..len).rev() {
+            row = index.lf(row);
+            if suffix % sample_rate == 0 {
+                samples.push((row, suffix as Index));
+                index.row_samples[suffix / sample_rate] = row as Index;
+            }
+        }
+
+        samples.sort_unstable();
+        index.sampled_rows = Marks::new(len + 1, samples.iter().map(|(row, _)| *row));
+        index.suffix_samples = samples.into_iter().map(|(_, suffix)| suffix).collect();
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.last.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last.is_empty()
+    }
+
+    pub fn count(&self, pattern: &[u8]) -> usize {
+        self.rows(pattern).len()
+    }
+
+    pub fn locate(&self, pattern: &[u8]) -> Vec<usize> {
+        let mut positions: Vec<usize> = self.rows(pattern).map(|row| self.suffix(row)).collect();
+        positions.sort_unstable();
+        positions
+    }
+
+    pub fn extract(&self, range: Range<usize>) -> Result<Vec<u8>> {
+        if range.start > range.end || range.end > self.len() {
+            return Err(Error::new("Range out of text bounds"));
+        }
+
+        let end = std::cmp::min(
+            range.end.div_ceil(self.sample_rate) * self.sample_rate,
+            self.len(),
+        );
+        let mut row = if end == self.len() {
+            0
+        } else {
+            self.row_samples[end / self.sample_rate] as usize
+        };
+
+        let mut output = Vec::with_capacity(range.len());
+        for position in (range.start..end).rev() {
+            if position < range.end {
+                output.push(self.last[self.position(row)]);
+            }
+            row = self.lf(row);
+        }
+
+        output.reverse();
+        Ok(output)
+    }
+
+    fn rows(&self, pattern: &[u8]) -> Range<usize> {
+        let mut rows = 0..self.len() + 1;
+
+        for byte in pattern.iter().rev() {
+            let base = self.counts[*byte as usize];
+            rows = base + self.rank_rows(*byte, rows.start)..base + self.rank_rows(*byte, rows.end);
+            if rows.is_empty() {
+                return 0..0;
+            }
+        }
+
+        rows
+    }
+
+    fn suffix(&self, mut row: usize) -> usize {
+        let mut steps = 0;
+        while !self.sampled_rows.get(row) {
+            row = self.lf(row);
+            steps += 1;
+        }
+
+        self.suffix_samples[self.sampled_rows.rank(row)] as usize + steps
+    }
+
+    fn position(&self, row: usize) -> usize {
+        if row > self.primary {
+            row - 1
+        } else {
+            row
+        }
+    }
+
+    fn rank_rows(&self, byte: u8, row: usize) -> usize {
+        self.rank.rank(&self.last, byte, self.position(row))
+    }
+
+    fn lf(&self, row: usize) -> usize {
+        let byte = self.last[self.position(row)];
+        self.counts[byte as usize] + self.rank_rows(byte, row)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn occurrences(text: &[u8], pattern: &[u8]) -> Vec<usize> {
+        (0..=text.len().saturating_sub(pattern.len()))
+            .filter(|i| text[*i..].starts_with(pattern))
+            .collect()
+    }
+
+    #[test]
+    fn count_test() {
+        let index = FmIndex::new("mississippi".as_bytes(), 3).unwrap();
+        assert_eq!(index.count("ssi".as_bytes()), 2);
+        assert_eq!(index.count("i".as_bytes()), 4);
+        assert_eq!(index.count("mississippi".as_bytes()), 1);
+        assert_eq!(index.count("pim".as_bytes()), 0);
+        assert_eq!(index.count("x".as_bytes()), 0);
+    }
+
+    #[test]
+    fn locate_test() {
+        let text = "abracadabra abracadabra cadabra".as_bytes();
+        for sample_rate in 1..6 {
+            let index = FmIndex::new(text, sample_rate).unwrap();
+            for pattern in ["a", "abra", "cad", "ra ", "bra", "z", ""] {
+                let pattern = pattern.as_bytes();
+                assert_eq!(index.locate(pattern), occurrences(text, pattern));
+            }
+        }
+    }
+
+    #[test]
+    fn extract_test() {
+        let text = "abracadabra abracadabra cadabra".as_bytes();
+        for sample_rate in 1..6 {
+            let index = FmIndex::new(text, sample_rate).unwrap();
+            for start in 0..=text.len() {
+                for end in start..=text.len() {
+                    assert_eq!(index.extract(start..end).unwrap(), &text[start..end]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_test() {
+        let index = FmIndex::new(&[], 4).unwrap();
+        assert!(index.is_empty());
+        assert_eq!(index.count("a".as_bytes()), 0);
+        assert_eq!(index.extract(0..0).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn invalid_options_test() {
+        assert!(FmIndex::new("banana".as_bytes(), 0).is_err());
+        let (last, primary) = bwt::apply_terminated("banana".as_bytes());
+        assert!(FmIndex::from_bwt(last.clone(), 7, 2).is_err());
+        assert!(FmIndex::from_bwt(last, primary, 2).is_ok());
+    }
+
+    #[test]
+    fn extract_out_of_bounds_test() {
+        let index = FmIndex::new("banana".as_bytes(), 2).unwrap();
+        assert!(index.extract(2..7).is_err());
+        assert!(index.extract(7..7).is_err());
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 4..3;
+        assert!(index.extract(reversed).is_err());
+    }
+
+    #[test]
+    fn wrapping_pattern_test() {
+        let index = FmIndex::new("abab".as_bytes(), 1).unwrap();
+        assert_eq!(index.locate("ba".as_bytes()), vec![1]);
+        assert_eq!(index.count("bab".as_bytes()), 1);
+        assert_eq!(index.count("baba".as_bytes()), 0);
+    }
+}
@@ -0,0 +1,97 @@
+use super::{push_number, read_len, read_number, FreePositions};
+use crate::result::{Error, Result};
+
+// The block length and the first position of every symbol, offset by one so that zero marks an
+// absent symbol. Then every position gives the distance to the next occurrence of its symbol, or
+// zero for the last one. Distances only count the positions the decoder does not know yet.
+pub fn apply(buffer: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(buffer.len() + 257);
+    push_number(&mut output, buffer.len());
+
+    let mut next = [None; 256];
+    let mut following = vec![None; buffer.len()];
+    for (i, byte) in buffer.iter().enumerate().rev() {
+        following[i] = next[*byte as usize];
+        next[*byte as usize] = Some(i);
+    }
+
+    let mut unknown = FreePositions::new(buffer.len());
+    for first in next.iter() {
+        push_number(&mut output, first.map_or(0, |first| first + 1));
+        if let Some(first) = first {
+            unknown.take(*first);
+        }
+    }
+
+    for position in following {
+        match position {
+            Some(position) => {
+                push_number(&mut output, unknown.before(position) + 1);
+                unknown.take(position);
+            }
+            None => push_number(&mut output, 0),
+        }
+    }
+
+    output
+}
+
+pub fn reverse(buffer: &[u8]) -> Result<Vec<u8>> {
+    let mut input_iter = buffer.iter();
+    let len = read_len(&mut input_iter)?;
+    let mut output: Vec<Option<u8>> = vec![None; len];
+    let mut unknown = FreePositions::new(len);
+
+    for symbol in 0..=255 {
+        match read_number(&mut input_iter)? {
+            0 => {}
+            first => match output.get_mut(first - 1) {
+                Some(slot @ None) => {
+                    *slot = Some(symbol);
+                    unknown.take(first - 1);
+                }
+                _ => return Err(Error::new("Distance out of range")),
+            },
+        }
+    }
+
+    for i in 0..len {
+        let symbol = output[i].ok_or_else(|| Error::new("Unknown symbol position"))?;
+        match read_number(&mut input_iter)? {
+            0 => {}
+            distance => {
+                let position = unknown
+                    .find(distance - 1)
+                    .ok_or_else(|| Error::new("Distance out of range"))?;
+                output[position] = Some(symbol);
+                unknown.take(position);
+            }
+        }
+    }
+
+    match input_iter.next() {
+        None => Ok(output.into_iter().map(|x| x.unwrap()).collect()),
+        Some(_) => Err(Error::new("Unexpected distances")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply_test() {
+        let applied = apply("abcab".as_bytes());
+        assert_eq!(applied[0], 5);
+        assert_eq!(&applied[98..101], &[1, 2, 3]);
+        assert_eq!(&applied[257..], &[1, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn reverse_invalid_test() {
+        let mut applied = apply("abcab".as_bytes());
+        applied[257] = 3;
+        assert!(reverse(&applied).is_err());
+        assert!(reverse(&applied[..200]).is_err());
+    }
+}
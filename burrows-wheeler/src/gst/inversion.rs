@@ -0,0 +1,102 @@
+use super::{push_number, read_len, read_number, FreePositions};
+use crate::config::MAX_BLOCK_SIZE;
+use crate::result::{Error, Result};
+
+fn counts(buffer: &[u8]) -> [usize; 256] {
+    let mut counts = [0; 256];
+    for byte in buffer {
+        counts[*byte as usize] += 1;
+    }
+    counts
+}
+
+// For every symbol but the largest one present, the number of larger symbols before each of its
+// occurrences, counted from the previous occurrence. The largest symbol fills the remaining
+// positions.
+pub fn apply(buffer: &[u8]) -> Vec<u8> {
+    let counts = counts(buffer);
+    let mut output = Vec::with_capacity(buffer.len() + 256);
+    for count in counts.iter() {
+        push_number(&mut output, *count);
+    }
+
+    let mut starts = [0; 257];
+    for symbol in 0..256 {
+        starts[symbol + 1] = starts[symbol] + counts[symbol];
+    }
+    let mut positions = vec![0; buffer.len()];
+    let mut next = starts;
+    for (i, byte) in buffer.iter().enumerate() {
+        positions[next[*byte as usize]] = i;
+        next[*byte as usize] += 1;
+    }
+
+    let largest = counts.iter().rposition(|count| *count > 0).unwrap_or(0);
+    let mut free = FreePositions::new(buffer.len());
+    for symbol in 0..largest {
+        let mut previous = 0;
+        for position in &positions[starts[symbol]..starts[symbol + 1]] {
+            let rank = free.before(*position);
+            push_number(&mut output, rank - previous);
+            free.take(*position);
+            previous = rank;
+        }
+    }
+
+    output
+}
+
+pub fn reverse(buffer: &[u8]) -> Result<Vec<u8>> {
+    let mut input_iter = buffer.iter();
+    let mut counts = [0; 256];
+    for count in counts.iter_mut() {
+        *count = read_len(&mut input_iter)?;
+    }
+
+    let len = counts
+        .iter()
+        .try_fold(0usize, |sum, count| sum.checked_add(*count))
+        .filter(|len| *len <= MAX_BLOCK_SIZE)
+        .ok_or_else(|| Error::new("Block exceeds block size"))?;
+    let largest = counts.iter().rposition(|count| *count > 0).unwrap_or(0);
+
+    let mut output = vec![largest as u8; len];
+    let mut free = FreePositions::new(len);
+    for (symbol, count) in counts.iter().enumerate().take(largest) {
+        let mut previous = 0;
+        for _ in 0..*count {
+            let rank = previous + read_number(&mut input_iter)?;
+            let position = free
+                .find(rank)
+                .ok_or_else(|| Error::new("Inversion frequency out of range"))?;
+            output[position] = symbol as u8;
+            free.take(position);
+            previous = rank;
+        }
+    }
+
+    match input_iter.next() {
+        None => Ok(output),
+        Some(_) => Err(Error::new("Unexpected inversion frequencies")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply_test() {
+        let applied = apply("abcab".as_bytes());
+        assert_eq!(&applied[256..], &[0, 2, 0, 1]);
+        assert_eq!(&applied[97..100], &[2, 2, 1]);
+    }
+
+    #[test]
+    fn reverse_invalid_test() {
+        let mut applied = apply("abcab".as_bytes());
+        applied[257] = 9;
+        assert!(reverse(&applied).is_err());
+        assert!(reverse(&applied[..200]).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+mod distance;
+mod inversion;
+mod wfc;
+
+pub use wfc::Weights;
+
+use crate::config::MAX_BLOCK_SIZE;
+use crate::mtf::{self, Mode};
+use crate::result::{Error, Result};
+use std::str::FromStr;
+
+const ESCAPE: u8 = 255;
+const MAX_NUMBER_LEN: usize = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stage {
+    MoveToFront(Mode),
+    WeightedFrequencyCount(Weights),
+    InversionFrequencies,
+    DistanceCoding,
+}
+
+impl Default for Stage {
+    fn default() -> Self {
+        Stage::MoveToFront(Mode::default())
+    }
+}
+
+impl FromStr for Stage {
+    type Err = Error;
+
+    fn from_str(str: &str) -> std::result::Result<Self, Self::Err> {
+        match str {
+            "mtf" => Ok(Stage::MoveToFront(Mode::MoveToFront)),
+            "mtf1" => Ok(Stage::MoveToFront(Mode::MoveToFrontOne)),
+            "mtf2" => Ok(Stage::MoveToFront(Mode::MoveToFrontTwo)),
+            "sticky" => Ok(Stage::MoveToFront(Mode::Sticky(128))),
+            "wfc" => Ok(Stage::WeightedFrequencyCount(Weights::default())),
+            "if" => Ok(Stage::InversionFrequencies),
+            "dc" => Ok(Stage::DistanceCoding),
+            _ => match str.strip_prefix("sticky:").map(|k| k.parse()) {
+                Some(Ok(k)) => Ok(Stage::MoveToFront(Mode::Sticky(k))),
+                _ => Err(Error::new("Unknown stage")),
+            },
+        }
+    }
+}
+
+pub fn apply(buffer: &[u8], stage: &Stage) -> Vec<u8> {
+    match stage {
+        Stage::MoveToFront(mode) => mtf::apply_with(buffer, *mode),
+        Stage::WeightedFrequencyCount(weights) => wfc::apply(buffer, weights),
+        Stage::InversionFrequencies => inversion::apply(buffer),
+        Stage::DistanceCoding => distance::apply(buffer),
+    }
+}
+
+pub fn reverse(buffer: &[u8], stage: &Stage) -> Result<Vec<u8>> {
+    match stage {
+        Stage::MoveToFront(mode) => Ok(mtf::reverse_with(buffer, *mode)),
+        Stage::WeightedFrequencyCount(weights) => Ok(wfc::reverse(buffer, weights)),
+        Stage::InversionFrequencies => inversion::reverse(buffer),
+        Stage::DistanceCoding => distance::reverse(buffer),
+    }
+}
+
+// Longest output a stage may produce for a block of the given length.
+pub fn bound(stage: &Stage, len: usize) -> usize {
+    match stage {
+        Stage::MoveToFront(_) | Stage::WeightedFrequencyCount(_) => len,
+        Stage::InversionFrequencies | Stage::DistanceCoding => MAX_NUMBER_LEN * (len + 257),
+    }
+}
+
+// Numbers below the escape byte take a single byte, the rest follow it as big-endian u32.
+fn push_number(output: &mut Vec<u8>, number: usize) {
+    if number < ESCAPE as usize {
+        output.push(number as u8);
+    } else {
+        output.push(ESCAPE);
+        output.extend_from_slice(&((number - ESCAPE as usize) as u32).to_be_bytes());
+    }
+}
+
+fn read_number(input_iter: &mut std::slice::Iter<u8>) -> Result<usize> {
+    match input_iter.next() {
+        Some(&ESCAPE) => {
+            let mut bytes = [0; 4];
+            for byte in bytes.iter_mut() {
+                *byte = *input_iter
+                    .next()
+                    .ok_or_else(|| Error::new("Unexpected end of number"))?;
+            }
+            Ok(u32::from_be_bytes(bytes) as usize + ESCAPE as usize)
+        }
+        Some(number) => Ok(*number as usize),
+        None => Err(Error::new("Unexpected end of number")),
+    }
+}
+
+fn read_len(input_iter: &mut std::slice::Iter<u8>) -> Result<usize> {
+    match read_number(input_iter)? {
+        len if len <= MAX_BLOCK_SIZE => Ok(len),
+        _ => Err(Error::new("Block exceeds block size")),
+    }
+}
+
+// Fenwick tree over the positions of a block that are not taken yet.
+struct FreePositions {
+    tree: Vec<u32>,
+}
+
+impl FreePositions {
+    fn new(len: usize) -> FreePositions {
+        let mut tree = vec![1; len + 1];
+        tree[0] = 0;
+        for i in 1..=len {
+            let parent = i + (i & i.wrapping_neg());
+            if parent <= len {
+                tree[parent] += tree[i];
+            }
+        }
+        FreePositions { tree }
+    }
+
+    fn before(&self, position: usize) -> usize {
+        let mut i = position;
+        let mut sum = 0;
+        while i > 0 {
+            sum += self.tree[i] as usize;
+            i &= i - 1;
+        }
+        sum
+    }
+
+    fn take(&mut self, position: usize) {
+        let mut i = position + 1;
+        while i < self.tree.len() {
+            self.tree[i] -= 1;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    // Position of the free one with `rank` free positions before it.
+    fn find(&self, mut rank: usize) -> Option<usize> {
+        let len = self.tree.len() - 1;
+        let mut position = 0;
+        let mut step = if len == 0 { 0 } else { 1 << len.ilog2() };
+
+        while step > 0 {
+            if position + step <= len && (self.tree[position + step] as usize) <= rank {
+                position += step;
+                rank -= self.tree[position] as usize;
+            }
+            step >>= 1;
+        }
+
+        (position < len).then_some(position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn number_test() {
+        let mut output = Vec::new();
+        for number in [0, 254, 255, 70000] {
+            push_number(&mut output, number);
+        }
+        assert_eq!(output.len(), 1 + 1 + 5 + 5);
+
+        let mut input_iter = output.iter();
+        for number in [0, 254, 255, 70000] {
+            assert_eq!(read_number(&mut input_iter), Ok(number));
+        }
+        assert!(read_number(&mut input_iter).is_err());
+        assert!(read_number(&mut [ESCAPE, 0].iter()).is_err());
+    }
+
+    #[test]
+    fn reverse_test() {
+        let string = "DRDOBBS mississippi aaaabbbbeeeeeddddda banana".as_bytes();
+        let stages = [
+            Stage::MoveToFront(Mode::MoveToFront),
+            Stage::MoveToFront(Mode::MoveToFrontTwo),
+            Stage::MoveToFront(Mode::Sticky(64)),
+            Stage::WeightedFrequencyCount(Weights::default()),
+            Stage::WeightedFrequencyCount(Weights::new(vec![(1, 1), (3, 0), (2, 7)])),
+            Stage::InversionFrequencies,
+            Stage::DistanceCoding,
+        ];
+
+        for stage in stages.iter() {
+            for len in 0..=string.len() {
+                let applied = apply(&string[..len], stage);
+                assert!(applied.len() <= bound(stage, len));
+                assert_eq!(reverse(&applied, stage), Ok(string[..len].to_vec()));
+            }
+        }
+    }
+
+    #[test]
+    fn from_str_test() {
+        assert_eq!("if".parse(), Ok(Stage::InversionFrequencies));
+        assert_eq!(
+            "wfc".parse(),
+            Ok(Stage::WeightedFrequencyCount(Weights::default()))
+        );
+        assert_eq!(
+            "sticky:200".parse(),
+            Ok(Stage::MoveToFront(Mode::Sticky(200)))
+        );
+        assert!("sticky:256".parse::<Stage>().is_err());
+        assert!("bwt".parse::<Stage>().is_err());
+    }
+}
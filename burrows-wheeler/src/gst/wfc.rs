@@ -0,0 +1,136 @@
+// Weights are a step function of the distance to a previous occurrence: every step covers a run of
+// consecutive distances, starting from distance one. Occurrences past the last step weigh nothing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Weights {
+    steps: Vec<(usize, u32)>,
+}
+
+impl Weights {
+    pub fn new(steps: Vec<(usize, u32)>) -> Weights {
+        assert!(
+            !steps.is_empty() && steps.iter().all(|(len, _)| *len > 0),
+            "Weight steps must be non-empty"
+        );
+        Weights { steps }
+    }
+
+    pub fn steps(&self) -> &[(usize, u32)] {
+        &self.steps
+    }
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights::new(vec![
+            (1, 1 << 15),
+            (1, 1 << 14),
+            (2, 1 << 13),
+            (4, 1 << 11),
+            (8, 1 << 9),
+            (16, 1 << 7),
+            (32, 1 << 5),
+            (192, 1 << 2),
+        ])
+    }
+}
+
+// Symbols ordered by decreasing weight. A symbol gaining weight goes in front of the symbols it
+// ties with, one losing weight stays in front of them.
+struct List {
+    order: [u8; 256],
+    ranks: [u8; 256],
+    weights: [i64; 256],
+}
+
+impl List {
+    fn new() -> List {
+        let mut order = [0; 256];
+        for (i, x) in order.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+
+        List {
+            order,
+            ranks: order,
+            weights: [0; 256],
+        }
+    }
+
+    fn swap(&mut self, rank: usize, other: usize) {
+        self.order.swap(rank, other);
+        self.ranks[self.order[rank] as usize] = rank as u8;
+        self.ranks[self.order[other] as usize] = other as u8;
+    }
+
+    fn adjust(&mut self, symbol: u8, delta: i64) {
+        let weight = self.weights[symbol as usize] + delta;
+        self.weights[symbol as usize] = weight;
+
+        let mut rank = self.ranks[symbol as usize] as usize;
+        if delta > 0 {
+            while rank > 0 && self.weights[self.order[rank - 1] as usize] <= weight {
+                self.swap(rank, rank - 1);
+                rank -= 1;
+            }
+        } else if delta < 0 {
+            while rank < 255 && self.weights[self.order[rank + 1] as usize] > weight {
+                self.swap(rank, rank + 1);
+                rank += 1;
+            }
+        }
+    }
+
+    // Called once the byte at `position` is known. Every occurrence whose distance crosses a step
+    // boundary changes its weight.
+    fn update(&mut self, history: &[u8], position: usize, weights: &Weights) {
+        let steps = weights.steps();
+        self.adjust(history[position], steps[0].1 as i64);
+
+        let mut boundary = 0;
+        for (i, (len, weight)) in steps.iter().enumerate() {
+            boundary += len;
+            if boundary > position {
+                break;
+            }
+
+            let next = steps.get(i + 1).map_or(0, |(_, weight)| *weight);
+            self.adjust(history[position - boundary], next as i64 - *weight as i64);
+        }
+    }
+}
+
+pub fn apply(buffer: &[u8], weights: &Weights) -> Vec<u8> {
+    let mut list = List::new();
+    let mut output = Vec::with_capacity(buffer.len());
+
+    for (i, byte) in buffer.iter().enumerate() {
+        output.push(list.ranks[*byte as usize]);
+        list.update(buffer, i, weights);
+    }
+
+    output
+}
+
+pub fn reverse(buffer: &[u8], weights: &Weights) -> Vec<u8> {
+    let mut list = List::new();
+    let mut output = Vec::with_capacity(buffer.len());
+
+    for rank in buffer {
+        output.push(list.order[*rank as usize]);
+        list.update(&output, output.len() - 1, weights);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply_test() {
+        let weights = Weights::new(vec![(2, 1)]);
+        assert_eq!(apply("abab".as_bytes(), &weights), vec![97, 98, 1, 1]);
+        assert_eq!(apply("aaba".as_bytes(), &weights), vec![97, 0, 98, 0]);
+    }
+}
@@ -0,0 +1,415 @@
+use super::{bit_reader, BitReader, BoxedByteIterator};
+use crate::bitstream::{BitOrder, BitWriter};
+use crate::config::Index;
+use crate::result::{Error, Result};
+use bit_vec::BitVec;
+use std::io::Result as IoResult;
+
+// The block ends with a symbol of its own, as the coder never knows its length up front.
+const END_OF_BLOCK: usize = 256;
+const SYMBOLS: usize = END_OF_BLOCK + 1;
+const SYMBOL_BITS: u32 = 9;
+const MAX_NODES: usize = 2 * SYMBOLS + 1;
+
+pub fn encode(input_iter: BoxedByteIterator, initial: Option<Index>) -> BoxedByteIterator {
+    Box::new(write(input_iter, initial).unwrap().into_iter())
+}
+
+fn write(input_iter: BoxedByteIterator, initial: Option<Index>) -> IoResult<Vec<u8>> {
+    let mut writer = BitWriter::new(Vec::new(), BitOrder::LsbFirst);
+    if let Some(initial) = initial {
+        for i in (0..Index::BITS).rev() {
+            writer.write_bit((initial >> i) & 1 == 1)?;
+        }
+    }
+
+    let mut tree = Tree::new();
+    for symbol in input_iter.map(|byte| byte as usize).chain([END_OF_BLOCK]) {
+        for bit in tree.encode(symbol) {
+            writer.write_bit(bit)?;
+        }
+    }
+    writer.finish()
+}
+
+pub fn decode(
+    input_iter: &mut dyn Iterator<Item = u8>,
+    with_initial: bool,
+) -> Option<Result<(Box<DecoderIterator<'_>>, Option<Index>)>> {
+    let mut bit_iter = Box::new(bit_reader(input_iter));
+    match bit_iter.peek(1) {
+        Ok((_, 0)) => return None,
+        Ok(_) => {}
+        Err(err) => return Some(Err(err)),
+    }
+
+    let mut initial = None;
+    if with_initial {
+        match read_bits(&mut bit_iter, Index::BITS) {
+            Ok(bits) => initial = Some(bits as Index),
+            Err(err) => return Some(Err(err)),
+        }
+    }
+
+    let iter = DecoderIterator {
+        input_iter: bit_iter,
+        tree: Tree::new(),
+        finished: false,
+    };
+    Some(Ok((Box::new(iter), initial)))
+}
+
+pub fn read_block(input_iter: &mut BoxedByteIterator, with_initial: bool) -> Option<Vec<u8>> {
+    let mut block = Vec::new();
+    {
+        let mut recording_iter = (&mut *input_iter).inspect(|byte| block.push(*byte));
+        // errors are left for the decoder to report
+        if let Ok((decoded_iter, _)) = decode(&mut recording_iter, with_initial)? {
+            decoded_iter.take_while(|x| x.is_ok()).for_each(drop);
+        }
+    }
+    Some(block)
+}
+
+// Values are written from their highest bit.
+fn read_bits(input_iter: &mut BitReader<'_>, bits: u32) -> Result<u64> {
+    let mut value = 0;
+    for i in (0..bits).rev() {
+        value |= (input_iter.read_bit()? as u64) << i;
+    }
+    Ok(value)
+}
+
+// Vitter's algorithm Λ. Nodes are numbered so that weights never decrease with the number and
+// leaves come before internal nodes of the same weight. An update slides every node on the path
+// to the root past the block of nodes it would break the order with, which keeps the tree
+// optimal among the ones with the same weights. The higher numbered child is coded with `1`.
+struct Tree {
+    weight: Vec<u64>,
+    parent: Vec<Option<usize>>,
+    children: Vec<Option<[usize; 2]>>,
+    symbol: Vec<usize>,
+    number: Vec<usize>,
+    at: Vec<usize>,
+    leaves: [Option<usize>; SYMBOLS],
+    not_yet_transmitted: usize,
+}
+
+impl Tree {
+    fn new() -> Tree {
+        // the root starts out as the not yet transmitted leaf
+        Tree {
+            weight: vec![0],
+            parent: vec![None],
+            children: vec![None],
+            symbol: vec![END_OF_BLOCK],
+            number: vec![MAX_NODES - 1],
+            at: vec![0; MAX_NODES],
+            leaves: [None; SYMBOLS],
+            not_yet_transmitted: 0,
+        }
+    }
+
+    fn root(&self) -> usize {
+        self.at[MAX_NODES - 1]
+    }
+
+    fn is_leaf(&self, node: usize) -> bool {
+        self.children[node].is_none()
+    }
+
+    fn add_node(&mut self, number: usize, parent: usize, symbol: usize) -> usize {
+        let node = self.weight.len();
+        self.weight.push(0);
+        self.parent.push(Some(parent));
+        self.children.push(None);
+        self.symbol.push(symbol);
+        self.number.push(number);
+        self.at[number] = node;
+        node
+    }
+
+    fn code(&self, node: usize) -> BitVec {
+        let mut code = Vec::new();
+        let mut node = node;
+        while let Some(parent) = self.parent[node] {
+            let [left, right] = self.children[parent].unwrap();
+            let sibling = if left == node { right } else { left };
+            code.push(self.number[node] > self.number[sibling]);
+            node = parent;
+        }
+        code.into_iter().rev().collect()
+    }
+
+    fn child(&self, node: usize, bit: bool) -> usize {
+        let [left, right] = self.children[node].unwrap();
+        if (self.number[left] > self.number[right]) == bit {
+            left
+        } else {
+            right
+        }
+    }
+
+    // Exchanges two nodes along with their subtrees.
+    fn swap(&mut self, a: usize, b: usize) {
+        let (number_a, number_b) = (self.number[a], self.number[b]);
+        self.number[a] = number_b;
+        self.number[b] = number_a;
+        self.at[number_a] = b;
+        self.at[number_b] = a;
+
+        let (parent_a, parent_b) = (self.parent[a].unwrap(), self.parent[b].unwrap());
+        if parent_a != parent_b {
+            for child in self.children[parent_a].as_mut().unwrap().iter_mut() {
+                if *child == a {
+                    *child = b;
+                }
+            }
+            for child in self.children[parent_b].as_mut().unwrap().iter_mut() {
+                if *child == b {
+                    *child = a;
+                }
+            }
+            self.parent[a] = Some(parent_b);
+            self.parent[b] = Some(parent_a);
+        }
+    }
+
+    // The highest numbered node of the same weight and kind.
+    fn leader(&self, node: usize) -> usize {
+        let mut leader = node;
+        for number in self.number[node] + 1..MAX_NODES {
+            let next = self.at[number];
+            if self.weight[next] != self.weight[node] || self.is_leaf(next) != self.is_leaf(node) {
+                break;
+            }
+            leader = next;
+        }
+        leader
+    }
+
+    fn slide_and_increment(&mut self, node: usize) -> Option<usize> {
+        let previous_parent = self.parent[node];
+        let weight = self.weight[node];
+        let is_leaf = self.is_leaf(node);
+
+        while self.number[node] + 1 < MAX_NODES {
+            let next = self.at[self.number[node] + 1];
+            let in_block = if is_leaf {
+                !self.is_leaf(next) && self.weight[next] == weight
+            } else {
+                self.is_leaf(next) && self.weight[next] == weight + 1
+            };
+            if !in_block {
+                break;
+            }
+            self.swap(node, next);
+        }
+
+        self.weight[node] += 1;
+        if is_leaf {
+            self.parent[node]
+        } else {
+            previous_parent
+        }
+    }
+
+    fn update(&mut self, symbol: usize) {
+        let mut leaf_to_increment = None;
+        let mut node = match self.leaves[symbol] {
+            None => {
+                // the not yet transmitted leaf gets the new symbol and a new one as children
+                let parent = self.not_yet_transmitted;
+                let number = self.number[parent];
+                let leaf = self.add_node(number - 1, parent, symbol);
+                let not_yet_transmitted = self.add_node(number - 2, parent, END_OF_BLOCK);
+                self.children[parent] = Some([not_yet_transmitted, leaf]);
+                self.not_yet_transmitted = not_yet_transmitted;
+                self.leaves[symbol] = Some(leaf);
+                leaf_to_increment = Some(leaf);
+                Some(parent)
+            }
+            Some(leaf) => {
+                let leader = self.leader(leaf);
+                if leader != leaf {
+                    self.swap(leaf, leader);
+                }
+
+                let parent = self.parent[leaf];
+                let children = parent.and_then(|parent| self.children[parent]);
+                if children.is_some_and(|x| x.contains(&self.not_yet_transmitted)) {
+                    leaf_to_increment = Some(leaf);
+                    parent
+                } else {
+                    Some(leaf)
+                }
+            }
+        };
+
+        while let Some(current) = node {
+            node = self.slide_and_increment(current);
+        }
+        if let Some(leaf) = leaf_to_increment {
+            self.slide_and_increment(leaf);
+        }
+    }
+
+    fn encode(&mut self, symbol: usize) -> BitVec {
+        let code = match self.leaves[symbol] {
+            Some(leaf) => self.code(leaf),
+            None => {
+                let mut code = self.code(self.not_yet_transmitted);
+                for i in (0..SYMBOL_BITS).rev() {
+                    code.push((symbol >> i) & 1 == 1);
+                }
+                code
+            }
+        };
+        self.update(symbol);
+        code
+    }
+}
+
+pub struct DecoderIterator<'a> {
+    input_iter: Box<BitReader<'a>>,
+    tree: Tree,
+    finished: bool,
+}
+
+impl<'a> DecoderIterator<'a> {
+    fn decode(&mut self) -> Result<Option<u8>> {
+        let mut node = self.tree.root();
+        while !self.tree.is_leaf(node) {
+            node = self.tree.child(node, self.input_iter.read_bit()?);
+        }
+
+        let symbol = if node == self.tree.not_yet_transmitted {
+            match read_bits(&mut self.input_iter, SYMBOL_BITS)? {
+                symbol if (symbol as usize) < SYMBOLS => symbol as usize,
+                _ => return Err(Error::new("Unexpected symbol")),
+            }
+        } else {
+            self.tree.symbol[node]
+        };
+
+        if symbol == END_OF_BLOCK {
+            return Ok(None);
+        }
+        if node == self.tree.not_yet_transmitted && self.tree.leaves[symbol].is_some() {
+            return Err(Error::new("Unexpected symbol"));
+        }
+        self.tree.update(symbol);
+        Ok(Some(symbol as u8))
+    }
+}
+
+impl<'a> Iterator for DecoderIterator<'a> {
+    type Item = Result<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.decode() {
+            Ok(Some(byte)) => Some(Ok(byte)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(err) => {
+                self.finished = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    impl Tree {
+        // Sibling property: weights never decrease with the numbers, leaves come first within a
+        // weight, and every internal node weighs as much as its children.
+        fn check(&self) {
+            let numbers = &self.at[self.number[self.not_yet_transmitted]..];
+            for pair in numbers.windows(2) {
+                let (a, b) = (pair[0], pair[1]);
+                assert!(self.weight[a] <= self.weight[b]);
+                if self.weight[a] == self.weight[b] {
+                    assert!(self.is_leaf(a) || !self.is_leaf(b));
+                }
+            }
+            for node in numbers {
+                if let Some([left, right]) = self.children[*node] {
+                    assert_eq!(self.weight[*node], self.weight[left] + self.weight[right]);
+                }
+            }
+        }
+    }
+
+    fn encode_vec(input: &[u8], initial: Option<Index>) -> Vec<u8> {
+        encode(Box::new(Vec::from(input).into_iter()), initial).collect()
+    }
+
+    #[test]
+    fn update_test() {
+        let mut tree = Tree::new();
+        for byte in "abracadabra mississippi".bytes().chain(0..=255) {
+            tree.update(byte as usize);
+            tree.check();
+        }
+        assert_eq!(tree.weight[tree.root()], 23 + 256);
+    }
+
+    #[test]
+    fn decode_encoded_test() -> Result<()> {
+        let mut input: Vec<u8> = "abracadabra mississippi".bytes().collect();
+        input.extend((0..20000u32).map(|x| (x * x % 251 % 17) as u8));
+        input.extend(0..=255);
+
+        for initial in [None, Some(42)] {
+            let encoded = encode_vec(&input, initial);
+            let mut encoded_iter: BoxedByteIterator = Box::new(encoded.into_iter());
+            let (decoded_iter, decoded_initial) =
+                decode(encoded_iter.as_mut(), initial.is_some()).unwrap()?;
+            assert_eq!(decoded_iter.collect::<Result<Vec<u8>>>()?, input);
+            assert_eq!(decoded_initial, initial);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn adapts_test() {
+        // once the ones show up the zeros keep a one bit code
+        let input: Vec<u8> = (0..20000).map(|i| (i % 10 == 0) as u8).collect();
+        assert!(encode_vec(&input, None).len() < 20000 * 12 / 10 / 8);
+        assert!(encode_vec(&[], None).len() == 2);
+    }
+
+    #[test]
+    fn read_block_test() {
+        let first = encode_vec("abbcccdddd".as_bytes(), Some(3));
+        let second = encode_vec(&[], Some(0));
+
+        let mut input_iter: BoxedByteIterator =
+            Box::new(first.clone().into_iter().chain(second.clone()));
+        assert_eq!(read_block(&mut input_iter, true), Some(first));
+        assert_eq!(read_block(&mut input_iter, true), Some(second));
+        assert_eq!(read_block(&mut input_iter, true), None);
+    }
+
+    #[test]
+    fn decode_invalid_test() {
+        let encoded = encode_vec("aab".as_bytes(), None);
+        let mut encoded_iter: BoxedByteIterator = Box::new(vec![encoded[0]].into_iter());
+        let (decoded_iter, _) = decode(encoded_iter.as_mut(), false).unwrap().unwrap();
+        assert!(decoded_iter.collect::<Result<Vec<u8>>>().is_err());
+
+        // `a` is sent as a new symbol twice
+        let mut encoded_iter: BoxedByteIterator = Box::new(vec![12, 49, 4].into_iter());
+        let (decoded_iter, _) = decode(encoded_iter.as_mut(), false).unwrap().unwrap();
+        assert!(decoded_iter.collect::<Result<Vec<u8>>>().is_err());
+    }
+}
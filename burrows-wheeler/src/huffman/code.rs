@@ -0,0 +1,250 @@
+use super::encode::code_lengths;
+use crate::config::MAX_CODE_LEN;
+use crate::result::{Error, Result};
+use bit_vec::BitVec;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+
+/// A canonical Huffman code over any ordered symbol type.
+///
+/// Codes are assigned in order of length and then symbol, so the code lengths alone define the
+/// code. No code is longer than 63 bits.
+#[derive(Clone, Debug)]
+pub struct Code<S> {
+    // symbols in canonical order, and how many codes there are of every length
+    symbols: Vec<S>,
+    counts: Vec<u64>,
+    codes: HashMap<S, BitVec>,
+}
+
+impl<S: Copy + Ord + Hash> Code<S> {
+    /// Builds an optimal code for the symbols of nonzero frequency. Frequencies of repeated
+    /// symbols add up. With `max_code_len` the code is the optimal one within that many bits.
+    pub fn from_frequencies(
+        frequencies: impl IntoIterator<Item = (S, u64)>,
+        max_code_len: Option<u8>,
+    ) -> Result<Code<S>> {
+        let mut merged = BTreeMap::new();
+        for (symbol, frequency) in frequencies {
+            *merged.entry(symbol).or_insert(0) += frequency;
+        }
+        merged.retain(|_, frequency| *frequency > 0);
+
+        let max_code_len = max_code_len.unwrap_or(MAX_CODE_LEN);
+        if max_code_len == 0 || max_code_len > MAX_CODE_LEN {
+            return Err(Error::new("Code length limit out of range"));
+        }
+        if merged.len() as u64 > 1 << max_code_len {
+            return Err(Error::new("Code length limit too small for the alphabet"));
+        }
+
+        let (symbols, frequencies): (Vec<S>, Vec<u64>) = merged.into_iter().unzip();
+        let lengths = code_lengths(&frequencies, Some(max_code_len))?;
+        Code::from_lengths(symbols.into_iter().zip(lengths))
+    }
+
+    /// Builds the canonical code with the given code lengths. Symbols of length 0 get no code.
+    pub fn from_lengths(lengths: impl IntoIterator<Item = (S, u8)>) -> Result<Code<S>> {
+        let mut lengths: Vec<(S, u8)> = lengths.into_iter().filter(|(_, len)| *len > 0).collect();
+        lengths.sort();
+        if lengths.windows(2).any(|x| x[0].0 == x[1].0) {
+            return Err(Error::new("Repeated symbol"));
+        }
+        if lengths.iter().any(|(_, len)| *len > MAX_CODE_LEN) {
+            return Err(Error::new("Code length out of range"));
+        }
+
+        let kraft_sum: u128 = lengths
+            .iter()
+            .map(|(_, len)| 1u128 << (MAX_CODE_LEN - len))
+            .sum();
+        if kraft_sum > 1u128 << MAX_CODE_LEN {
+            return Err(Error::new("Code lengths exceed Kraft inequality"));
+        }
+
+        let max_len = lengths.iter().map(|(_, len)| *len).max().unwrap_or(0);
+        let mut counts = vec![0; max_len as usize + 1];
+        for (_, len) in lengths.iter() {
+            counts[*len as usize] += 1;
+        }
+
+        let codes = canonical(lengths);
+        Ok(Code {
+            symbols: codes.iter().map(|(symbol, _)| *symbol).collect(),
+            counts,
+            codes: codes.into_iter().collect(),
+        })
+    }
+
+    /// The symbols that have a code along with their code lengths, in canonical order.
+    pub fn lengths(&self) -> Vec<(S, u8)> {
+        self.symbols
+            .iter()
+            .map(|symbol| (*symbol, self.codes[symbol].len() as u8))
+            .collect()
+    }
+
+    /// The code of `symbol`, the first bit first.
+    pub fn code(&self, symbol: S) -> Option<&BitVec> {
+        self.codes.get(&symbol)
+    }
+
+    /// Appends the codes of `symbols` to `bits`.
+    pub fn encode_into(&self, symbols: &[S], bits: &mut BitVec) -> Result<()> {
+        for symbol in symbols {
+            let code = self
+                .code(*symbol)
+                .ok_or_else(|| Error::new("Symbol without a code"))?;
+            bits.extend(code.iter());
+        }
+        Ok(())
+    }
+
+    /// The codes of `symbols` one after another.
+    pub fn encode(&self, symbols: &[S]) -> Result<BitVec> {
+        let mut bits = BitVec::new();
+        self.encode_into(symbols, &mut bits)?;
+        Ok(bits)
+    }
+
+    /// The code tree as a Graphviz DOT digraph, with the leaves labeled by `label`.
+    pub fn to_dot(&self, name: &str, label: impl Fn(S) -> String) -> String {
+        // nodes are named after the prefixes that lead to them
+        let node = |code: &BitVec, len: usize| {
+            let bits: String = code
+                .iter()
+                .take(len)
+                .map(|bit| (b'0' + bit as u8) as char)
+                .collect();
+            format!("n{}", bits)
+        };
+
+        let mut dot = format!("digraph \"{}\" {{\n  node [shape=point];\n", escape(name));
+        let mut inner = HashSet::new();
+        for symbol in self.symbols.iter() {
+            let code = &self.codes[symbol];
+            for len in 1..=code.len() {
+                let child = node(code, len);
+                if len == code.len() || inner.insert(child.clone()) {
+                    let bit = code[len - 1] as u8;
+                    let parent = node(code, len - 1);
+                    dot += &format!("  {} -> {} [label=\"{}\"];\n", parent, child, bit);
+                }
+            }
+            let label = escape(&label(*symbol));
+            dot += &format!(
+                "  {} [shape=box, label=\"{}\"];\n",
+                node(code, code.len()),
+                label
+            );
+        }
+        dot + "}\n"
+    }
+
+    /// Decodes every code in `bits`, which have to end with the last one.
+    pub fn decode(&self, bits: &BitVec) -> Result<Vec<S>> {
+        let mut bits = bits.iter().peekable();
+        let mut symbols = Vec::new();
+        while bits.peek().is_some() {
+            symbols.push(self.decode_symbol(&mut bits)?);
+        }
+        Ok(symbols)
+    }
+
+    /// Decodes the next code from `bits`.
+    pub fn decode_symbol(&self, bits: &mut impl Iterator<Item = bool>) -> Result<S> {
+        // the codes of every length are consecutive, starting right after the previous length's
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for count in self.counts.iter().skip(1) {
+            let bit = bits
+                .next()
+                .ok_or_else(|| Error::new("Unexpected end of code word"))?;
+            code = code << 1 | bit as u64;
+            if code - first < *count {
+                return Ok(self.symbols[index + (code - first) as usize]);
+            }
+            index += *count as usize;
+            first = (first + count) << 1;
+        }
+        Err(Error::new("Unexpected code word"))
+    }
+}
+
+fn escape(str: &str) -> String {
+    str.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Symbols of length 0 are left out. The lengths have to satisfy the Kraft inequality.
+pub(super) fn canonical<S: Copy + Ord>(
+    lengths: impl IntoIterator<Item = (S, u8)>,
+) -> Vec<(S, BitVec)> {
+    let mut lengths: Vec<(S, u8)> = lengths.into_iter().filter(|(_, len)| *len > 0).collect();
+    lengths.sort_by_key(|(symbol, len)| (*len, *symbol));
+
+    let mut code: u64 = 0;
+    let mut previous_len = 0;
+    let mut codes = Vec::with_capacity(lengths.len());
+    for (symbol, len) in lengths {
+        code <<= len - previous_len;
+        previous_len = len;
+        codes.push((
+            symbol,
+            (0..len).rev().map(|i| (code >> i) & 1 == 1).collect(),
+        ));
+        code += 1;
+    }
+    codes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_frequencies_test() {
+        let frequencies = [(300u16, 1), (1000, 2), (7, 3), (9, 4), (5, 0)];
+        let code = Code::from_frequencies(frequencies, None).unwrap();
+        assert_eq!(code.lengths(), vec![(9, 1), (7, 2), (300, 3), (1000, 3)]);
+        assert_eq!(code.code(300), Some(&BitVec::from_fn(3, |i| i < 2)));
+        assert_eq!(code.code(5), None);
+
+        let code = Code::from_frequencies(frequencies, Some(2)).unwrap();
+        assert!(code.lengths().iter().all(|(_, len)| *len == 2));
+        assert!(Code::from_frequencies(frequencies, Some(1)).is_err());
+    }
+
+    #[test]
+    fn from_lengths_test() {
+        let code = Code::from_lengths([('a', 3), ('b', 3), ('c', 2), ('d', 2), ('e', 2), ('f', 0)]);
+        let code = code.unwrap();
+        assert_eq!(code.code('c'), Some(&BitVec::from_fn(2, |_| false)));
+        assert_eq!(code.code('b'), Some(&BitVec::from_fn(3, |_| true)));
+
+        assert!(Code::from_lengths([('a', 1), ('b', 1), ('c', 1)]).is_err());
+        assert!(Code::from_lengths([('a', 1), ('a', 2)]).is_err());
+        assert!(Code::from_lengths([('a', 64)]).is_err());
+    }
+
+    #[test]
+    fn decode_encoded_test() {
+        let input: Vec<u32> = (0..1000).map(|x| x * x % 613).collect();
+        let code = Code::from_frequencies(input.iter().map(|x| (*x, 1)), None).unwrap();
+        let encoded = code.encode(&input).unwrap();
+        assert_eq!(code.decode(&encoded), Ok(input));
+
+        assert!(code.encode(&[613]).is_err());
+        let mut truncated = encoded.clone();
+        truncated.truncate(encoded.len() - 1);
+        assert!(code.decode(&truncated).is_err());
+    }
+
+    #[test]
+    fn decode_single_symbol_test() {
+        let code = Code::from_frequencies([(b'a', 5)], None).unwrap();
+        assert_eq!(
+            code.decode(&code.encode(b"aaa").unwrap()),
+            Ok(b"aaa".to_vec())
+        );
+        assert!(code.decode(&BitVec::from_elem(1, true)).is_err());
+    }
+}
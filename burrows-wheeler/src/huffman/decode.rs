@@ -1,78 +1,589 @@
-use super::header::Header;
-use super::iterator::BitIterator;
+use super::header::{CodeDescriptor, Header};
+use super::{bit_reader, BitReader, BoxedByteIterator, GROUP_SIZE};
 use crate::config::Index;
 use crate::result::{Error, Result};
-use bit_vec::BitVec;
-use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+
+const TABLE_BITS: u32 = 10;
+const LETTERS: usize = 4;
+
+pub type Decoded<'a, S = u8> = (Box<DecoderIterator<'a, S>>, Option<Index>);
 
 pub fn decode(
     input_iter: &mut Box<dyn Iterator<Item = u8>>,
-) -> Option<Result<(Box<DecoderIterator<'_>>, Index)>> {
-    let mut bit_iter = Box::new(BitIterator::new(input_iter, usize::MAX));
-    let header = Header::decode(&mut bit_iter)?;
-    bit_iter.bit_size(header.bit_size);
-
-    let codes: HashMap<BitVec, u8> = header
-        .code_descriptors
-        .into_iter()
-        .map(|code_descriptor| {
-            (
-                code_descriptor.code.into_iter().collect(),
-                code_descriptor.letter,
-            )
-        })
-        .collect();
+    with_initial: bool,
+) -> Option<Result<Decoded<'_>>> {
+    decode_symbols(input_iter, with_initial, 256)
+}
 
-    let iter = DecoderIterator::new(codes, bit_iter);
+// Letters are decoded as `S`, which has to hold every letter of the alphabet.
+pub fn decode_symbols<S: TryFrom<u16>>(
+    input_iter: &mut Box<dyn Iterator<Item = u8>>,
+    with_initial: bool,
+    alphabet_size: usize,
+) -> Option<Result<Decoded<'_, S>>> {
+    let mut bit_iter = Box::new(bit_reader(input_iter));
+    let header = match Header::decode(&mut bit_iter, with_initial, alphabet_size)? {
+        Ok(header) => header,
+        Err(error) => return Some(Err(error)),
+    };
+    bit_iter.limit(header.bit_size as u64);
+
+    let iter = DecoderIterator::new(&header.code_descriptors, header.selectors, bit_iter);
     Some(Ok((Box::new(iter), header.initial)))
 }
 
-pub struct DecoderIterator<'a> {
-    input_iter: Box<BitIterator<'a>>,
-    codes: HashMap<BitVec, u8>,
+pub fn read_block(input_iter: &mut BoxedByteIterator, with_initial: bool) -> Option<Vec<u8>> {
+    read_symbols_block(input_iter, with_initial, 256)
 }
 
-impl<'a> DecoderIterator<'a> {
-    pub fn new(codes: HashMap<BitVec, u8>, input_iter: Box<BitIterator<'a>>) -> DecoderIterator {
-        DecoderIterator { input_iter, codes }
-    }
+pub fn read_symbols_block(
+    input_iter: &mut BoxedByteIterator,
+    with_initial: bool,
+    alphabet_size: usize,
+) -> Option<Vec<u8>> {
+    let mut block = Vec::new();
+    let (header_size, bit_size) = {
+        let mut recording_iter = (&mut *input_iter).inspect(|byte| block.push(*byte));
+        let mut bit_iter = bit_reader(&mut recording_iter);
+        match Header::decode(&mut bit_iter, with_initial, alphabet_size)? {
+            Ok(header) => (bit_iter.bits_read() as usize, header.bit_size),
+            // left for the decoder to report
+            Err(_) => return Some(block),
+        }
+    };
+
+    let size = header_size.saturating_add(bit_size).div_ceil(8);
+    block.extend(input_iter.take(size - block.len()));
+    Some(block)
 }
 
-impl<'a> Iterator for DecoderIterator<'a> {
-    type Item = Result<u8>;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Entry {
+    Invalid,
+    // the letters the bits start with, up to `LETTERS` of them, with the length of the first code
+    // and of all of them
+    Letters {
+        letters: [u16; LETTERS],
+        count: u8,
+        first: u8,
+        len: u8,
+    },
+    Table {
+        offset: usize,
+        bits: u32,
+    },
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut current = BitVec::new();
-        let mut ended_flag = true;
-
-        loop {
-            let bit = match self.input_iter.next() {
-                None if ended_flag => return None,
-                None => return Some(Err(Error::new("Unexpected code word"))),
-                Some(bit) => bit,
+// Codes are looked up by their next bits: the first table is indexed by up to `TABLE_BITS` of
+// them, and codes longer than that continue in the tables their prefixes point to. Every table is
+// only as wide as the longest code it holds. The tables of all the codes of a block share one
+// vector, each code starting from its root, whose entries also hold the codes that follow within
+// its bits so that short codes are decoded several at a time.
+struct Lookup {
+    entries: Vec<Entry>,
+    roots: Vec<(usize, u32)>,
+}
+
+impl Lookup {
+    fn new(code_descriptors: &[Vec<CodeDescriptor>]) -> Lookup {
+        let mut entries = Vec::new();
+        let roots = code_descriptors
+            .iter()
+            .map(|table| {
+                // the first bit of a code is the lowest one, as it is read first
+                let codes: Vec<(u64, u32, u16)> = table
+                    .iter()
+                    .map(|code_descriptor| {
+                        let code = code_descriptor
+                            .code
+                            .iter()
+                            .rev()
+                            .fold(0, |code, bit| code << 1 | bit as u64);
+                        (
+                            code,
+                            code_descriptor.code.len() as u32,
+                            code_descriptor.letter,
+                        )
+                    })
+                    .collect();
+                let offset = entries.len();
+                let bits = Lookup::add(&mut entries, &codes);
+                Lookup::chain(&mut entries[offset..offset + (1 << bits)]);
+                (offset, bits)
+            })
+            .collect();
+        Lookup { entries, roots }
+    }
+
+    fn add(entries: &mut Vec<Entry>, codes: &[(u64, u32, u16)]) -> u32 {
+        let bits = codes
+            .iter()
+            .map(|(_, len, _)| *len)
+            .max()
+            .unwrap_or(0)
+            .min(TABLE_BITS);
+        let offset = entries.len();
+        entries.resize(offset + (1 << bits), Entry::Invalid);
+
+        let mut long_codes: Vec<(u64, u32, u16)> = Vec::new();
+        for (code, len, letter) in codes.iter().copied() {
+            if len <= bits {
+                for suffix in 0..1 << (bits - len) {
+                    entries[offset + (code | suffix << len) as usize] = Entry::Letters {
+                        letters: [letter; LETTERS],
+                        count: 1,
+                        first: len as u8,
+                        len: len as u8,
+                    };
+                }
+            } else {
+                long_codes.push((code, len, letter));
+            }
+        }
+
+        let mask = (1 << bits) - 1;
+        long_codes.sort_by_key(|(code, _, _)| *code & mask);
+        for group in long_codes.chunk_by(|x, y| (x.0 ^ y.0) & mask == 0) {
+            let prefix = (group[0].0 & mask) as usize;
+            let rest: Vec<(u64, u32, u16)> = group
+                .iter()
+                .map(|(code, len, letter)| (code >> bits, len - bits, *letter))
+                .collect();
+            let table = Entry::Table {
+                offset: entries.len(),
+                bits: Lookup::add(entries, &rest),
             };
-            ended_flag = false;
-            current.push(bit);
+            entries[offset + prefix] = table;
+        }
+
+        bits
+    }
 
-            if let Some(letter) = self.codes.get(&current) {
-                return Some(Ok(*letter));
+    // The codes that follow the first one within the bits of a root table come with it.
+    fn chain(table: &mut [Entry]) {
+        let bits = table.len().trailing_zeros();
+        for index in 0..table.len() {
+            if let Entry::Letters {
+                mut letters,
+                mut count,
+                first,
+                mut len,
+            } = table[index]
+            {
+                while (count as usize) < LETTERS {
+                    match table[index >> len] {
+                        Entry::Letters {
+                            letters: next,
+                            first: next_len,
+                            ..
+                        } if (len + next_len) as u32 <= bits => {
+                            letters[count as usize] = next[0];
+                            count += 1;
+                            len += next_len;
+                        }
+                        _ => break,
+                    }
+                }
+                table[index] = Entry::Letters {
+                    letters,
+                    count,
+                    first,
+                    len,
+                };
+            }
+        }
+    }
+}
+
+// Bits taken from the reader a word at a time, which the codes are then looked up in. It is
+// passed by value so that it stays in registers while a group is decoded.
+#[derive(Clone, Copy, Default)]
+struct Window {
+    bits: u64,
+    len: u32,
+}
+
+impl Window {
+    fn filled(self, input_iter: &mut BitReader<'_>) -> Result<Window> {
+        let (bits, len) = input_iter.peek(u32::BITS.min(u64::BITS - self.len))?;
+        input_iter.consume(len);
+        Ok(Window {
+            bits: self.bits | bits << self.len,
+            len: self.len + len,
+        })
+    }
+
+    fn consumed(self, len: u32) -> Window {
+        Window {
+            bits: self.bits >> len,
+            len: self.len - len,
+        }
+    }
+}
+
+// Symbols are decoded a group at a time, as the whole group shares its code, and handed out from
+// there. The symbols decoded before an error come first, and the error ends the iteration.
+pub struct DecoderIterator<'a, S = u8> {
+    input_iter: Box<BitReader<'a>>,
+    window: Window,
+    lookup: Lookup,
+    selectors: Vec<u8>,
+    group: usize,
+    letters: [u16; GROUP_SIZE + LETTERS - 1],
+    decoded: usize,
+    position: usize,
+    error: Option<Error>,
+    done: bool,
+    symbol: PhantomData<S>,
+}
+
+impl<'a, S> DecoderIterator<'a, S> {
+    fn new(
+        code_descriptors: &[Vec<CodeDescriptor>],
+        selectors: Vec<u8>,
+        input_iter: Box<BitReader<'a>>,
+    ) -> DecoderIterator<'a, S> {
+        DecoderIterator {
+            input_iter,
+            window: Window::default(),
+            lookup: Lookup::new(code_descriptors),
+            selectors,
+            group: 0,
+            letters: [0; GROUP_SIZE + LETTERS - 1],
+            decoded: 0,
+            position: 0,
+            error: None,
+            done: false,
+            symbol: PhantomData,
+        }
+    }
+
+    // Fills `letters` with the next group, counting them in `decoded` as they come.
+    fn decode_group(&mut self) -> Result<()> {
+        if self.window.len == 0 && self.input_iter.is_empty() {
+            return Ok(());
+        }
+        let (root, bits) = if self.lookup.roots.len() > 1 {
+            match self.selectors.get(self.group) {
+                Some(selector) => self.lookup.roots[*selector as usize],
+                None => return Err(Error::new("Missing selector")),
+            }
+        } else {
+            self.lookup.roots.first().copied().unwrap_or_default()
+        };
+        self.group += 1;
+
+        // the window's bits past its length are zeros, so it is looked up in whole and an entry whose
+        // codes it does not hold yet is told by their length
+        let mask = (1 << bits) - 1;
+        let mut window = self.window;
+        let mut decoded = self.decoded;
+        let result = 'group: loop {
+            if decoded == GROUP_SIZE {
+                break Ok(());
+            }
+            if window.len < u32::BITS {
+                window = match window.filled(&mut self.input_iter) {
+                    Ok(window) => window,
+                    Err(err) => break Err(err),
+                };
+                if window.len == 0 {
+                    break Ok(());
+                }
+            }
+            match self.lookup.entries[root + (window.bits & mask) as usize] {
+                Entry::Letters {
+                    letters,
+                    count,
+                    len,
+                    ..
+                } if len as u32 <= window.len && count as usize <= GROUP_SIZE - decoded => {
+                    self.letters[decoded..decoded + LETTERS].copy_from_slice(&letters);
+                    decoded += count as usize;
+                    window = window.consumed(len as u32);
+                }
+                Entry::Letters { letters, first, .. } if first as u32 <= window.len => {
+                    self.letters[decoded] = letters[0];
+                    decoded += 1;
+                    window = window.consumed(first as u32);
+                }
+                Entry::Table {
+                    offset: next,
+                    bits: next_bits,
+                } if bits <= window.len => {
+                    window = window.consumed(bits);
+                    let (mut offset, mut bits) = (next, next_bits);
+                    self.letters[decoded] = loop {
+                        if window.len < bits {
+                            window = match window.filled(&mut self.input_iter) {
+                                Ok(window) => window,
+                                Err(err) => break 'group Err(err),
+                            };
+                        }
+                        let index = window.bits & ((1 << bits) - 1);
+                        match self.lookup.entries[offset + index as usize] {
+                            Entry::Letters { letters, first, .. } if first as u32 <= window.len => {
+                                window = window.consumed(first as u32);
+                                break letters[0];
+                            }
+                            Entry::Table {
+                                offset: next,
+                                bits: next_bits,
+                            } if bits <= window.len => {
+                                window = window.consumed(bits);
+                                offset = next;
+                                bits = next_bits;
+                            }
+                            _ => break 'group Err(Error::new("Unexpected code word")),
+                        }
+                    };
+                    decoded += 1;
+                }
+                _ => break Err(Error::new("Unexpected code word")),
+            }
+        };
+        self.window = window;
+        self.decoded = decoded;
+        result
+    }
+
+    // Moves on to the next group, leaving `decoded` at zero once the symbols have run out. An
+    // error is handed out after the symbols decoded before it. Kept out of line, so that what
+    // `next` does for the other symbols is inlined where they are read.
+    #[cold]
+    #[inline(never)]
+    fn next_group(&mut self) -> Option<Error> {
+        self.position = 0;
+        self.decoded = 0;
+        if self.done {
+            return self.error.take();
+        }
+        if let Err(err) = self.decode_group() {
+            self.error = Some(err);
+            self.done = true;
+        }
+        if self.decoded == 0 {
+            self.done = true;
+            return self.error.take();
+        }
+        None
+    }
+}
+
+#[cold]
+fn out_of_alphabet() -> Error {
+    Error::new("Letter out of alphabet")
+}
+
+impl<'a, S: TryFrom<u16>> Iterator for DecoderIterator<'a, S> {
+    type Item = Result<S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position == self.decoded {
+            if let Some(err) = self.next_group() {
+                return Some(Err(err));
+            }
+            if self.decoded == 0 {
+                return None;
             }
         }
+
+        let letter = self.letters[self.position];
+        self.position += 1;
+        Some(S::try_from(letter).map_err(|_| out_of_alphabet()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::huffman::encode;
+    use crate::huffman::{encode, encode_symbols};
 
     #[test]
     fn decode_encoded() -> Result<()> {
         let input = "abbcccdddddeeoifhweag128138y2o".as_bytes();
-        let mut encoded_iter = &mut Box::new(encode(|| Box::new(input.iter().copied()), 0));
-        let (decoded_iter, _) = decode(&mut encoded_iter).unwrap()?;
+        let encoded_iter =
+            &mut Box::new(encode(|| Box::new(input.iter().copied()), Some(7), None).unwrap());
+        let (decoded_iter, initial) = decode(encoded_iter, true).unwrap()?;
+        let decoded: Vec<u8> = decoded_iter.map(|x| x.unwrap()).collect();
+        assert_eq!(input, &decoded);
+        assert_eq!(initial, Some(7));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_encoded_without_initial() -> Result<()> {
+        let input = "abbcccdddddeeoifhweag128138y2o".as_bytes();
+        let encoded_iter =
+            &mut Box::new(encode(|| Box::new(input.iter().copied()), None, None).unwrap());
+        let (decoded_iter, initial) = decode(encoded_iter, false).unwrap()?;
+        let decoded: Vec<u8> = decoded_iter.map(|x| x.unwrap()).collect();
+        assert_eq!(input, &decoded);
+        assert_eq!(initial, None);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_encoded_single_letter() -> Result<()> {
+        let input = "aaaaaaaaaaaaa".as_bytes();
+        let encoded_iter =
+            &mut Box::new(encode(|| Box::new(input.iter().copied()), None, None).unwrap());
+        let (decoded_iter, _) = decode(encoded_iter, false).unwrap()?;
         let decoded: Vec<u8> = decoded_iter.map(|x| x.unwrap()).collect();
         assert_eq!(input, &decoded);
         Ok(())
     }
+
+    fn fibonacci_input(letters: u8) -> Vec<u8> {
+        let (mut input, mut previous) = (vec![0], 1);
+        for letter in 1..letters {
+            let len = input.len();
+            input.extend(std::iter::repeat_n(letter, previous));
+            previous = len;
+        }
+        input
+    }
+
+    #[test]
+    fn decode_encoded_limited() -> Result<()> {
+        let input = fibonacci_input(20);
+        let supplier = || Box::new(input.clone().into_iter()) as BoxedByteIterator;
+        let encoded_iter = &mut Box::new(encode(supplier, None, Some(8)).unwrap());
+        let (decoded_iter, _) = decode(encoded_iter, false).unwrap()?;
+        let decoded: Vec<u8> = decoded_iter.map(|x| x.unwrap()).collect();
+        assert_eq!(input, decoded);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_encoded_long_codes() -> Result<()> {
+        // codes up to 24 bits long take three tables
+        let input = fibonacci_input(25);
+        let supplier = || Box::new(input.clone().into_iter()) as BoxedByteIterator;
+        let encoded_iter = &mut Box::new(encode(supplier, None, None).unwrap());
+        let (decoded_iter, _) = decode(encoded_iter, false).unwrap()?;
+        let decoded: Vec<u8> = decoded_iter.map(|x| x.unwrap()).collect();
+        assert_eq!(input, decoded);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_encoded_tables() -> Result<()> {
+        let mut input = fibonacci_input(12);
+        input.extend(input.iter().rev().map(|x| 20 - x).collect::<Vec<_>>());
+        input.extend(fibonacci_input(12));
+        let supplier = || Box::new(input.clone().into_iter()) as BoxedByteIterator;
+
+        let encoded: Vec<u8> = encode(supplier, None, None).unwrap().collect();
+        let mut header_iter = Box::new(encoded.clone().into_iter()) as BoxedByteIterator;
+        let header = Header::decode(&mut bit_reader(&mut header_iter), false, 256);
+        assert!(header.unwrap()?.code_descriptors.len() > 1);
+
+        let encoded_iter = &mut (Box::new(encoded.into_iter()) as BoxedByteIterator);
+        let (decoded_iter, _) = decode(encoded_iter, false).unwrap()?;
+        let decoded: Vec<u8> = decoded_iter.map(|x| x.unwrap()).collect();
+        assert_eq!(input, decoded);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_encoded_symbols() -> Result<()> {
+        // runs of zeros coded as in bzip2 leave 258 letters
+        let input: Vec<u16> = (0..5000u32).map(|x| (x * x % 7919 % 258) as u16).collect();
+        let encoded: Vec<u8> = encode_symbols(input.clone(), 258, Some(3), None)
+            .unwrap()
+            .collect();
+
+        let encoded_iter = &mut (Box::new(encoded.clone().into_iter()) as BoxedByteIterator);
+        let (decoded_iter, initial) = decode_symbols::<u16>(encoded_iter, true, 258).unwrap()?;
+        let decoded: Vec<u16> = decoded_iter.map(|x| x.unwrap()).collect();
+        assert_eq!(input, decoded);
+        assert_eq!(initial, Some(3));
+
+        let encoded_iter = &mut (Box::new(encoded.clone().into_iter()) as BoxedByteIterator);
+        assert_eq!(read_symbols_block(encoded_iter, true, 258), Some(encoded));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_invalid_code() {
+        // the only code is `0`, the last byte holds nothing but its occurrences
+        let input = "aaaaaaaaaaaaaaaa".bytes();
+        let mut corrupted: Vec<u8> = encode(|| Box::new(input.clone()), None, None)
+            .unwrap()
+            .collect();
+        *corrupted.last_mut().unwrap() = u8::MAX;
+
+        let encoded_iter = &mut (Box::new(corrupted.into_iter()) as BoxedByteIterator);
+        let (decoded_iter, _) = decode(encoded_iter, false).unwrap().unwrap();
+        assert!(decoded_iter.collect::<Result<Vec<u8>>>().is_err());
+    }
+
+    #[test]
+    fn chain_test() {
+        // `a` is 0, `b` 10 and `c` 11, read from the lowest bit
+        let codes = [
+            (0b0, 1, b'a' as u16),
+            (0b01, 2, b'b' as u16),
+            (0b11, 2, b'c' as u16),
+        ];
+        let mut entries = Vec::new();
+        let bits = Lookup::add(&mut entries, &codes);
+        Lookup::chain(&mut entries);
+        assert_eq!(bits, 2);
+
+        let letters = |index: usize| match entries[index] {
+            Entry::Letters {
+                letters,
+                count,
+                len,
+                ..
+            } => (letters[..count as usize].to_vec(), len),
+            entry => panic!("{:?}", entry),
+        };
+        assert_eq!(letters(0b00), (b"aa".map(u16::from).to_vec(), 2));
+        assert_eq!(letters(0b10), (vec![b'a' as u16], 1));
+        assert_eq!(letters(0b01), (vec![b'b' as u16], 2));
+        assert_eq!(letters(0b11), (vec![b'c' as u16], 2));
+    }
+
+    #[test]
+    fn decode_encoded_chained_across_groups() -> Result<()> {
+        // mostly one-bit codes, which fill the tables with several letters each
+        let input: Vec<u8> = (0..1000u32)
+            .map(|x| (x % 7 == 0) as u8 * (x % 3) as u8)
+            .collect();
+        let supplier = || Box::new(input.clone().into_iter()) as BoxedByteIterator;
+        let encoded_iter = &mut Box::new(encode(supplier, None, None).unwrap());
+        let (decoded_iter, _) = decode(encoded_iter, false).unwrap()?;
+        let decoded: Vec<u8> = decoded_iter.map(|x| x.unwrap()).collect();
+        assert_eq!(input, decoded);
+        Ok(())
+    }
+
+    #[test]
+    fn read_block_test() {
+        let first = "abbcccdddddeeoifhweag128138y2o".as_bytes();
+        let second = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbba".as_bytes();
+        let first_encoded: Vec<u8> = encode(|| Box::new(first.iter().copied()), Some(0), None)
+            .unwrap()
+            .collect();
+        let second_encoded: Vec<u8> = encode(|| Box::new(second.iter().copied()), Some(0), None)
+            .unwrap()
+            .collect();
+
+        let mut input_iter: BoxedByteIterator = Box::new(
+            first_encoded
+                .clone()
+                .into_iter()
+                .chain(second_encoded.clone()),
+        );
+
+        assert_eq!(read_block(&mut input_iter, true), Some(first_encoded));
+        assert_eq!(read_block(&mut input_iter, true), Some(second_encoded));
+        assert_eq!(read_block(&mut input_iter, true), None);
+    }
 }
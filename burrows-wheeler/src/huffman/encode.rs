@@ -1,113 +1,336 @@
-use super::header::Header;
-use super::BoxedByteIterator;
+use super::header::{canonical_codes, Header};
+use super::{BoxedByteIterator, GROUP_SIZE, MAX_TABLES};
+use crate::bitstream::{BitOrder, BitWriter};
 use crate::config::Index;
-use crate::huffman::iterator::ByteIterator;
+use crate::result::{Error, Result};
 use bit_vec::BitVec;
 use priority_queue::PriorityQueue;
 use std::collections::HashMap;
+use std::io::Result as IoResult;
 
-pub fn encode<F>(input_iter_supplier: F, initial: Index) -> BoxedByteIterator
+const ITERATIONS: usize = 4;
+
+pub fn encode<F>(
+    input_iter_supplier: F,
+    initial: Option<Index>,
+    max_code_len: Option<u8>,
+) -> Result<BoxedByteIterator>
 where
     F: Fn() -> BoxedByteIterator,
 {
-    let frequencies = count_frequency(input_iter_supplier());
-    let codes = build_codes(&frequencies);
-    let iter = iter(frequencies, input_iter_supplier(), codes, initial);
-    Box::new(iter)
+    encode_symbols(input_iter_supplier().collect(), 256, initial, max_code_len)
+}
+
+// Every symbol has to be a letter of the alphabet, the header only describes that many of them.
+pub fn encode_symbols<S: Copy + Into<u16> + 'static>(
+    input: Vec<S>,
+    alphabet_size: usize,
+    initial: Option<Index>,
+    max_code_len: Option<u8>,
+) -> Result<BoxedByteIterator> {
+    let frequencies = count_frequency(input.iter().copied(), alphabet_size);
+    let (lengths, selectors, bit_size) = choose_tables(&input, &frequencies, max_code_len)?;
+    let header = Header::encode(&lengths, &selectors, bit_size, initial);
+
+    let codes: Vec<HashMap<u16, BitVec>> = lengths.iter().map(|table| build_codes(table)).collect();
+    let encoded = write(&header, &input, &codes, &selectors)?;
+    Ok(Box::new(encoded.into_iter()))
+}
+
+// Several tables only pay off if they save more than their own lengths and the selectors. Along
+// with the tables and the selectors comes the size of the coded input.
+pub(super) fn choose_tables<S: Copy + Into<u16>>(
+    input: &[S],
+    letter_frequency: &[u64],
+    max_code_len: Option<u8>,
+) -> Result<(Vec<Vec<u8>>, Vec<u8>, usize)> {
+    let tables = table_count(input.len());
+    let candidates = std::iter::once(1)
+        .chain(Some(tables).filter(|tables| *tables > 1))
+        .map(|tables| {
+            let (lengths, selectors) = fit_tables(input, letter_frequency, tables, max_code_len)?;
+            let bit_size = bit_size(input, &lengths, &selectors);
+            let header_size = Header::encode(&lengths, &selectors, bit_size, None).len();
+            Ok((lengths, selectors, bit_size, header_size))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let (lengths, selectors, bit_size, _) = candidates
+        .into_iter()
+        .min_by_key(|(_, _, bit_size, header_size)| header_size + bit_size)
+        .unwrap();
+    Ok((lengths, selectors, bit_size))
 }
 
 #[derive(PartialEq, Eq, Hash)]
 enum HuffmanNode {
-    Leaf(u8),
+    Leaf(usize),
     InnerVertex(Box<HuffmanNode>, Box<HuffmanNode>),
 }
 
-fn vec_with(vec: &BitVec, element: bool) -> BitVec {
-    let mut new_vec = vec.clone();
-    new_vec.push(element);
-    new_vec
+pub(super) fn count_frequency<S: Into<u16>>(
+    input_iter: impl Iterator<Item = S>,
+    alphabet_size: usize,
+) -> Vec<u64> {
+    let mut letter_frequency = vec![0; alphabet_size];
+    for letter in input_iter {
+        letter_frequency[letter.into() as usize] += 1;
+    }
+    letter_frequency
 }
 
-fn count_frequency(input_iter: impl Iterator<Item = u8>) -> HashMap<u8, u64> {
-    let mut letter_frequency = HashMap::new();
-    for byte in input_iter {
-        *letter_frequency.entry(byte).or_insert(0) += 1;
+fn table_count(len: usize) -> usize {
+    match len {
+        0..=199 => 1,
+        200..=599 => 3,
+        600..=1199 => 4,
+        1200..=2399 => 5,
+        _ => MAX_TABLES,
     }
-    letter_frequency
 }
 
-fn build_codes(letter_frequency: &HashMap<u8, u64>) -> HashMap<u8, BitVec> {
-    let mut queue = PriorityQueue::<Box<HuffmanNode>, i64>::with_capacity(letter_frequency.len());
+// The tables start from letter ranges of about the same frequency. Then every group picks the
+// table that codes it the shortest and the tables are rebuilt from the groups that picked them.
+fn fit_tables<S: Copy + Into<u16>>(
+    input: &[S],
+    letter_frequency: &[u64],
+    tables: usize,
+    max_code_len: Option<u8>,
+) -> Result<(Vec<Vec<u8>>, Vec<u8>)> {
+    let groups = input.len().div_ceil(GROUP_SIZE);
+    if tables == 1 {
+        return Ok((
+            vec![code_lengths(letter_frequency, max_code_len)?],
+            vec![0; groups],
+        ));
+    }
+
+    let alphabet_size = letter_frequency.len();
+    let mut lengths = vec![vec![0; alphabet_size]; tables];
+    let mut remaining = input.len() as u64;
+    let mut letter = 0;
+    for (i, table) in lengths.iter_mut().enumerate() {
+        let target = remaining / (tables - i) as u64;
+        let (start, mut sum) = (letter, 0);
+        while letter < alphabet_size && (sum < target || i == tables - 1) {
+            sum += letter_frequency[letter];
+            letter += 1;
+        }
+        for (x, len) in table.iter_mut().enumerate() {
+            *len = if (start..letter).contains(&x) { 0 } else { 15 };
+        }
+        remaining -= sum;
+    }
+
+    let mut selectors = vec![0; groups];
+    for _ in 0..ITERATIONS {
+        let mut table_frequencies = vec![vec![0; alphabet_size]; tables];
+        for (group, selector) in input.chunks(GROUP_SIZE).zip(selectors.iter_mut()) {
+            let best = (0..tables)
+                .min_by_key(|table| {
+                    group
+                        .iter()
+                        .map(|x| lengths[*table][(*x).into() as usize] as u64)
+                        .sum::<u64>()
+                })
+                .unwrap();
+            *selector = best as u8;
+            for x in group {
+                table_frequencies[best][(*x).into() as usize] += 1;
+            }
+        }
+
+        // letters of the block a table has not seen still need a code in it
+        lengths = table_frequencies
+            .iter()
+            .map(|table_frequency| {
+                let mut weights = vec![0; alphabet_size];
+                for (x, weight) in weights.iter_mut().enumerate() {
+                    if letter_frequency[x] > 0 {
+                        *weight = (table_frequency[x] << 8).max(1);
+                    }
+                }
+                code_lengths(&weights, max_code_len)
+            })
+            .collect::<Result<_>>()?;
+    }
+
+    Ok((lengths, selectors))
+}
 
-    for (letter, number) in letter_frequency {
-        queue.push(Box::new(HuffmanNode::Leaf(*letter)), -(*number as i64));
+pub(super) fn code_lengths(letter_frequency: &[u64], max_code_len: Option<u8>) -> Result<Vec<u8>> {
+    let lengths = build_code_lengths(letter_frequency);
+    match max_code_len {
+        Some(max_code_len) if lengths.iter().any(|length| *length > max_code_len) => {
+            build_limited_code_lengths(letter_frequency, max_code_len)
+        }
+        _ => Ok(lengths),
     }
+}
 
-    let mut codes = HashMap::new();
+fn bit_size<S: Copy + Into<u16>>(input: &[S], lengths: &[Vec<u8>], selectors: &[u8]) -> usize {
+    input
+        .chunks(GROUP_SIZE)
+        .zip(selectors)
+        .map(|(group, selector)| {
+            let lengths = &lengths[*selector as usize];
+            group
+                .iter()
+                .map(|x| lengths[(*x).into() as usize] as usize)
+                .sum::<usize>()
+        })
+        .sum()
+}
+
+// Only the lengths of the codes matter, the codes themselves are canonical. Ties are broken by the
+// order the nodes were created in, so the same input always gets the same lengths.
+fn build_code_lengths(letter_frequency: &[u64]) -> Vec<u8> {
+    let mut queue = PriorityQueue::<Box<HuffmanNode>, (i64, i64)>::new();
+
+    let mut id = 0;
+    for (letter, number) in letter_frequency.iter().enumerate() {
+        if *number > 0 {
+            queue.push(
+                Box::new(HuffmanNode::Leaf(letter)),
+                (-(*number as i64), -id),
+            );
+            id += 1;
+        }
+    }
+
+    let mut lengths = vec![0; letter_frequency.len()];
     if queue.is_empty() {
-        return codes;
+        return lengths;
     }
 
     while queue.len() != 1 {
-        let (left, left_priority) = queue.pop().unwrap();
-        let (right, right_priority) = queue.pop().unwrap();
+        let (left, (left_priority, _)) = queue.pop().unwrap();
+        let (right, (right_priority, _)) = queue.pop().unwrap();
         queue.push(
             Box::new(HuffmanNode::InnerVertex(left, right)),
-            left_priority + right_priority,
+            (left_priority + right_priority, -id),
         );
+        id += 1;
     }
 
     let (root, _) = queue.pop().unwrap();
-    update_codes(&mut codes, *root, BitVec::new());
-    codes
+    update_lengths(&mut lengths, *root, 0);
+    lengths
 }
 
-fn update_codes(codes: &mut HashMap<u8, BitVec>, node: HuffmanNode, code: BitVec) {
+fn update_lengths(lengths: &mut [u8], node: HuffmanNode, length: u8) {
     match node {
-        HuffmanNode::Leaf(letter) => {
-            codes.insert(letter, code);
-        }
+        // a single letter still needs a bit per occurrence
+        HuffmanNode::Leaf(letter) => lengths[letter] = length.max(1),
         HuffmanNode::InnerVertex(left, right) => {
-            update_codes(codes, *left, vec_with(&code, false));
-            update_codes(codes, *right, vec_with(&code, true));
+            update_lengths(lengths, *left, length + 1);
+            update_lengths(lengths, *right, length + 1);
         }
     }
 }
 
-fn iter(
-    letter_frequency: HashMap<u8, u64>,
-    input_iter: impl Iterator<Item = u8> + 'static,
-    codes: HashMap<u8, BitVec>,
-    initial: Index,
-) -> impl Iterator<Item = u8> {
-    let header = Header::encode(letter_frequency, &codes, initial);
-    let header_iter = std::iter::once(header);
-
-    let coded_iter = EncodingIterator {
-        input_iter: Box::new(input_iter),
-        codes,
-    };
-
-    ByteIterator::new(Box::new(header_iter.chain(coded_iter)))
+// Package-merge: the cheapest set of 2n - 2 items from the leaves merged with packages of pairs
+// of items from the level below, over `max_code_len` levels. Every time a leaf is chosen its
+// code gets a bit longer. Leaves are taken in order of frequency, so it is enough to know how
+// many leaves and packages each level contributes.
+fn build_limited_code_lengths(letter_frequency: &[u64], max_code_len: u8) -> Result<Vec<u8>> {
+    let mut leaves: Vec<(u64, usize)> = letter_frequency
+        .iter()
+        .enumerate()
+        .filter(|(_, number)| **number > 0)
+        .map(|(letter, number)| (*number, letter))
+        .collect();
+    leaves.sort();
+
+    let mut lengths = vec![0; letter_frequency.len()];
+    if leaves.len() < 2 {
+        for (_, letter) in leaves {
+            lengths[letter] = 1;
+        }
+        return Ok(lengths);
+    }
+    if leaves.len() as u64 > 1 << max_code_len {
+        return Err(Error::new("Code length limit too small for the alphabet"));
+    }
+
+    // for every level from the deepest one, whether each of its items is a package
+    let mut levels: Vec<Vec<bool>> = Vec::with_capacity(max_code_len as usize);
+    let mut previous: Vec<u64> = Vec::new();
+    for _ in 0..max_code_len {
+        let packages: Vec<u64> = previous.chunks_exact(2).map(|x| x[0] + x[1]).collect();
+        let mut merged = Vec::with_capacity(leaves.len() + packages.len());
+        let mut is_package = Vec::with_capacity(leaves.len() + packages.len());
+        let (mut i, mut j) = (0, 0);
+        while i < leaves.len() || j < packages.len() {
+            if j == packages.len() || (i < leaves.len() && leaves[i].0 <= packages[j]) {
+                merged.push(leaves[i].0);
+                is_package.push(false);
+                i += 1;
+            } else {
+                merged.push(packages[j]);
+                is_package.push(true);
+                j += 1;
+            }
+        }
+        levels.push(is_package);
+        previous = merged;
+    }
+
+    let mut taken = 2 * leaves.len() - 2;
+    for is_package in levels.iter().rev() {
+        let packages = is_package[..taken].iter().filter(|x| **x).count();
+        for (_, letter) in &leaves[..taken - packages] {
+            lengths[*letter] += 1;
+        }
+        taken = 2 * packages;
+    }
+
+    Ok(lengths)
 }
 
-struct EncodingIterator {
-    input_iter: BoxedByteIterator,
-    codes: HashMap<u8, BitVec>,
+fn build_codes(lengths: &[u8]) -> HashMap<u16, BitVec> {
+    canonical_codes(lengths)
+        .into_iter()
+        .map(|code_descriptor| (code_descriptor.letter, code_descriptor.code))
+        .collect()
 }
 
-impl Iterator for EncodingIterator {
-    type Item = BitVec;
+// The first bit of a code is the lowest one, as it is written first.
+fn write<S: Copy + Into<u16>>(
+    header: &BitVec,
+    input: &[S],
+    codes: &[HashMap<u16, BitVec>],
+    selectors: &[u8],
+) -> IoResult<Vec<u8>> {
+    let codes: Vec<HashMap<u16, (u64, u32)>> = codes
+        .iter()
+        .map(|codes| {
+            codes
+                .iter()
+                .map(|(letter, code)| {
+                    let value = code
+                        .iter()
+                        .rev()
+                        .fold(0, |value, bit| value << 1 | bit as u64);
+                    (*letter, (value, code.len() as u32))
+                })
+                .collect()
+        })
+        .collect();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.input_iter.next() {
-            None => None,
-            Some(byte) => match self.codes.get(&byte) {
+    let mut writer = BitWriter::new(Vec::new(), BitOrder::LsbFirst);
+    for bit in header {
+        writer.write_bit(bit)?;
+    }
+    for (group, selector) in input.chunks(GROUP_SIZE).zip(selectors) {
+        let codes = &codes[*selector as usize];
+        for letter in group {
+            match codes.get(&(*letter).into()) {
                 None => panic!("Data changed invalidating header: unexpected letter"),
-                Some(code) => Some(code.clone()),
-            },
+                Some((code, len)) => writer.write_bits(*code, *len)?,
+            }
         }
     }
+    writer.finish()
 }
 
 #[cfg(test)]
@@ -131,6 +354,18 @@ mod tests {
         };
     }
 
+    macro_rules! frequencies {
+        ($($letter:expr => $number:expr),* $(,)?) => {
+            {
+                let mut frequencies = [0; 256];
+                $(
+                    frequencies[$letter as usize] = $number;
+                )*
+                frequencies
+            }
+        };
+    }
+
     macro_rules! code {
         ( $( $x:expr ),* ) => {
             {
@@ -144,58 +379,167 @@ mod tests {
     #[test]
     fn count_frequency_test() {
         let input = "abbcccdddd";
-        let frequencies = count_frequency(input.bytes());
+        let frequencies = count_frequency(input.bytes(), 256);
 
-        let expected = hashmap! {
+        let expected = frequencies! {
             b'a' => 1,
             b'b' => 2,
             b'c' => 3,
             b'd' => 4,
         };
 
-        assert_eq!(expected, frequencies)
+        assert_eq!(frequencies, expected)
     }
 
     #[test]
     fn build_codes_test() {
-        let letter_frequency = hashmap! {
+        let letter_frequency = frequencies! {
             b'a' => 1,
             b'b' => 2,
             b'c' => 3,
             b'd' => 4,
         };
 
-        let codes = build_codes(&letter_frequency);
+        let codes = build_codes(&build_code_lengths(&letter_frequency));
 
         let expected = hashmap! {
-            b'a' => code![1, 1, 0],
-            b'b' => code![1, 1, 1],
-            b'c' => code![1, 0],
-            b'd' => code![0],
+            b'a' as u16 => code![1, 1, 0],
+            b'b' as u16 => code![1, 1, 1],
+            b'c' as u16 => code![1, 0],
+            b'd' as u16 => code![0],
         };
 
         assert_eq!(expected, codes)
     }
 
     #[test]
-    fn encode_test() {
-        let codes = hashmap! {
-            b'a' => code![1, 1, 0],
-            b'b' => code![1, 1, 1],
-            b'c' => code![1, 0],
-            b'd' => code![0],
+    fn build_code_lengths_test() {
+        let letter_frequency = [1; 256];
+        assert!(build_code_lengths(&letter_frequency)
+            .iter()
+            .all(|length| *length == 8));
+
+        let letter_frequency = frequencies! { b'a' => 10 };
+        let lengths = build_code_lengths(&letter_frequency);
+        assert_eq!(lengths[b'a' as usize], 1);
+        assert_eq!(lengths.iter().filter(|x| **x > 0).count(), 1);
+    }
+
+    fn fibonacci_frequency(len: usize) -> [u64; 256] {
+        let mut frequencies = [0; 256];
+        let (mut previous, mut current) = (1, 1);
+        for frequency in frequencies.iter_mut().take(len) {
+            *frequency = current;
+            (previous, current) = (current, previous + current);
+        }
+        frequencies
+    }
+
+    fn kraft_sum(lengths: &[u8]) -> u128 {
+        lengths
+            .iter()
+            .filter(|length| **length > 0)
+            .map(|length| 1u128 << (64 - length))
+            .sum()
+    }
+
+    fn cost(letter_frequency: &[u64], lengths: &[u8]) -> u64 {
+        letter_frequency
+            .iter()
+            .zip(lengths.iter())
+            .map(|(number, length)| number * *length as u64)
+            .sum()
+    }
+
+    #[test]
+    fn build_limited_code_lengths_test() {
+        let letter_frequency = fibonacci_frequency(60);
+        let unlimited = build_code_lengths(&letter_frequency);
+        assert_eq!(*unlimited.iter().max().unwrap(), 59);
+
+        for max_code_len in [6, 8, 15, 20, 59, 63] {
+            let lengths = build_limited_code_lengths(&letter_frequency, max_code_len).unwrap();
+            assert!(lengths.iter().all(|length| *length <= max_code_len));
+            assert_eq!(kraft_sum(&lengths), 1 << 64);
+        }
+
+        let lengths = build_limited_code_lengths(&letter_frequency, 59).unwrap();
+        assert_eq!(
+            cost(&letter_frequency, &lengths),
+            cost(&letter_frequency, &unlimited)
+        );
+    }
+
+    #[test]
+    fn build_limited_code_lengths_optimal_test() {
+        let letter_frequency = frequencies! {
+            b'a' => 1,
+            b'b' => 1,
+            b'c' => 2,
+            b'd' => 4,
+            b'e' => 8,
         };
 
-        let input = "abbcccddddddddd";
-        let encoded: Vec<u8> = iter(HashMap::new(), input.bytes(), codes, 0)
-            .skip(((Index::BITS + usize::BITS) / 8) as usize)
+        // lengths 4, 4, 3, 2, 1 cost 30, the best with at most 3 bits is 3, 3, 3, 3, 1
+        let lengths = build_limited_code_lengths(&letter_frequency, 3).unwrap();
+        assert_eq!(cost(&letter_frequency, &lengths), 32);
+        assert_eq!(kraft_sum(&lengths), 1 << 64);
+        assert!(build_limited_code_lengths(&letter_frequency, 2).is_err());
+
+        let lengths = build_limited_code_lengths(&frequencies! { b'a' => 5 }, 8).unwrap();
+        assert_eq!(lengths[b'a' as usize], 1);
+    }
+
+    #[test]
+    fn fit_tables_test() {
+        let mut input = Vec::new();
+        for i in 0..100 {
+            let letters: &[u8] = if i % 10 < 5 { b"aabc" } else { b"xyzz" };
+            for j in 0..GROUP_SIZE {
+                input.push(letters[(i + j * 7) % letters.len()]);
+            }
+        }
+
+        let frequencies = count_frequency(input.iter().copied(), 256);
+        let (lengths, selectors) = fit_tables(&input, &frequencies, 2, None).unwrap();
+        assert_eq!(selectors.len(), 100);
+        for (i, selector) in selectors.iter().enumerate() {
+            assert_eq!(*selector, selectors[i % 10 / 5 * 5]);
+        }
+        assert_ne!(selectors[0], selectors[5]);
+        assert!(lengths
+            .iter()
+            .all(|table| (0..256).all(|x| (table[x] > 0) == (frequencies[x] > 0))));
+
+        let (single, _) = fit_tables(&input, &frequencies, 1, None).unwrap();
+        assert!(bit_size(&input, &lengths, &selectors) < bit_size(&input, &single, &[0; 100]));
+    }
+
+    #[test]
+    fn deterministic_test() {
+        let input = "abbcccddddeeeeffffgggg".as_bytes();
+        let encoded: Vec<u8> = encode(|| Box::new(input.iter().copied()), None, None)
+            .unwrap()
             .collect();
+        for _ in 0..10 {
+            assert!(encode(|| Box::new(input.iter().copied()), None, None)
+                .unwrap()
+                .eq(encoded.iter().copied()));
+        }
+    }
+
+    #[test]
+    fn encode_test() {
+        let codes = hashmap! {
+            b'a' as u16 => code![1, 1, 0],
+            b'b' as u16 => code![1, 1, 1],
+            b'c' as u16 => code![1, 0],
+            b'd' as u16 => code![0],
+        };
 
-        let expected = vec![
-            0b10011010, 0b01101000, 0b10101100, 0b01100001, 0b10100011, 0b11111101, 0b00010101,
-            0b00000000,
-        ];
+        let input = "abbcccddddddddd".as_bytes();
+        let encoded = write(&BitVec::new(), input, &[codes], &[0]).unwrap();
 
-        assert_eq!(encoded, expected)
+        assert_eq!(encoded, vec![0b11111011, 0b00101011, 0b00000000])
     }
 }
@@ -1,148 +1,263 @@
-use super::iterator::BitIterator;
-use crate::config::Index;
+use super::code::canonical;
+use super::BitReader;
+use super::MAX_TABLES;
+use crate::config::{Index, MAX_CODE_LEN};
+use crate::result::{Error, Result};
 use bit_vec::BitVec;
-use std::cmp::Ordering;
-use std::collections::HashMap;
+
+const LEN_BITS: u32 = 6;
+const LETTER_GROUP_SIZE: usize = 16;
+const TABLES_BITS: u32 = 3;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct CodeDescriptor {
     pub code: BitVec,
-    pub letter: u8,
+    pub letter: u16,
 }
 
 #[derive(Debug)]
 pub struct Header {
-    pub code_descriptors: Vec<CodeDescriptor>,
+    pub code_descriptors: Vec<Vec<CodeDescriptor>>,
+    pub selectors: Vec<u8>,
     pub bit_size: usize,
-    pub initial: Index,
+    pub initial: Option<Index>,
+}
+
+pub fn canonical_codes(lengths: &[u8]) -> Vec<CodeDescriptor> {
+    let lengths = lengths
+        .iter()
+        .enumerate()
+        .map(|(letter, len)| (letter as u16, *len));
+    canonical(lengths)
+        .into_iter()
+        .map(|(letter, code)| CodeDescriptor { code, letter })
+        .collect()
 }
 
 impl Header {
+    // Every table is used for the groups whose selectors point to it. The selectors are written
+    // only when there are several tables, move-to-front coded and in unary.
+    //
+    // The lengths are stored for the used letters only, found from a bitmap of groups of letters
+    // followed by a bitmap of every used group. Every table has a length for each of them, written
+    // as steps from the previous one: `10` increments, `11` decrements and `0` moves to the next
+    // letter. The alphabet size is not stored, the decoder has to know it.
     pub fn encode(
-        letter_frequency: HashMap<u8, u64>,
-        codes: &HashMap<u8, BitVec>,
-        initial: Index,
+        lengths: &[Vec<u8>],
+        selectors: &[u8],
+        bit_size: usize,
+        initial: Option<Index>,
     ) -> BitVec {
         let mut result = BitVec::new();
-        Header::add_bytes(&mut result, &initial.to_be_bytes());
-
-        let mut bit_size = 0;
-        for (k, v) in letter_frequency {
-            bit_size += codes[&k].len() * (v as usize);
+        if let Some(initial) = initial {
+            Header::add_bytes(&mut result, &initial.to_be_bytes());
         }
         Header::add_bytes(&mut result, &bit_size.to_be_bytes());
 
-        let mut tree = codes.iter().collect::<Vec<_>>();
-        tree.sort_by(|(_, first_code), (_, second_code)| {
-            let min_len = std::cmp::min(first_code.len(), second_code.len());
-            let bit_compare = first_code
-                .iter()
-                .take(min_len)
-                .cmp(second_code.iter().take(min_len));
-
-            match bit_compare {
-                Ordering::Equal if first_code.len() == second_code.len() => Ordering::Equal,
-                Ordering::Equal
-                    if (first_code.len() > second_code.len() && !first_code[min_len])
-                        || (first_code.len() < second_code.len() && second_code[min_len]) =>
-                {
-                    Ordering::Less
+        Header::add_bits(&mut result, lengths.len() as u64, TABLES_BITS);
+        if lengths.len() > 1 {
+            Header::add_bytes(&mut result, &(selectors.len() as u32).to_be_bytes());
+            let mut order: Vec<u8> = (0..lengths.len() as u8).collect();
+            for selector in selectors {
+                let rank = order.iter().position(|x| x == selector).unwrap();
+                for _ in 0..rank {
+                    result.push(true);
                 }
-                Ordering::Equal => Ordering::Greater,
-                otherwise => otherwise,
+                result.push(false);
+                order[..=rank].rotate_right(1);
             }
-        });
+        }
 
-        let mut current_len = 0;
+        let alphabet_size = lengths.first().map_or(0, |table| table.len());
+        let used: Vec<bool> = (0..alphabet_size)
+            .map(|letter| lengths.iter().any(|table| table[letter] > 0))
+            .collect();
+        let groups: Vec<&[bool]> = used.chunks(LETTER_GROUP_SIZE).collect();
+        for group in groups.iter() {
+            result.push(group.contains(&true));
+        }
+        for group in groups.iter().filter(|group| group.contains(&true)) {
+            for used in group.iter() {
+                result.push(*used);
+            }
+        }
 
-        for (byte, code) in tree.into_iter() {
-            for _ in current_len..code.len() {
+        for table in lengths {
+            let mut used = (0..alphabet_size)
+                .filter(|x| used[*x])
+                .map(|x| table[x])
+                .peekable();
+            let mut current = match used.peek() {
+                Some(first) => *first,
+                None => break,
+            };
+            Header::add_bits(&mut result, current as u64, LEN_BITS);
+
+            for len in used {
+                while current < len {
+                    result.push(true);
+                    result.push(false);
+                    current += 1;
+                }
+                while current > len {
+                    result.push(true);
+                    result.push(true);
+                    current -= 1;
+                }
                 result.push(false);
             }
-            result.push(true);
-            Header::add_byte(&mut result, *byte);
-
-            current_len = code
-                .iter()
-                .rev()
-                .position(|x| !x)
-                .map(|x| code.len() - x)
-                .unwrap_or(code.len() - 1);
         }
 
         result
     }
 
-    fn add_byte(bitvec: &mut BitVec, byte: u8) {
-        for i in (0..u8::BITS).rev() {
-            bitvec.push((byte >> i) & 1 == 1);
+    fn add_bits(bitvec: &mut BitVec, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            bitvec.push((value >> i) & 1 == 1);
         }
     }
 
     fn add_bytes(bitvec: &mut BitVec, bytes: &[u8]) {
         for i in bytes {
-            Header::add_byte(bitvec, *i);
+            Header::add_bits(bitvec, *i as u64, u8::BITS);
+        }
+    }
+
+    // Values are written from their highest bit.
+    fn read_bits(input_iter: &mut BitReader<'_>, bits: u32) -> Result<u64> {
+        let mut value = 0;
+        for i in (0..bits).rev() {
+            value |= (input_iter.read_bit()? as u64) << i;
         }
+        Ok(value)
     }
 
-    pub fn decode(input_iter: &mut Box<BitIterator<'_>>) -> Option<Header> {
-        let mut initial: Index = 0;
-        for i in (0..Index::BITS).rev() {
-            initial |= (input_iter.next()? as Index) << i;
+    // Nothing once the input has ended, a header cut short is an error.
+    pub fn decode(
+        input_iter: &mut BitReader<'_>,
+        with_initial: bool,
+        alphabet_size: usize,
+    ) -> Option<Result<Header>> {
+        match input_iter.peek(1) {
+            Ok((_, 0)) => None,
+            Ok(_) => Some(Header::read(input_iter, with_initial, alphabet_size)),
+            Err(err) => Some(Err(err)),
         }
+    }
 
-        let mut bit_size: usize = 0;
-        for i in (0..usize::BITS).rev() {
-            bit_size |= (input_iter.next()? as usize) << i;
+    fn read(
+        input_iter: &mut BitReader<'_>,
+        with_initial: bool,
+        alphabet_size: usize,
+    ) -> Result<Header> {
+        let mut initial = None;
+        if with_initial {
+            initial = Some(Header::read_bits(input_iter, Index::BITS)? as Index);
         }
+        let bit_size = Header::read_bits(input_iter, usize::BITS)? as usize;
 
-        let mut code_descriptors = Vec::new();
-        let mut current = BitVec::new();
+        let tables = Header::read_bits(input_iter, TABLES_BITS)? as usize;
+        if tables == 0 || tables > MAX_TABLES {
+            return Err(Error::new("Invalid number of tables"));
+        }
 
-        while {
-            while !input_iter.next()? {
-                current.push(false);
+        let mut selectors = Vec::new();
+        if tables > 1 {
+            let len = Header::read_bits(input_iter, u32::BITS)?;
+            let mut order: Vec<u8> = (0..tables as u8).collect();
+            for _ in 0..len {
+                let mut rank = 0;
+                while input_iter.read_bit()? {
+                    rank += 1;
+                    if rank == tables {
+                        return Err(Error::new("Invalid selector"));
+                    }
+                }
+                selectors.push(order[rank]);
+                order[..=rank].rotate_right(1);
             }
+        }
 
-            let mut byte: u8 = 0;
-            for i in (0..u8::BITS).rev() {
-                byte |= (input_iter.next()? as u8) << i;
+        let mut groups = vec![false; alphabet_size.div_ceil(LETTER_GROUP_SIZE)];
+        for group in groups.iter_mut() {
+            *group = input_iter.read_bit()?;
+        }
+
+        let mut used = Vec::new();
+        for (group, _) in groups.iter().enumerate().filter(|(_, used)| **used) {
+            let end = ((group + 1) * LETTER_GROUP_SIZE).min(alphabet_size);
+            for letter in group * LETTER_GROUP_SIZE..end {
+                if input_iter.read_bit()? {
+                    used.push(letter);
+                }
             }
+        }
+
+        let mut code_descriptors = Vec::with_capacity(tables);
+        for _ in 0..tables {
+            let lengths = Header::read_lengths(input_iter, &used, alphabet_size)?;
+            code_descriptors.push(canonical_codes(&lengths));
+        }
 
-            code_descriptors.push(CodeDescriptor {
-                code: current.clone(),
-                letter: byte,
-            });
-
-            let condition = current.all();
-            current = current
-                .iter()
-                .rev()
-                .skip_while(|x| *x)
-                .skip(1)
-                .collect::<BitVec>()
-                .into_iter()
-                .rev()
-                .collect::<BitVec>();
-            current.push(true);
-
-            !condition
-        } {}
-
-        let header = Header {
+        Ok(Header {
             initial,
             bit_size,
             code_descriptors,
-        };
+            selectors,
+        })
+    }
+
+    fn read_lengths(
+        input_iter: &mut BitReader<'_>,
+        used: &[usize],
+        alphabet_size: usize,
+    ) -> Result<Vec<u8>> {
+        let mut lengths = vec![0; alphabet_size];
+        if used.is_empty() {
+            return Ok(lengths);
+        }
+
+        let mut current = Header::read_bits(input_iter, LEN_BITS)? as u8;
+        for letter in used {
+            while input_iter.read_bit()? {
+                current = match input_iter.read_bit()? {
+                    false if current < MAX_CODE_LEN => current + 1,
+                    true if current > 1 => current - 1,
+                    _ => return Err(Error::new("Code length out of range")),
+                };
+            }
+            if current == 0 {
+                return Err(Error::new("Code length out of range"));
+            }
+            lengths[*letter] = current;
+        }
+
+        let kraft_sum: u128 = lengths
+            .iter()
+            .filter(|len| **len > 0)
+            .map(|len| 1u128 << (MAX_CODE_LEN - len))
+            .sum();
+        if kraft_sum > 1u128 << MAX_CODE_LEN {
+            return Err(Error::new("Code lengths exceed Kraft inequality"));
+        }
 
-        Some(header)
+        Ok(lengths)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::huffman::iterator::ByteIterator;
+    use crate::bitstream::{BitOrder, BitWriter};
+    use crate::huffman::bit_reader;
+
+    fn byte_iter(bits: BitVec) -> Box<dyn Iterator<Item = u8>> {
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::LsbFirst);
+        for bit in bits {
+            writer.write_bit(bit).unwrap();
+        }
+        Box::new(writer.finish().unwrap().into_iter())
+    }
 
     macro_rules! code {
         ( $( $x:expr ),* ) => {
@@ -154,50 +269,58 @@ mod test {
         };
     }
 
-    macro_rules! hashmap {
-        (@single $($x:tt)*) => (());
-        (@count $($rest:expr),*) => (<[()]>::len(&[$(hashmap!(@single $rest)),*]));
+    fn lengths() -> Vec<u8> {
+        let mut lengths = vec![0; 256];
+        lengths[b'a' as usize] = 3;
+        lengths[b'b' as usize] = 3;
+        lengths[b'c' as usize] = 2;
+        lengths[b'd' as usize] = 2;
+        lengths[b'e' as usize] = 2;
+        lengths
+    }
 
-        ($($key:expr => $value:expr,)+) => { hashmap!($($key => $value),+) };
-        ($($key:expr => $value:expr),*) => {
-            {
-                let cap = hashmap!(@count $($key),*);
-                let mut map = HashMap::with_capacity(cap);
-                $(
-                    map.insert($key, $value);
-                )*
-                map
-            }
-        };
+    fn expected() -> Vec<CodeDescriptor> {
+        vec![
+            CodeDescriptor {
+                letter: b'c'.into(),
+                code: code![0, 0],
+            },
+            CodeDescriptor {
+                letter: b'd'.into(),
+                code: code![0, 1],
+            },
+            CodeDescriptor {
+                letter: b'e'.into(),
+                code: code![1, 0],
+            },
+            CodeDescriptor {
+                letter: b'a'.into(),
+                code: code![1, 1, 0],
+            },
+            CodeDescriptor {
+                letter: b'b'.into(),
+                code: code![1, 1, 1],
+            },
+        ]
+    }
+
+    #[test]
+    fn canonical_codes_test() {
+        assert_eq!(canonical_codes(&lengths()), expected());
+        assert_eq!(canonical_codes(&[0; 256]), vec![]);
     }
 
     #[test]
     fn encode_test() {
-        let encoded = Header::encode(
-            hashmap! {
-                b'a' => 1,
-                b'b' => 1,
-                b'c' => 4,
-                b'd' => 4,
-                b'e' => 4,
-            },
-            &hashmap! {
-                b'a' => code![0, 0, 0],
-                b'b' => code![0, 0, 1],
-                b'c' => code![0, 1],
-                b'd' => code![1, 0],
-                b'e' => code![1, 1],
-            },
-            2,
-        );
+        let encoded = Header::encode(&[lengths()], &[], 0, Some(2));
         let encoded = encoded
             .into_iter()
             .skip((Index::BITS + usize::BITS) as usize)
             .collect::<BitVec>();
 
         let expected = code![
-            0, 0, 0, 1, 0, 1, 1, 0, 0, 0, 0, 1, 1, 0, 1, 1, 0, 0, 0, 1, 0, 1, 0, 1, 1, 0, 0, 0, 1,
-            1, 0, 1, 0, 1, 1, 0, 0, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 0, 1
+            0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 0
         ];
 
         assert_eq!(encoded, expected);
@@ -205,94 +328,69 @@ mod test {
 
     #[test]
     fn decode_test() {
-        let mut encoded = vec![0u8; ((Index::BITS + usize::BITS) / 8) as usize];
-        let encoded_descriptors: Vec<u8> = vec![
-            0b01101000, 0b11011000, 0b10101000, 0b10110001, 0b00100110, 0b01001101, 0b00000001,
-        ];
-        encoded_descriptors
-            .into_iter()
-            .for_each(|x| encoded.push(x));
+        let mut encoded = vec![false; (Index::BITS + usize::BITS) as usize];
+        encoded.extend(code![
+            0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 0
+        ]);
 
-        let mut byte_iter: Box<dyn Iterator<Item = u8>> = Box::new(encoded.into_iter());
-
-        let bit_iter = BitIterator::new(&mut byte_iter, usize::MAX);
-        let decoded = Header::decode(&mut Box::new(bit_iter));
-
-        let expected: Vec<CodeDescriptor> = vec![
-            CodeDescriptor {
-                letter: b'a',
-                code: code![0, 0, 0],
-            },
-            CodeDescriptor {
-                letter: b'b',
-                code: code![0, 0, 1],
-            },
-            CodeDescriptor {
-                letter: b'c',
-                code: code![0, 1],
-            },
-            CodeDescriptor {
-                letter: b'd',
-                code: code![1, 0],
-            },
-            CodeDescriptor {
-                letter: b'e',
-                code: code![1, 1],
-            },
-        ];
+        let mut byte_iter = byte_iter(encoded.into_iter().collect());
+        let decoded = Header::decode(&mut bit_reader(&mut byte_iter), true, 256);
 
-        assert_eq!(expected, decoded.unwrap().code_descriptors)
+        assert_eq!(vec![expected()], decoded.unwrap().unwrap().code_descriptors)
     }
 
     #[test]
     fn decode_encoded_test() {
-        let encoded = Header::encode(
-            hashmap! {
-                b'a' => 1,
-                b'b' => 1,
-                b'c' => 4,
-                b'd' => 4,
-                b'e' => 4,
-            },
-            &hashmap! {
-                b'a' => code![0, 0, 0],
-                b'b' => code![0, 0, 1],
-                b'c' => code![0, 1],
-                b'd' => code![1, 0],
-                b'e' => code![1, 1],
-            },
-            2,
-        );
+        let encoded = Header::encode(&[lengths()], &[], 30, Some(2));
 
-        let mut byte_iter: Box<dyn Iterator<Item = u8>> =
-            Box::new(ByteIterator::new(Box::new(std::iter::once(encoded))));
+        let mut byte_iter = byte_iter(encoded);
+        let decoded = Header::decode(&mut bit_reader(&mut byte_iter), true, 256)
+            .unwrap()
+            .unwrap();
 
-        let bit_iter = BitIterator::new(&mut byte_iter, usize::MAX);
-        let decoded = Header::decode(&mut Box::new(bit_iter));
+        assert_eq!(vec![expected()], decoded.code_descriptors);
+        assert_eq!(decoded.selectors, vec![]);
+        assert_eq!(decoded.bit_size, 30);
+        assert_eq!(decoded.initial, Some(2));
+    }
 
-        let expected: Vec<CodeDescriptor> = vec![
-            CodeDescriptor {
-                letter: b'a',
-                code: code![0, 0, 0],
-            },
-            CodeDescriptor {
-                letter: b'b',
-                code: code![0, 0, 1],
-            },
-            CodeDescriptor {
-                letter: b'c',
-                code: code![0, 1],
-            },
-            CodeDescriptor {
-                letter: b'd',
-                code: code![1, 0],
-            },
-            CodeDescriptor {
-                letter: b'e',
-                code: code![1, 1],
-            },
+    #[test]
+    fn decode_encoded_tables_test() {
+        let mut other = vec![0; 256];
+        other[b'a' as usize] = 1;
+        for letter in b'b'..=b'e' {
+            other[letter as usize] = 3;
+        }
+        let selectors = [1, 1, 0, 1, 0, 0];
+        let encoded = Header::encode(&[lengths(), other.clone()], &selectors, 42, None);
+
+        let mut byte_iter = byte_iter(encoded);
+        let decoded = Header::decode(&mut bit_reader(&mut byte_iter), false, 256)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(decoded.code_descriptors.len(), 2);
+        assert_eq!(decoded.code_descriptors[0], expected());
+        assert_eq!(decoded.code_descriptors[1], canonical_codes(&other));
+        assert_eq!(decoded.selectors, selectors);
+        assert_eq!(decoded.bit_size, 42);
+    }
+
+    #[test]
+    fn decode_invalid_test() {
+        let mut overfull = vec![0; 256];
+        overfull[..3].copy_from_slice(&[1, 1, 1]);
+        let invalid = [
+            Header::encode(&[overfull], &[], 0, None),
+            Header::encode(&vec![lengths(); 7], &[], 0, None),
         ];
 
-        assert_eq!(expected, decoded.unwrap().code_descriptors)
+        for encoded in invalid {
+            let mut byte_iter = byte_iter(encoded);
+            assert!(Header::decode(&mut bit_reader(&mut byte_iter), false, 256)
+                .unwrap()
+                .is_err());
+        }
     }
 }
@@ -1,9 +1,26 @@
+use crate::bitstream::{self, BitOrder, IterRead};
+
+pub mod adaptive;
+mod code;
 mod decode;
 mod encode;
 mod header;
-mod iterator;
+mod report;
+
+pub use code::Code;
+pub use decode::{decode, decode_symbols, read_block, read_symbols_block};
+pub use encode::{encode, encode_symbols};
+pub use report::{BlockReport, TableReport};
 
-pub use decode::decode;
-pub use encode::encode;
+// Symbols are coded in groups, each with one of up to `MAX_TABLES` tables.
+const GROUP_SIZE: usize = 50;
+const MAX_TABLES: usize = 6;
 
 pub type BoxedByteIterator = Box<dyn Iterator<Item = u8>>;
+
+// Blocks are read bit by bit from the bytes of the stream, which go on with the next block.
+type BitReader<'a> = bitstream::BitReader<IterRead<&'a mut dyn Iterator<Item = u8>>>;
+
+fn bit_reader(input_iter: &mut dyn Iterator<Item = u8>) -> BitReader<'_> {
+    bitstream::BitReader::bounded(IterRead(input_iter), BitOrder::LsbFirst)
+}
@@ -0,0 +1,149 @@
+use super::code::Code;
+use super::encode::{choose_tables, count_frequency};
+use super::GROUP_SIZE;
+use crate::result::Result;
+use std::fmt::Write;
+
+pub struct TableReport {
+    pub code: Code<u16>,
+    pub frequencies: Vec<u64>,
+    pub groups: usize,
+}
+
+// The tables the static coder picks for a block of stage output, or of its zero runs, with the
+// frequencies of the letters in the groups coded with each of them.
+pub struct BlockReport {
+    pub tables: Vec<TableReport>,
+    pub symbols: usize,
+    pub bits: usize,
+    pub entropy: f64,
+}
+
+impl BlockReport {
+    pub fn new<S: Copy + Into<u16>>(
+        input: &[S],
+        alphabet_size: usize,
+        max_code_len: Option<u8>,
+    ) -> Result<BlockReport> {
+        let frequencies = count_frequency(input.iter().copied(), alphabet_size);
+        let (lengths, selectors, bits) = choose_tables(input, &frequencies, max_code_len)?;
+
+        let mut tables: Vec<TableReport> = lengths
+            .iter()
+            .map(|lengths| TableReport {
+                code: Code::from_lengths((0..).zip(lengths.iter().copied())).unwrap(),
+                frequencies: vec![0; alphabet_size],
+                groups: 0,
+            })
+            .collect();
+        for (group, selector) in input.chunks(GROUP_SIZE).zip(selectors) {
+            let table = &mut tables[selector as usize];
+            table.groups += 1;
+            for letter in group {
+                table.frequencies[(*letter).into() as usize] += 1;
+            }
+        }
+
+        Ok(BlockReport {
+            tables,
+            symbols: input.len(),
+            bits,
+            entropy: entropy(&frequencies),
+        })
+    }
+
+    pub fn average_len(&self) -> f64 {
+        match self.symbols {
+            0 => 0.0,
+            symbols => self.bits as f64 / symbols as f64,
+        }
+    }
+
+    pub fn to_table(&self) -> String {
+        let mut table = String::new();
+        for (i, report) in self.tables.iter().enumerate() {
+            writeln!(table, "table {}, {} groups", i + 1, report.groups).unwrap();
+            writeln!(table, "symbol\tfrequency\tcode\tlength").unwrap();
+            for (symbol, len) in report.code.lengths() {
+                let code: String = report
+                    .code
+                    .code(symbol)
+                    .unwrap()
+                    .iter()
+                    .map(|bit| (b'0' + bit as u8) as char)
+                    .collect();
+                let frequency = report.frequencies[symbol as usize];
+                writeln!(table, "{}\t{}\t{}\t{}", symbol, frequency, code, len).unwrap();
+            }
+        }
+        writeln!(
+            table,
+            "average code length {:.4} bits, order-0 entropy {:.4} bits",
+            self.average_len(),
+            self.entropy
+        )
+        .unwrap();
+        table
+    }
+
+    pub fn to_dot(&self, name: &str) -> String {
+        self.tables
+            .iter()
+            .enumerate()
+            .map(|(i, report)| {
+                report
+                    .code
+                    .to_dot(&format!("{} table {}", name, i + 1), |symbol| {
+                        format!("{}: {}", symbol, report.frequencies[symbol as usize])
+                    })
+            })
+            .collect()
+    }
+}
+
+// Bits per symbol of the best code that assumes every symbol independent of the others.
+fn entropy(frequencies: &[u64]) -> f64 {
+    let total: u64 = frequencies.iter().sum();
+    frequencies
+        .iter()
+        .filter(|frequency| **frequency > 0)
+        .map(|frequency| {
+            let probability = *frequency as f64 / total as f64;
+            -probability * probability.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn block_report_test() {
+        let input = "abbcccdddd".as_bytes();
+        let report = BlockReport::new(input, 256, None).unwrap();
+        assert_eq!(report.tables.len(), 1);
+        assert_eq!(report.bits, 3 + 3 * 2 + 2 * 3 + 4);
+        assert_eq!(report.average_len(), 1.9);
+        assert!((report.entropy - 1.8464).abs() < 1e-4);
+
+        let table = report.to_table();
+        assert!(table.contains("98\t2\t111\t3\n"));
+        assert!(table.contains("average code length 1.9000 bits, order-0 entropy 1.8464 bits"));
+
+        let dot = report.to_dot("block 1");
+        assert!(dot.starts_with("digraph \"block 1 table 1\" {"));
+        assert!(dot.contains("  n11 -> n111 [label=\"1\"];\n"));
+        assert!(dot.contains("  n111 [shape=box, label=\"98: 2\"];\n"));
+        assert_eq!(dot.matches(" -> ").count(), 6);
+    }
+
+    #[test]
+    fn block_report_wide_alphabet_test() {
+        let input: Vec<u16> = vec![0, 0, 1, 257, 257, 257, 257];
+        let report = BlockReport::new(&input, 258, None).unwrap();
+        assert_eq!(report.bits, 2 * 2 + 2 + 4);
+        assert_eq!(report.tables[0].frequencies[257], 4);
+        assert!(report.to_table().contains("257\t4\t0\t1\n"));
+    }
+}
@@ -1,11 +1,18 @@
+pub mod bitstream;
 pub mod bwt;
-mod config;
+pub mod config;
 mod decode;
 mod encode;
-mod huffman;
+pub mod fm_index;
+pub mod gst;
+pub mod huffman;
 pub mod mtf;
+mod parallel;
 pub mod result;
+mod segmentation;
+pub mod stream;
 pub mod utils;
+pub mod zero_runs;
 
-pub use decode::decode;
-pub use encode::encode;
+pub use decode::{decode, decode_parallel};
+pub use encode::{describe_with, encode, encode_parallel, encode_with};
@@ -1,41 +1,87 @@
-use atlist_rs::LinkedList;
+// Where a symbol found at some rank is moved to. MTF-1 only promotes a symbol to the front from
+// rank one, MTF-2 does so only if the previous symbol was not at the front already. Sticky
+// move-to-front moves a symbol at rank r to rank r * k / 256.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    MoveToFront,
+    MoveToFrontOne,
+    MoveToFrontTwo,
+    Sticky(u8),
+}
 
-pub fn apply(buffer: &[u8]) -> Vec<u8> {
-    let mut alphabet: LinkedList<u8> = (0..=255).collect();
+impl Mode {
+    fn target(self, rank: usize, previous: Option<usize>) -> usize {
+        match self {
+            Mode::MoveToFront => 0,
+            Mode::MoveToFrontOne if rank <= 1 => 0,
+            Mode::MoveToFrontTwo if rank == 0 || (rank == 1 && previous != Some(0)) => 0,
+            Mode::MoveToFrontOne | Mode::MoveToFrontTwo => 1,
+            Mode::Sticky(k) => rank * k as usize / 256,
+        }
+    }
+}
 
-    let mut output = Vec::with_capacity(buffer.len());
-    for i in buffer {
-        let mut iter = alphabet.iter_mut();
-        let mut index: u8 = 0;
+pub fn apply(buffer: &[u8]) -> Vec<u8> {
+    apply_with(buffer, Mode::MoveToFront)
+}
 
-        while *iter.unwrap() != *i {
-            iter.next();
-            index += 1;
-        }
+pub fn reverse(buffer: &[u8]) -> Vec<u8> {
+    reverse_with(buffer, Mode::MoveToFront)
+}
 
-        output.push(index);
-        alphabet.remove_iter_mut(&mut iter).unwrap();
-        alphabet.push_front(*i).unwrap();
+// After a transformation most symbols are found among the first few ranks, so these are checked
+// before scanning the rest.
+fn rank(alphabet: &[u8; 256], byte: u8) -> usize {
+    if alphabet[0] == byte {
+        0
+    } else if alphabet[1] == byte {
+        1
+    } else if alphabet[2] == byte {
+        2
+    } else if alphabet[3] == byte {
+        3
+    } else {
+        4 + alphabet[4..].iter().position(|x| *x == byte).unwrap()
     }
+}
 
-    output
+fn promote(alphabet: &mut [u8; 256], rank: usize, target: usize) {
+    let byte = alphabet[rank];
+    alphabet.copy_within(target..rank, target + 1);
+    alphabet[target] = byte;
 }
 
-pub fn reverse(buffer: &[u8]) -> Vec<u8> {
-    let mut alphabet: LinkedList<u8> = (0..=255).collect();
+fn alphabet() -> [u8; 256] {
+    let mut alphabet = [0; 256];
+    for (i, x) in alphabet.iter_mut().enumerate() {
+        *x = i as u8;
+    }
+    alphabet
+}
 
+pub fn apply_with(buffer: &[u8], mode: Mode) -> Vec<u8> {
+    let mut alphabet = alphabet();
+    let mut previous = None;
     let mut output = Vec::with_capacity(buffer.len());
     for i in buffer {
-        let mut iter = alphabet.iter_mut();
+        let rank = rank(&alphabet, *i);
+        output.push(rank as u8);
+        promote(&mut alphabet, rank, mode.target(rank, previous));
+        previous = Some(rank);
+    }
 
-        for _ in 0..*i {
-            iter.next();
-        }
+    output
+}
 
-        let letter = *iter.unwrap();
-        output.push(letter);
-        alphabet.remove_iter_mut(&mut iter).unwrap();
-        alphabet.push_front(letter).unwrap();
+pub fn reverse_with(buffer: &[u8], mode: Mode) -> Vec<u8> {
+    let mut alphabet = alphabet();
+    let mut previous = None;
+    let mut output = Vec::with_capacity(buffer.len());
+    for rank in buffer.iter().map(|x| *x as usize) {
+        output.push(alphabet[rank]);
+        promote(&mut alphabet, rank, mode.target(rank, previous));
+        previous = Some(rank);
     }
 
     output
@@ -59,4 +105,63 @@ mod test {
         let string = "aaaabbbbeeeeeddddda".as_bytes();
         assert_eq!(reverse(&apply(string)), string)
     }
+
+    #[test]
+    fn move_to_front_reference_test() {
+        let mut state: u32 = 3;
+        let buffer: Vec<u8> = (0..5000)
+            .map(|i| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 24) as u8 % (1 + i as u8 % 64)
+            })
+            .collect();
+
+        let mut alphabet: Vec<u8> = (0..=255).collect();
+        let expected: Vec<u8> = buffer
+            .iter()
+            .map(|byte| {
+                let rank = alphabet.iter().position(|x| x == byte).unwrap();
+                alphabet.remove(rank);
+                alphabet.insert(0, *byte);
+                rank as u8
+            })
+            .collect();
+
+        assert_eq!(apply(&buffer), expected);
+        assert_eq!(reverse(&expected), buffer);
+    }
+
+    #[test]
+    fn move_to_front_one_test() {
+        let string = "abbb\0\0".as_bytes();
+        assert_eq!(
+            apply_with(string, Mode::MoveToFrontOne),
+            vec![97, 98, 1, 0, 1, 0]
+        );
+        assert_eq!(
+            apply_with(string, Mode::MoveToFrontTwo),
+            vec![97, 98, 1, 0, 1, 1]
+        );
+        assert_eq!(
+            apply_with(string, Mode::Sticky(128)),
+            vec![97, 98, 49, 24, 0, 0]
+        );
+    }
+
+    #[test]
+    fn move_to_front_modes_test() {
+        let string = "mississippi river banks were flooded in the spring".as_bytes();
+        assert_eq!(apply_with(string, Mode::MoveToFront), apply(string));
+        assert_eq!(apply_with(string, Mode::Sticky(0)), apply(string));
+
+        let modes = [
+            Mode::MoveToFrontOne,
+            Mode::MoveToFrontTwo,
+            Mode::Sticky(128),
+            Mode::Sticky(255),
+        ];
+        for mode in modes {
+            assert_eq!(reverse_with(&apply_with(string, mode), mode), string)
+        }
+    }
 }
@@ -0,0 +1,133 @@
+use crate::result::{Error, Result};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// A fixed pool of workers maps the items, two per worker at most are in flight at once, and the
+// results come back in input order.
+pub struct ParallelMap<I: Iterator, T> {
+    input_iter: I,
+    jobs: Sender<(usize, I::Item)>,
+    results: Receiver<(usize, thread::Result<T>)>,
+    // results that arrived before the ones preceding them
+    done: HashMap<usize, thread::Result<T>>,
+    limit: usize,
+    sent: usize,
+    received: usize,
+}
+
+impl<I, T> ParallelMap<I, T>
+where
+    I: Iterator,
+    I::Item: Send + 'static,
+    T: Send + 'static,
+{
+    pub fn new<F>(input_iter: I, workers: usize, function: F) -> ParallelMap<I, T>
+    where
+        F: Fn(I::Item) -> T + Clone + Send + 'static,
+    {
+        let workers = std::cmp::max(workers, 1);
+        let (jobs, job_receiver) = mpsc::channel::<(usize, I::Item)>();
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        for _ in 0..workers {
+            let job_receiver = Arc::clone(&job_receiver);
+            let result_sender = result_sender.clone();
+            let function = function.clone();
+            thread::spawn(move || loop {
+                let job = match job_receiver.lock() {
+                    Ok(job_receiver) => job_receiver.recv(),
+                    Err(_) => return,
+                };
+                // the pool is dropped once the jobs are closed
+                let (index, item) = match job {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| function(item)));
+                if result_sender.send((index, result)).is_err() {
+                    return;
+                }
+            });
+        }
+
+        ParallelMap {
+            input_iter,
+            jobs,
+            results,
+            done: HashMap::new(),
+            limit: 2 * workers,
+            sent: 0,
+            received: 0,
+        }
+    }
+}
+
+impl<I, T> Iterator for ParallelMap<I, T>
+where
+    I: Iterator,
+{
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.sent - self.received < self.limit {
+            let item = match self.input_iter.next() {
+                None => break,
+                Some(item) => item,
+            };
+            if self.jobs.send((self.sent, item)).is_err() {
+                break;
+            }
+            self.sent += 1;
+        }
+
+        if self.received == self.sent {
+            return None;
+        }
+        let result = loop {
+            if let Some(result) = self.done.remove(&self.received) {
+                break result;
+            }
+            match self.results.recv() {
+                Ok((index, result)) => self.done.insert(index, result),
+                Err(_) => {
+                    self.sent = self.received;
+                    return Some(Err(Error::new("Worker threads stopped")));
+                }
+            };
+        };
+
+        self.received += 1;
+        Some(result.map_err(|_| Error::new("Worker thread panicked")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn order_test() {
+        let mapped = ParallelMap::new(0..1000u64, 4, |x| {
+            // later items tend to finish first
+            thread::sleep(std::time::Duration::from_micros(1000 - x));
+            x * x
+        });
+        assert!(mapped.map(|x| x.unwrap()).eq((0..1000).map(|x| x * x)));
+    }
+
+    #[test]
+    fn panic_test() {
+        let mapped: Vec<_> = ParallelMap::new(0..6, 2, |x| {
+            assert_ne!(x, 3);
+            x
+        })
+        .collect();
+        assert_eq!(mapped[2], Ok(2));
+        assert_eq!(mapped[3], Err(Error::new("Worker thread panicked")));
+        assert_eq!(mapped[5], Ok(5));
+    }
+}
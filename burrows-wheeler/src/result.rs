@@ -1,4 +1,4 @@
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Error {
@@ -13,6 +13,12 @@ impl From<IoError> for Error {
     }
 }
 
+impl From<Error> for IoError {
+    fn from(error: Error) -> Self {
+        IoError::new(ErrorKind::InvalidData, error.message)
+    }
+}
+
 impl Error {
     pub fn new(str: &str) -> Error {
         Error {
@@ -0,0 +1,197 @@
+use std::io::{Read, Result as IoResult};
+
+const MAX_CANDIDATES: usize = 256;
+const BLOCK_COST: f64 = 2048.0;
+
+fn x_log_x(x: u32) -> f64 {
+    if x == 0 {
+        0.0
+    } else {
+        x as f64 * (x as f64).log2()
+    }
+}
+
+// Static code length of a segment in bits for its order-0 and order-1 models, each charged with
+// half a log of the segment length for every parameter it has to learn.
+struct Model {
+    symbols: Vec<u32>,
+    pairs: Vec<u32>,
+}
+
+impl Model {
+    fn new() -> Model {
+        Model {
+            symbols: vec![0; 256],
+            pairs: vec![0; 256 * 256],
+        }
+    }
+
+    fn add(&mut self, buffer: &[u8], start: usize, end: usize) {
+        for i in start..end {
+            self.symbols[buffer[i] as usize] += 1;
+            if i > 0 {
+                self.pairs[(buffer[i - 1] as usize) << 8 | buffer[i] as usize] += 1;
+            }
+        }
+    }
+
+    fn subtract(&self, other: &Model) -> Model {
+        Model {
+            symbols: self
+                .symbols
+                .iter()
+                .zip(other.symbols.iter())
+                .map(|(x, y)| x - y)
+                .collect(),
+            pairs: self
+                .pairs
+                .iter()
+                .zip(other.pairs.iter())
+                .map(|(x, y)| x - y)
+                .collect(),
+        }
+    }
+
+    fn cost(&self) -> f64 {
+        let len: u32 = self.symbols.iter().sum();
+        let penalty = (len as f64).max(1.0).log2() / 2.0;
+
+        let parameters = self.symbols.iter().filter(|x| **x > 0).count();
+        let order0 = x_log_x(len) - self.symbols.iter().map(|x| x_log_x(*x)).sum::<f64>()
+            + penalty * parameters.saturating_sub(1) as f64;
+
+        let mut order1 = 0.0;
+        let mut parameters = 0;
+        for context in self.pairs.chunks(256) {
+            let total = context.iter().sum();
+            if total > 0 {
+                order1 += x_log_x(total) - context.iter().map(|x| x_log_x(*x)).sum::<f64>();
+                parameters += context.iter().filter(|x| **x > 0).count() - 1;
+            }
+        }
+        order1 += penalty * parameters as f64;
+
+        order0.min(order1)
+    }
+}
+
+pub fn find_cut(buffer: &[u8], min: usize) -> Option<usize> {
+    let len = buffer.len();
+    if len < 2 * min {
+        return None;
+    }
+
+    let step = std::cmp::max(std::cmp::max(min / 4, 1), (len - 2 * min) / MAX_CANDIDATES);
+    let mut total = Model::new();
+    total.add(buffer, 0, len);
+    let whole_cost = total.cost();
+
+    let mut left = Model::new();
+    left.add(buffer, 0, min);
+    let mut best: Option<(f64, usize)> = None;
+    let mut cut = min;
+
+    while cut <= len - min {
+        let mut right = total.subtract(&left);
+        right.pairs[(buffer[cut - 1] as usize) << 8 | buffer[cut] as usize] -= 1;
+
+        let gain = whole_cost - left.cost() - right.cost() - BLOCK_COST;
+        if gain > 0.0 && best.is_none_or(|(best_gain, _)| gain > best_gain) {
+            best = Some((gain, cut));
+        }
+
+        let next = std::cmp::min(cut + step, len - min + 1);
+        left.add(buffer, cut, std::cmp::min(next, len));
+        cut = next;
+    }
+
+    best.map(|(_, cut)| cut)
+}
+
+pub struct SegmentingIterator {
+    read: Box<dyn Read>,
+    min: usize,
+    max: usize,
+    pending: Vec<u8>,
+}
+
+impl SegmentingIterator {
+    pub fn from_read(min: usize, max: usize, read: Box<dyn Read>) -> SegmentingIterator {
+        SegmentingIterator {
+            read,
+            min,
+            max,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Iterator for SegmentingIterator {
+    type Item = IoResult<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let missing = self.max - self.pending.len();
+        if let Err(err) = (&mut self.read)
+            .take(missing as u64)
+            .read_to_end(&mut self.pending)
+        {
+            return Some(Err(err));
+        }
+
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let cut = find_cut(&self.pending, self.min).unwrap_or(self.pending.len());
+        let rest = self.pending.split_off(cut);
+        Some(Ok(std::mem::replace(&mut self.pending, rest)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mixed(text_len: usize, binary_len: usize) -> Vec<u8> {
+        let text =
+            "it was the best of times, it was the worst of times, it was the age of wisdom, ";
+        let mut state: u64 = 1;
+        text.bytes()
+            .cycle()
+            .take(text_len)
+            .chain((0..binary_len).map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            }))
+            .collect()
+    }
+
+    #[test]
+    fn find_cut_test() {
+        let buffer = mixed(20000, 12000);
+        let cut = find_cut(&buffer, 2048).unwrap();
+        assert!((19488..=20512).contains(&cut), "cut at {}", cut);
+    }
+
+    #[test]
+    fn find_no_cut_test() {
+        let buffer = mixed(32000, 0);
+        assert_eq!(find_cut(&buffer, 2048), None);
+    }
+
+    #[test]
+    fn segmenting_iterator_test() {
+        let buffer = mixed(20000, 12000);
+        let blocks: Vec<Vec<u8>> =
+            SegmentingIterator::from_read(2048, 16384, Box::new(Cursor::new(buffer.clone())))
+                .map(|x| x.unwrap())
+                .collect();
+
+        assert!(blocks.iter().all(|x| x.len() <= 16384));
+        assert!(blocks.iter().map(|x| x.len()).any(|x| x != 16384));
+        assert_eq!(blocks.concat(), buffer);
+    }
+}
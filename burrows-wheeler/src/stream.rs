@@ -0,0 +1,390 @@
+use crate::bwt::Transform;
+use crate::config::{BlockSize, Coder, Index, Options, MAX_BLOCK_SIZE, MAX_STREAMS};
+use crate::gst::{Stage, Weights};
+use crate::mtf::Mode;
+use crate::result::{Error, Result};
+use std::convert::TryInto;
+
+const MAGIC: [u8; 4] = *b"HBWT";
+const BIJECTIVE_FLAG: u8 = 1;
+const ADAPTIVE_FLAG: u8 = 2;
+const STAGE_FLAG: u8 = 4;
+const ADAPTIVE_CODER_FLAG: u8 = 8;
+const ZERO_RUNS_FLAG: u8 = 16;
+const STREAMS_FLAG: u8 = 32;
+
+const MOVE_TO_FRONT: u8 = 0;
+const WEIGHTED_FREQUENCY_COUNT: u8 = 1;
+const INVERSION_FREQUENCIES: u8 = 2;
+const DISTANCE_CODING: u8 = 3;
+
+pub fn encode_header(options: &Options, block_size: usize) -> Vec<u8> {
+    let mut flags = 0;
+    if options.transform == Transform::Bijective {
+        flags |= BIJECTIVE_FLAG;
+    }
+    if let BlockSize::Adaptive { .. } = options.block_size {
+        flags |= ADAPTIVE_FLAG;
+    }
+    if options.stage != Stage::default() {
+        flags |= STAGE_FLAG;
+    }
+    if options.coder == Coder::Adaptive {
+        flags |= ADAPTIVE_CODER_FLAG;
+    }
+    if options.zero_runs {
+        flags |= ZERO_RUNS_FLAG;
+    }
+    if options.streams > 1 {
+        flags |= STREAMS_FLAG;
+    }
+
+    let mut header = MAGIC.to_vec();
+    header.push(flags);
+    header.extend_from_slice(&(block_size as u32).to_be_bytes());
+    if let BlockSize::Adaptive { min, .. } = options.block_size {
+        header.extend_from_slice(&(min as u32).to_be_bytes());
+    }
+    if flags & STAGE_FLAG != 0 {
+        encode_stage(&mut header, &options.stage);
+    }
+    if flags & STREAMS_FLAG != 0 {
+        header.push(options.streams as u8);
+    }
+    header
+}
+
+pub fn encode_stage(header: &mut Vec<u8>, stage: &Stage) {
+    match stage {
+        Stage::MoveToFront(mode) => {
+            header.push(MOVE_TO_FRONT);
+            match mode {
+                Mode::MoveToFront => header.push(0),
+                Mode::MoveToFrontOne => header.push(1),
+                Mode::MoveToFrontTwo => header.push(2),
+                Mode::Sticky(k) => header.extend_from_slice(&[3, *k]),
+            }
+        }
+        Stage::WeightedFrequencyCount(weights) => {
+            header.push(WEIGHTED_FREQUENCY_COUNT);
+            header.push(weights.steps().len() as u8);
+            for (len, weight) in weights.steps() {
+                header.extend_from_slice(&(*len as u32).to_be_bytes());
+                header.extend_from_slice(&weight.to_be_bytes());
+            }
+        }
+        Stage::InversionFrequencies => header.push(INVERSION_FREQUENCIES),
+        Stage::DistanceCoding => header.push(DISTANCE_CODING),
+    }
+}
+
+pub fn decode_stage(input_iter: &mut dyn Iterator<Item = u8>) -> Result<Stage> {
+    let mut next = || {
+        input_iter
+            .next()
+            .ok_or_else(|| Error::new("Unexpected end of stream header"))
+    };
+
+    match next()? {
+        MOVE_TO_FRONT => match next()? {
+            0 => Ok(Stage::MoveToFront(Mode::MoveToFront)),
+            1 => Ok(Stage::MoveToFront(Mode::MoveToFrontOne)),
+            2 => Ok(Stage::MoveToFront(Mode::MoveToFrontTwo)),
+            3 => Ok(Stage::MoveToFront(Mode::Sticky(next()?))),
+            _ => Err(Error::new("Unknown stage")),
+        },
+        WEIGHTED_FREQUENCY_COUNT => {
+            let steps = next()?;
+            let steps = (0..steps)
+                .map(|_| {
+                    let len = read_u32(&input_iter.take(4).collect::<Vec<_>>());
+                    let weight = read_u32(&input_iter.take(4).collect::<Vec<_>>());
+                    len.zip(weight)
+                        .map(|(len, weight)| (len, weight as u32))
+                        .ok_or_else(|| Error::new("Unexpected end of stream header"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if steps.is_empty() || steps.iter().any(|(len, _)| *len == 0) {
+                return Err(Error::new("Invalid stage weights"));
+            }
+            Ok(Stage::WeightedFrequencyCount(Weights::new(steps)))
+        }
+        INVERSION_FREQUENCIES => Ok(Stage::InversionFrequencies),
+        DISTANCE_CODING => Ok(Stage::DistanceCoding),
+        _ => Err(Error::new("Unknown stage")),
+    }
+}
+
+// Adaptive streams prefix every block with its length, as the segmentation is not implied by the
+// block size.
+pub fn encode_block_length(length: usize) -> [u8; 4] {
+    (length as u32).to_be_bytes()
+}
+
+pub fn decode_block_length(input_iter: &mut dyn Iterator<Item = u8>) -> Option<Result<usize>> {
+    let first = input_iter.next()?;
+    let rest: Vec<u8> = input_iter.take(3).collect();
+    Some(
+        read_u32(&[&[first], rest.as_slice()].concat())
+            .ok_or_else(|| Error::new("Unexpected end of block length")),
+    )
+}
+
+// Interleaved streams prefix every block with the rows its streams after the first start from, as
+// short blocks have fewer streams.
+pub fn encode_starts(starts: &[Index]) -> Vec<u8> {
+    let mut encoded = vec![starts.len() as u8];
+    for start in starts {
+        encoded.extend_from_slice(&start.to_be_bytes());
+    }
+    encoded
+}
+
+pub fn decode_starts(input_iter: &mut dyn Iterator<Item = u8>) -> Option<Result<Vec<Index>>> {
+    let count = input_iter.next()?;
+    let starts = (0..count).map(|_| {
+        read_u32(&input_iter.take(4).collect::<Vec<_>>())
+            .map(|start| start as Index)
+            .ok_or_else(|| Error::new("Unexpected end of block starts"))
+    });
+    Some(starts.collect())
+}
+
+fn read_u32(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .try_into()
+        .ok()
+        .map(|x| u32::from_be_bytes(x) as usize)
+}
+
+pub fn decode_header(input_iter: &mut dyn Iterator<Item = u8>) -> Result<Options> {
+    let magic: Vec<u8> = (&mut *input_iter).take(MAGIC.len()).collect();
+    if magic != MAGIC {
+        return Err(Error::new("Unknown stream format"));
+    }
+
+    let flags = input_iter
+        .next()
+        .ok_or_else(|| Error::new("Unexpected end of stream header"))?;
+    let known = BIJECTIVE_FLAG
+        | ADAPTIVE_FLAG
+        | STAGE_FLAG
+        | ADAPTIVE_CODER_FLAG
+        | ZERO_RUNS_FLAG
+        | STREAMS_FLAG;
+    if flags & !known != 0 {
+        return Err(Error::new("Unknown stream flags"));
+    }
+    // the adaptive coder has a byte alphabet of its own
+    if flags & ADAPTIVE_CODER_FLAG != 0 && flags & ZERO_RUNS_FLAG != 0 {
+        return Err(Error::new("Zero runs need the static coder"));
+    }
+    if flags & BIJECTIVE_FLAG != 0 && flags & STREAMS_FLAG != 0 {
+        return Err(Error::new("Interleaved streams need the cyclic transform"));
+    }
+
+    let transform = if flags & BIJECTIVE_FLAG != 0 {
+        Transform::Bijective
+    } else {
+        Transform::Cyclic
+    };
+
+    let block_size = read_size(input_iter)?;
+    let block_size = if flags & ADAPTIVE_FLAG != 0 {
+        let min = read_size(input_iter)?;
+        if min > block_size {
+            return Err(Error::new("Block size out of range"));
+        }
+        BlockSize::Adaptive {
+            min,
+            max: block_size,
+        }
+    } else {
+        BlockSize::Fixed(block_size)
+    };
+
+    let stage = if flags & STAGE_FLAG != 0 {
+        decode_stage(input_iter)?
+    } else {
+        Stage::default()
+    };
+
+    let streams = if flags & STREAMS_FLAG != 0 {
+        match input_iter.next().map(|x| x as usize) {
+            Some(streams) if (2..=MAX_STREAMS).contains(&streams) => streams,
+            Some(_) => return Err(Error::new("Stream count out of range")),
+            None => return Err(Error::new("Unexpected end of stream header")),
+        }
+    } else {
+        1
+    };
+
+    let coder = if flags & ADAPTIVE_CODER_FLAG != 0 {
+        Coder::Adaptive
+    } else {
+        Coder::Static
+    };
+
+    Ok(Options {
+        transform,
+        block_size,
+        stage,
+        coder,
+        zero_runs: flags & ZERO_RUNS_FLAG != 0,
+        streams,
+        ..Options::default()
+    })
+}
+
+fn read_size(input_iter: &mut dyn Iterator<Item = u8>) -> Result<usize> {
+    let bytes: Vec<u8> = input_iter.take(4).collect();
+    let size = read_u32(&bytes).ok_or_else(|| Error::new("Unexpected end of stream header"))?;
+    if size == 0 || size > MAX_BLOCK_SIZE {
+        return Err(Error::new("Block size out of range"));
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_encoded_test() {
+        for transform in [Transform::Cyclic, Transform::Bijective] {
+            let options = Options {
+                transform,
+                block_size: BlockSize::Fixed(100 * 1024),
+                ..Options::default()
+            };
+            let mut encoded = encode_header(&options, 100 * 1024).into_iter();
+            assert_eq!(decode_header(&mut encoded), Ok(options));
+            assert_eq!(encoded.next(), None);
+        }
+    }
+
+    #[test]
+    fn decode_encoded_adaptive_test() {
+        let options = Options {
+            block_size: BlockSize::Adaptive {
+                min: 1024,
+                max: 100 * 1024,
+            },
+            ..Options::default()
+        };
+        let mut encoded = encode_header(&options, 100 * 1024).into_iter();
+        assert_eq!(decode_header(&mut encoded), Ok(options));
+        assert_eq!(encoded.next(), None);
+
+        let mut encoded = "HBWT\x02\0\0\x01\0\0\0\x02\0".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+    }
+
+    #[test]
+    fn decode_encoded_stage_test() {
+        let stages = [
+            Stage::MoveToFront(Mode::MoveToFrontOne),
+            Stage::MoveToFront(Mode::MoveToFrontTwo),
+            Stage::MoveToFront(Mode::Sticky(96)),
+            Stage::WeightedFrequencyCount(Weights::default()),
+            Stage::InversionFrequencies,
+            Stage::DistanceCoding,
+        ];
+        for stage in stages {
+            let options = Options {
+                block_size: BlockSize::Fixed(1024),
+                stage,
+                ..Options::default()
+            };
+            let mut encoded = encode_header(&options, 1024).into_iter();
+            assert_eq!(decode_header(&mut encoded), Ok(options));
+            assert_eq!(encoded.next(), None);
+        }
+
+        let mut encoded = "HBWT\x04\0\0\x01\0\x09".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+
+        let mut encoded = "HBWT\x04\0\0\x01\0\x01\x01\0\0\0\0\0\0\0\x01".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+    }
+
+    #[test]
+    fn decode_encoded_coder_test() {
+        let options = Options {
+            block_size: BlockSize::Fixed(1024),
+            coder: Coder::Adaptive,
+            ..Options::default()
+        };
+        let mut encoded = encode_header(&options, 1024).into_iter();
+        assert_eq!(decode_header(&mut encoded), Ok(options));
+        assert_eq!(encoded.next(), None);
+
+        let options = Options {
+            block_size: BlockSize::Fixed(1024),
+            zero_runs: true,
+            ..Options::default()
+        };
+        let mut encoded = encode_header(&options, 1024).into_iter();
+        assert_eq!(decode_header(&mut encoded), Ok(options));
+        assert_eq!(encoded.next(), None);
+
+        let mut encoded = "HBWT\x18\0\0\x04\0".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+    }
+
+    #[test]
+    fn decode_encoded_streams_test() {
+        let options = Options {
+            block_size: BlockSize::Fixed(1024),
+            streams: 8,
+            ..Options::default()
+        };
+        let mut encoded = encode_header(&options, 1024).into_iter();
+        assert_eq!(decode_header(&mut encoded), Ok(options));
+        assert_eq!(encoded.next(), None);
+
+        let mut encoded = "HBWT\x20\0\0\x04\0\x01".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+
+        let mut encoded = "HBWT\x21\0\0\x04\0\x04".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+    }
+
+    #[test]
+    fn decode_starts_test() {
+        let mut encoded = encode_starts(&[3, 70000]).into_iter();
+        assert_eq!(decode_starts(&mut encoded), Some(Ok(vec![3, 70000])));
+        assert_eq!(decode_starts(&mut encoded), None);
+
+        let mut encoded = "\x02\0\0\0\x01\0".bytes();
+        assert!(decode_starts(&mut encoded).unwrap().is_err());
+    }
+
+    #[test]
+    fn decode_block_length_test() {
+        let mut encoded = encode_block_length(70000).to_vec().into_iter();
+        assert_eq!(decode_block_length(&mut encoded), Some(Ok(70000)));
+        assert_eq!(decode_block_length(&mut encoded), None);
+
+        let mut encoded = "\0\x01".bytes();
+        assert!(decode_block_length(&mut encoded).unwrap().is_err());
+    }
+
+    #[test]
+    fn decode_unknown_test() {
+        let mut encoded = "HBWZ\0".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+
+        let mut encoded = "HBWT\x40\0\0\x01\0".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+
+        let mut encoded = "HBWT\0\0\0\0\0".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+
+        let mut encoded = "HBWT\0\x04\0\0\x01".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+
+        let mut encoded = "HBWT\0\0\x01".bytes();
+        assert!(decode_header(&mut encoded).is_err());
+    }
+}
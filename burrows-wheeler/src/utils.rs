@@ -1,19 +1,28 @@
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Result as IoResult, Write};
+use std::io::{BufReader, BufWriter, Error as IoError, ErrorKind, Read, Result as IoResult, Write};
 
 pub fn launch<F>(function: F)
 where
     F: FnOnce(&str, Box<dyn Read>) -> IoResult<()>,
 {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        println!("Usage: <input file path> <output file path>");
+    launch_with(std::env::args().skip(1).collect(), "", function)
+}
+
+pub fn launch_with<F>(args: Vec<String>, options_usage: &str, function: F)
+where
+    F: FnOnce(&str, Box<dyn Read>) -> IoResult<()>,
+{
+    if args.len() != 2 {
+        println!(
+            "Usage: {}<input file path> <output file path>",
+            options_usage
+        );
         return;
     }
 
-    let input_file_path = &args[1];
-    let output_file_path = &args[2];
+    let input_file_path = &args[0];
+    let output_file_path = &args[1];
 
     let result = File::open(input_file_path)
         .and_then(|file| function(output_file_path, Box::new(BufReader::new(file))));
@@ -23,23 +32,56 @@ where
     }
 }
 
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let position = args.iter().position(|x| x == name)?;
+    if position + 1 == args.len() {
+        return None;
+    }
+
+    let value = args.remove(position + 1);
+    args.remove(position);
+    Some(value)
+}
+
+pub fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|x| x == name) {
+        Some(position) => {
+            args.remove(position);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn available_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|workers| workers.get())
+        .unwrap_or(1)
+}
+
 pub fn write_iter<'a>(
     output_file_path: &str,
-    iter: Box<dyn Iterator<Item = u8> + 'a>,
+    mut iter: Box<dyn Iterator<Item = u8> + 'a>,
 ) -> IoResult<()> {
     let mut buf_writer = BufWriter::new(File::create(output_file_path)?);
-    iter.map(|x| buf_writer.write(&[x]).map(|_| ())).collect()
+    iter.try_for_each(|x| buf_writer.write(&[x]).map(|_| ()))
 }
 
+// The output of a stream that fails midway is removed rather than left truncated.
 pub fn write_iter_result<'a, E: Debug>(
     output_file_path: &str,
     iter: Box<dyn Iterator<Item = Result<u8, E>> + 'a>,
 ) -> IoResult<()> {
     let mut buf_writer = BufWriter::new(File::create(output_file_path)?);
 
-    for byte in iter {
-        buf_writer.write_all(&[byte.unwrap()])?;
-    }
+    let result = iter
+        .map(|byte| byte.map_err(|err| IoError::new(ErrorKind::InvalidData, format!("{:?}", err))))
+        .try_for_each(|byte| buf_writer.write_all(&[byte?]))
+        .and_then(|_| buf_writer.flush());
 
-    Ok(())
+    if result.is_err() {
+        drop(buf_writer);
+        std::fs::remove_file(output_file_path)?;
+    }
+    result
 }
@@ -0,0 +1,148 @@
+use crate::result::{Error, Result};
+
+// Runs of zeros are written as their lengths in bijective base 2, the lowest digit first: `RUN_A`
+// stands for one and `RUN_B` for two times the digit's weight. Other bytes move up by one to make
+// room for them, and the block ends with `END_OF_BLOCK`, as in bzip2.
+pub const RUN_A: u16 = 0;
+pub const RUN_B: u16 = 1;
+pub const END_OF_BLOCK: u16 = 257;
+pub const ALPHABET_SIZE: usize = END_OF_BLOCK as usize + 1;
+
+pub fn apply(data: &[u8]) -> Vec<u16> {
+    let mut result = Vec::with_capacity(data.len() / 2 + 1);
+    let mut run: u64 = 0;
+    for byte in data.iter().chain([1].iter()) {
+        if *byte == 0 {
+            run += 1;
+            continue;
+        }
+
+        while run > 0 {
+            if run % 2 == 1 {
+                result.push(RUN_A);
+                run = (run - 1) / 2;
+            } else {
+                result.push(RUN_B);
+                run = (run - 2) / 2;
+            }
+        }
+        result.push(*byte as u16 + 1);
+    }
+
+    // the sentinel one is replaced with the end of the block
+    *result.last_mut().unwrap() = END_OF_BLOCK;
+    result
+}
+
+pub fn reverse<I: Iterator<Item = Result<u16>>>(iter: I) -> ReverseIterator<I> {
+    ReverseIterator {
+        iter,
+        zeros: 0,
+        byte: None,
+        finished: false,
+    }
+}
+
+// Runs are expanded lazily, so that whoever reads the bytes can stop at a block's bound before a
+// corrupted run length gets spelled out.
+pub struct ReverseIterator<I> {
+    iter: I,
+    zeros: u64,
+    byte: Option<u8>,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = Result<u16>>> Iterator for ReverseIterator<I> {
+    type Item = Result<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.zeros > 0 {
+                self.zeros -= 1;
+                return Some(Ok(0));
+            }
+            if let Some(byte) = self.byte.take() {
+                return Some(Ok(byte));
+            }
+            if self.finished {
+                return self
+                    .iter
+                    .next()
+                    .map(|_| Err(Error::new("Symbols after end of block")));
+            }
+
+            // a run, which may be empty, up to the symbol that ends it
+            let mut weight: u64 = 1;
+            loop {
+                let symbol = match self.iter.next() {
+                    Some(Ok(symbol)) => symbol,
+                    Some(Err(err)) => return Some(Err(err)),
+                    None => return Some(Err(Error::new("Missing end of block"))),
+                };
+
+                match symbol {
+                    RUN_A | RUN_B if weight <= u32::MAX as u64 => {
+                        self.zeros += weight * (symbol - RUN_A + 1) as u64;
+                        weight *= 2;
+                    }
+                    RUN_A | RUN_B => return Some(Err(Error::new("Zero run too long"))),
+                    END_OF_BLOCK => self.finished = true,
+                    _ if (symbol as usize) < ALPHABET_SIZE => self.byte = Some((symbol - 1) as u8),
+                    _ => return Some(Err(Error::new("Unknown symbol"))),
+                }
+                if symbol > RUN_B {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reverse_vec(symbols: &[u16]) -> Result<Vec<u8>> {
+        reverse(symbols.iter().map(|x| Ok(*x))).collect()
+    }
+
+    #[test]
+    fn apply_test() {
+        let data = [0, 0, 0, 5, 0, 0, 0, 0, 255, 0];
+        assert_eq!(
+            apply(&data),
+            vec![RUN_A, RUN_A, 6, RUN_B, RUN_A, 256, RUN_A, END_OF_BLOCK]
+        );
+        assert_eq!(apply(&[]), vec![END_OF_BLOCK]);
+    }
+
+    #[test]
+    fn reverse_applied_test() {
+        let mut data = vec![3, 1, 0, 0, 2];
+        for run in [1, 2, 3, 7, 8, 100, 1000, 65536] {
+            data.extend(vec![0; run]);
+            data.push(run as u8 | 1);
+        }
+        data.extend(vec![0; 77]);
+
+        let symbols = apply(&data);
+        assert!(symbols.len() < data.len() / 10);
+        assert_eq!(reverse_vec(&symbols), Ok(data));
+        assert_eq!(reverse_vec(&apply(&[])), Ok(vec![]));
+    }
+
+    #[test]
+    fn reverse_invalid_test() {
+        assert!(reverse_vec(&[RUN_A, 5]).is_err());
+        assert!(reverse_vec(&[5, END_OF_BLOCK, 5]).is_err());
+        assert!(reverse_vec(&[300, END_OF_BLOCK]).is_err());
+        assert!(reverse_vec(&[RUN_B; 64]).is_err());
+
+        // a long run is only expanded as far as it is read
+        let mut symbols = vec![RUN_B; 30];
+        symbols.push(END_OF_BLOCK);
+        assert!(reverse(symbols.into_iter().map(Ok))
+            .take(10)
+            .all(|x| x == Ok(0)));
+    }
+}
@@ -1,16 +1,38 @@
-use burrows_wheeler::{decode, encode};
+use burrows_wheeler::result::Result;
+use burrows_wheeler::{decode, decode_parallel, encode, encode_parallel};
 use std::fs::File;
-use std::io::{Cursor, Read};
+use std::io::{BufReader, Cursor, Read};
 
 #[test]
 fn war_and_peace_test() {
     let file_name = "files/war&peace.txt";
     let war_and_peace = File::open(file_name).unwrap();
-    let encoded = encode(Box::new(war_and_peace)).collect::<Vec<_>>();
+    let encoded = encode(Box::new(war_and_peace))
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
     let encoded = Cursor::new(encoded);
 
-    let file = File::open(file_name).unwrap().bytes().map(|x| x.unwrap());
+    let file = BufReader::new(File::open(file_name).unwrap())
+        .bytes()
+        .map(|x| x.unwrap());
     let decoded = decode(Box::new(encoded)).map(|x| x.unwrap());
 
     assert_eq!(std::cmp::Ordering::Equal, file.cmp(decoded));
 }
+
+#[test]
+fn war_and_peace_parallel_test() {
+    let file_name = "files/war&peace.txt";
+    let war_and_peace = File::open(file_name).unwrap();
+    let encoded = encode_parallel(Box::new(war_and_peace), 4)
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    let encoded = Cursor::new(encoded);
+
+    let file = BufReader::new(File::open(file_name).unwrap())
+        .bytes()
+        .map(|x| x.unwrap());
+    let decoded = decode_parallel(Box::new(encoded), 4).map(|x| x.unwrap());
+
+    assert_eq!(std::cmp::Ordering::Equal, file.cmp(decoded));
+}
@@ -0,0 +1,33 @@
+use burrows_wheeler::fm_index::FmIndex;
+use std::fs;
+
+#[test]
+fn war_and_peace_search_test() {
+    let text = fs::read("files/war&peace.txt").unwrap();
+    let index = FmIndex::new(&text, 32).unwrap();
+
+    for pattern in [
+        "Natasha",
+        "Pierre Bezukhov",
+        "Borodino",
+        "Napoleon's",
+        "xyzzy",
+    ] {
+        let pattern = pattern.as_bytes();
+        let expected: Vec<usize> = text
+            .windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| *window == pattern)
+            .map(|(i, _)| i)
+            .collect();
+
+        assert_eq!(index.count(pattern), expected.len());
+        assert_eq!(index.locate(pattern), expected);
+        for position in expected.into_iter().take(10) {
+            assert_eq!(
+                index.extract(position..position + pattern.len()).unwrap(),
+                pattern
+            );
+        }
+    }
+}
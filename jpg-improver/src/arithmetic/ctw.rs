@@ -0,0 +1,320 @@
+use super::{BitPredictor, RangeDecoder, RangeEncoder};
+use burrows_wheeler::result::{Error, Result};
+use std::str::FromStr;
+
+// Bytes are coded bit by bit, the bits seen of the current byte select one of 255 binary context
+// trees, and the contexts of a tree are the last `depth` bits of the bytes before it, the latest
+// first. Every node weighs its own Krichevsky–Trofimov estimate with the product of its children's
+// weighted probabilities, which keeps the redundancy within the best tree source of that depth
+// plus the cost of describing it, as long as no node is lost to a collision in the table.
+pub const MAX_DEPTH: usize = 48;
+const DEFAULT_DEPTH: usize = 24;
+
+// Nodes live in a hash table, a node whose slot is taken by another context starts over.
+const TABLE_BITS: u32 = 22;
+
+// Probabilities are fractions of 2^32 and logarithms are base 2 in units of 2^-12 bits, both kept in
+// integers, so every platform codes the same stream.
+const PROBABILITY_BITS: u32 = 32;
+const ONE: u64 = 1 << PROBABILITY_BITS;
+const LOG_BITS: u32 = 12;
+const LOG_MASK: u32 = (1 << LOG_BITS) - 1;
+// log2(β) is kept within this many bits, so a node can always change its mind.
+const MAX_LOG_BETA: i32 = 32 << LOG_BITS;
+
+// LOG2[i] is log2(1 + i / 2^12) and EXP2[i] is 2^(-i / 2^12) as a fraction of 2^32.
+static LOG2: [u32; 1 << LOG_BITS] = build_log2();
+static EXP2: [u64; 1 << LOG_BITS] = build_exp2();
+
+// Squaring the mantissa doubles its logarithm, so every square past 2 gives the next bit of it.
+const fn build_log2() -> [u32; 1 << LOG_BITS] {
+    const EXTRA_BITS: u32 = 4;
+    let mut table = [0; 1 << LOG_BITS];
+    let mut i = 0;
+    while i < table.len() {
+        let mut y = (ONE + ((i as u64) << (PROBABILITY_BITS - LOG_BITS))) as u128;
+        let mut log = 0;
+        let mut bit = 0;
+        while bit < LOG_BITS + EXTRA_BITS {
+            y = (y * y) >> PROBABILITY_BITS;
+            log <<= 1;
+            if y >= 2 * ONE as u128 {
+                y >>= 1;
+                log |= 1;
+            }
+            bit += 1;
+        }
+        table[i] = (log + (1 << (EXTRA_BITS - 1))) >> EXTRA_BITS;
+        i += 1;
+    }
+    table
+}
+
+// 2^(-i / 2^12) is the product of the roots 2^(-2^k / 2^12) for the bits k set in i, and every
+// root is the square root of the one after it.
+const fn build_exp2() -> [u64; 1 << LOG_BITS] {
+    let mut roots = [0; LOG_BITS as usize];
+    let mut k = LOG_BITS as usize - 1;
+    roots[k] = ((ONE as u128) << (PROBABILITY_BITS - 1)).isqrt() as u64;
+    while k > 0 {
+        k -= 1;
+        roots[k] = ((roots[k + 1] as u128) << PROBABILITY_BITS).isqrt() as u64;
+    }
+
+    let mut table = [0; 1 << LOG_BITS];
+    let mut i = 0;
+    while i < table.len() {
+        let mut value = ONE as u128;
+        let mut k = 0;
+        while k < LOG_BITS as usize {
+            if i & (1 << k) != 0 {
+                value =
+                    (value * roots[k] as u128 + (1 << (PROBABILITY_BITS - 1))) >> PROBABILITY_BITS;
+            }
+            k += 1;
+        }
+        table[i] = value as u64;
+        i += 1;
+    }
+    table
+}
+
+// log2(x) for x > 0.
+fn log2(x: u64) -> i32 {
+    let n = u64::BITS - 1 - x.leading_zeros();
+    let mantissa = match n.checked_sub(LOG_BITS) {
+        Some(shift) => x >> shift,
+        None => x << (LOG_BITS - n),
+    };
+    ((n << LOG_BITS) + LOG2[(mantissa as u32 & LOG_MASK) as usize]) as i32
+}
+
+// The weight β / (1 + β) a node gives its own estimate, as a fraction of 2^32.
+fn weight(log_beta: i32) -> u64 {
+    let magnitude = log_beta.unsigned_abs();
+    // 2^-|log2(β)|
+    let power = EXP2[(magnitude & LOG_MASK) as usize]
+        .checked_shr(magnitude >> LOG_BITS)
+        .unwrap_or(0);
+    let weight = (((ONE as u128) << PROBABILITY_BITS) / (ONE + power) as u128) as u64;
+    match log_beta >= 0 {
+        true => weight,
+        false => ONE - weight,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    pub depth: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            depth: DEFAULT_DEPTH,
+        }
+    }
+}
+
+// `ctw`, optionally followed by the depth in bits, as in `ctw:24`.
+impl FromStr for Options {
+    type Err = Error;
+
+    fn from_str(str: &str) -> std::result::Result<Self, Self::Err> {
+        let depth = match str.split_once(':') {
+            Some(("ctw", depth)) => match depth.parse() {
+                Ok(depth) if depth <= MAX_DEPTH => depth,
+                _ => return Err(Error::new("Invalid CTW depth")),
+            },
+            None if str == "ctw" => DEFAULT_DEPTH,
+            _ => return Err(Error::new("Unknown coder")),
+        };
+        Ok(Options { depth })
+    }
+}
+
+impl Options {
+    pub fn validate(&self) -> Result<()> {
+        if self.depth > MAX_DEPTH {
+            return Err(Error::new("Invalid CTW depth"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Node {
+    check: u32,
+    counts: [u32; 2],
+    // log2(β), where β is the ratio of the node's estimate to its children's weighted probability
+    log_beta: i32,
+}
+
+impl Node {
+    // The Krichevsky–Trofimov estimate of the next bit being a one, (c + 1/2) / (n + 1).
+    fn estimate(&self) -> u64 {
+        let total = self.counts[0] as u128 + self.counts[1] as u128;
+        let ones = 2 * self.counts[1] as u128 + 1;
+        ((ones << PROBABILITY_BITS) / (2 * total + 2)) as u64
+    }
+
+    fn log_estimate(&self, bit: bool) -> i32 {
+        let total = self.counts[0] as u64 + self.counts[1] as u64;
+        log2(2 * self.counts[bit as usize] as u64 + 1) - log2(2 * total + 2)
+    }
+}
+
+struct Model {
+    nodes: Vec<Node>,
+    depth: usize,
+    // the bits of the current byte after a leading one and the bytes before
+    c0: u32,
+    history: u64,
+    // the slots of the current context from the root down and their weighted probabilities of a one
+    path: Vec<usize>,
+    weighted: Vec<u64>,
+}
+
+impl Model {
+    fn new(options: &Options) -> Model {
+        Model {
+            nodes: vec![Node::default(); 1 << TABLE_BITS],
+            depth: options.depth,
+            c0: 1,
+            history: 0,
+            path: vec![0; options.depth + 1],
+            weighted: vec![0; options.depth + 1],
+        }
+    }
+}
+
+impl BitPredictor for Model {
+    fn p(&mut self) -> u32 {
+        for depth in 0..=self.depth {
+            let context = self.history & ((1 << depth) - 1);
+            let hash = ((self.c0 as u64) << 56 ^ (depth as u64) << 48 ^ context)
+                .wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            let (slot, check) = ((hash >> (64 - TABLE_BITS)) as usize, hash as u32);
+            if self.nodes[slot].check != check {
+                self.nodes[slot] = Node {
+                    check,
+                    ..Node::default()
+                };
+            }
+            self.path[depth] = slot;
+        }
+
+        // the leaves only have their estimate, every node above weighs it with its child's
+        let mut p = self.nodes[self.path[self.depth]].estimate();
+        self.weighted[self.depth] = p.clamp(1, ONE - 1);
+        for depth in (0..self.depth).rev() {
+            let node = &self.nodes[self.path[depth]];
+            let weight = weight(node.log_beta);
+            p = (weight * node.estimate() + (ONE - weight) * p) >> PROBABILITY_BITS;
+            p = p.clamp(1, ONE - 1);
+            self.weighted[depth] = p;
+        }
+        ((p + (1 << 15)) >> 16).clamp(1, 65535) as u32
+    }
+
+    fn update(&mut self, bit: bool) {
+        for depth in 0..=self.depth {
+            let node = &mut self.nodes[self.path[depth]];
+            if depth < self.depth {
+                let child = match bit {
+                    true => self.weighted[depth + 1],
+                    false => ONE - self.weighted[depth + 1],
+                };
+                let log_child = log2(child) - ((PROBABILITY_BITS as i32) << LOG_BITS);
+                let log_beta = node.log_beta + node.log_estimate(bit) - log_child;
+                node.log_beta = log_beta.clamp(-MAX_LOG_BETA, MAX_LOG_BETA);
+            }
+            node.counts[bit as usize] += 1;
+        }
+
+        self.c0 = self.c0 << 1 | bit as u32;
+        if self.c0 >= 256 {
+            self.history = self.history << 8 | (self.c0 & 0xFF) as u64;
+            self.c0 = 1;
+        }
+    }
+}
+
+pub fn encode(data: &[u8], options: &Options) -> Result<Vec<u8>> {
+    options.validate()?;
+    Ok(RangeEncoder::new().encode_bytes(data, &mut Model::new(options)))
+}
+
+pub fn decode(data: &[u8], options: &Options) -> Result<Vec<u8>> {
+    options.validate()?;
+    RangeDecoder::decode_bytes(data, &mut Model::new(options))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::corpus;
+
+    #[test]
+    fn options_from_str_test() {
+        assert_eq!("ctw".parse(), Ok(Options::default()));
+        assert_eq!("ctw:0".parse(), Ok(Options { depth: 0 }));
+        assert!("ctw:49".parse::<Options>().is_err());
+        assert!("ctw:".parse::<Options>().is_err());
+        assert!("ctx:8".parse::<Options>().is_err());
+    }
+
+    #[test]
+    fn tables_test() {
+        assert_eq!(log2(1), 0);
+        assert_eq!(log2(1 << 40), 40 << LOG_BITS);
+        // log2(3) = 1.5849625
+        assert!(log2(3).abs_diff(6492) <= 1);
+        // 2^(-1/2) = 0.70710678
+        assert!(EXP2[1 << (LOG_BITS - 1)].abs_diff(3_037_000_500) <= 4);
+        assert_eq!(weight(0), ONE / 2);
+        assert_eq!(weight(1 << LOG_BITS), 2 * ONE / 3);
+        assert_eq!(weight(-(1 << LOG_BITS)), ONE - 2 * ONE / 3);
+        assert_eq!(weight(MAX_LOG_BETA), ONE - 1);
+    }
+
+    #[test]
+    fn reverse_test() {
+        for depth in [0, 8, DEFAULT_DEPTH, MAX_DEPTH] {
+            let options = Options { depth };
+            let len = corpus::assert_reverses(
+                |input| encode(input, &options).unwrap(),
+                |encoded| decode(encoded, &options),
+            );
+            if depth >= DEFAULT_DEPTH {
+                assert!(len < corpus::text().len() / 5);
+            }
+        }
+        assert!(decode(&[0, 0, 1], &Options::default()).is_err());
+    }
+
+    #[test]
+    fn depth_test() {
+        // Every third byte repeats the one two before it, which only a depth of 16 bits sees.
+        let noise = corpus::noise(6000, 4, 3);
+        let input: Vec<u8> = noise
+            .chunks(2)
+            .flat_map(|pair| [pair[0], pair[1] | 0x10, pair[0]])
+            .collect();
+        let shallow = encode(&input, &Options { depth: 8 }).unwrap().len();
+        let deep = encode(&input, &Options { depth: 16 }).unwrap().len();
+        assert!(deep < shallow * 4 / 5);
+    }
+
+    #[test]
+    fn redundancy_test() {
+        // a constant source costs every node on the byte's path at most log2(n) / 2 + 1 bits, and
+        // the weighting at most one more bit per level above the leaves
+        let n = 10_000;
+        let options = Options { depth: 8 };
+        let encoded = encode(&vec![b'a'; n], &options).unwrap();
+        let bound = 8.0 * ((n as f64).log2() / 2.0 + 1.0 + options.depth as f64);
+        // the length prefix and the bytes the coder flushes
+        assert!((encoded.len().saturating_sub(8) * 8) as f64 <= bound);
+    }
+}
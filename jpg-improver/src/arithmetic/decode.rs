@@ -1,93 +1,115 @@
 use super::{
-    frequencies::Frequencies, CODE_VALUE_BITS, CODE_VALUE_FIRST_QUARTER, CODE_VALUE_HALF,
-    CODE_VALUE_MAX, CODE_VALUE_THIRD_QUARTER, EOF_SYMBOL,
+    encode::BitPredictor,
+    frequencies::{Frequencies, Model},
+    EOF_SYMBOL, RANGE_TOP,
 };
+use burrows_wheeler::result::{Error, Result};
+use std::slice::Iter;
 
-fn bit_iterator(data: &[u8]) -> impl Iterator<Item = bool> + '_ {
-    data.iter()
-        .flat_map(|x| (0..8).rev().map(move |i| (x >> i) & 1 == 1))
+// Follows the encoder's range, keeping the code relative to its low end, so carries never show up.
+pub(crate) struct RangeDecoder<'a> {
+    code: u32,
+    range: u32,
+    unit: u32,
+    data: Iter<'a, u8>,
 }
 
-fn slide(iter: &mut Box<impl Iterator<Item = bool>>, code_value: &mut usize) {
-    match iter.next() {
-        Some(next_bit) => *code_value = 2 * *code_value + (next_bit as usize),
-        None => *code_value *= 2,
+impl<'a> RangeDecoder<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> RangeDecoder<'a> {
+        let mut decoder = RangeDecoder {
+            code: 0,
+            range: u32::MAX,
+            unit: 0,
+            data: data.iter(),
+        };
+        for _ in 0..4 {
+            decoder.code = decoder.code << 8 | decoder.next_byte();
+        }
+        decoder
     }
-}
 
-fn get_symbol_index(frequencies: &mut Frequencies, cum: usize) -> usize {
-    let mut symbol_index = 1;
-    while frequencies.low(symbol_index) > cum {
-        symbol_index += 1;
+    fn next_byte(&mut self) -> u32 {
+        self.data.next().copied().unwrap_or(0) as u32
     }
-    symbol_index
-}
 
-fn next_symbol_index(
-    iter: &mut Box<impl Iterator<Item = bool>>,
-    frequencies: &mut Frequencies,
-    low: &mut usize,
-    high: &mut usize,
-    code_value: &mut usize,
-) -> usize {
-    let range = *high - *low + 1;
-    let total = frequencies.total();
-    let cum = ((*code_value - *low + 1) * total - 1) / range;
+    // Where the code falls out of `total`, the interval holding it has to be consumed next.
+    pub(crate) fn target(&mut self, total: usize) -> usize {
+        self.unit = self.range / total as u32;
+        (self.code / self.unit).min(total as u32 - 1) as usize
+    }
 
-    let symbol_index = get_symbol_index(frequencies, cum);
-    let symbol_low = frequencies.low(symbol_index);
-    let symbol_high = frequencies.high(symbol_index);
-    *high = *low + range * symbol_high / total - 1;
-    *low += range * symbol_low / total;
+    pub(crate) fn consume(&mut self, low: usize, high: usize) {
+        self.code -= self.unit * low as u32;
+        self.range = self.unit * (high - low) as u32;
 
-    loop {
-        if *high < CODE_VALUE_HALF {
-        } else if *low >= CODE_VALUE_HALF {
-            *code_value -= CODE_VALUE_HALF;
-            *low -= CODE_VALUE_HALF;
-            *high -= CODE_VALUE_HALF;
-        } else if *low >= CODE_VALUE_FIRST_QUARTER && *high < CODE_VALUE_THIRD_QUARTER {
-            *code_value -= CODE_VALUE_FIRST_QUARTER;
-            *low -= CODE_VALUE_FIRST_QUARTER;
-            *high -= CODE_VALUE_FIRST_QUARTER;
+        while self.range < RANGE_TOP {
+            self.range <<= 8;
+            self.code = self.code << 8 | self.next_byte();
+        }
+    }
+
+    pub(crate) fn decode_bit(&mut self, probability: u32) -> bool {
+        let split = (self.range >> 16) * probability;
+        let bit = self.code < split;
+        if bit {
+            self.range = split;
         } else {
-            break;
+            self.code -= split;
+            self.range -= split;
+        }
+
+        while self.range < RANGE_TOP {
+            self.range <<= 8;
+            self.code = self.code << 8 | self.next_byte();
+        }
+        bit
+    }
+
+    // The bytes `RangeEncoder::encode_bytes` codes, with the same predictor.
+    pub(crate) fn decode_bytes(data: &[u8], predictor: &mut impl BitPredictor) -> Result<Vec<u8>> {
+        let (len, data) = match data.split_first_chunk() {
+            Some((len, data)) => (u32::from_be_bytes(*len), data),
+            None => return Err(Error::new("Unexpected end of data")),
+        };
+
+        let mut decoder = RangeDecoder::new(data);
+        let mut decoded = Vec::new();
+        for _ in 0..len {
+            let byte = (0..8).fold(0, |byte, _| {
+                let bit = decoder.decode_bit(predictor.p());
+                predictor.update(bit);
+                byte << 1 | bit as u8
+            });
+            decoded.push(byte);
         }
-        *low *= 2;
-        *high = 2 * *high + 1;
-        slide(iter, code_value);
+        Ok(decoded)
     }
 
-    symbol_index
+    fn decode(&mut self, model: &impl Model) -> usize {
+        let symbol = model.find(self.target(model.total()));
+        let (low, high) = model.interval(symbol);
+        self.consume(low, high);
+        symbol
+    }
 }
 
 pub fn decode(data: &[u8]) -> Vec<u8> {
-    let mut encoded_data = Box::new(bit_iterator(data));
-    let mut decoded_data = Vec::new();
-    let mut frequencies = Frequencies::new();
-    let mut low = 0;
-    let mut high = CODE_VALUE_MAX;
-    let mut code_value = 0;
+    decode_with::<Frequencies>(data)
+}
 
-    for _ in 0..CODE_VALUE_BITS {
-        slide(&mut encoded_data, &mut code_value);
-    }
+pub fn decode_with<M: Model>(data: &[u8]) -> Vec<u8> {
+    let mut decoder = RangeDecoder::new(data);
+    let mut decoded_data = Vec::new();
+    let mut model = M::new();
 
     loop {
-        let symbol_index = next_symbol_index(
-            &mut encoded_data,
-            &mut frequencies,
-            &mut low,
-            &mut high,
-            &mut code_value,
-        );
-        if symbol_index == EOF_SYMBOL {
+        let symbol = decoder.decode(&model);
+        if symbol == EOF_SYMBOL {
             break;
         }
 
-        let char = frequencies.index_to_char[symbol_index];
-        decoded_data.push(char as u8);
-        frequencies.update(symbol_index);
+        decoded_data.push(symbol as u8);
+        model.update(symbol);
     }
     decoded_data
 }
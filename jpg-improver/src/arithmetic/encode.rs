@@ -1,108 +1,129 @@
 use super::{
-    frequencies::Frequencies, CODE_VALUE_FIRST_QUARTER, CODE_VALUE_HALF, CODE_VALUE_MAX,
-    CODE_VALUE_THIRD_QUARTER, EOF_SYMBOL,
+    frequencies::{Frequencies, Model},
+    EOF_SYMBOL, RANGE_TOP,
 };
 
-pub struct BitWriter {
-    pub data: Vec<u8>,
-    bit_index: isize,
+// A model of the bits of bytes, the highest first, for `encode_bit` and `decode_bit`.
+pub(crate) trait BitPredictor {
+    // The probability of the next bit being a one out of 2^16, neither 0 nor 2^16.
+    fn p(&mut self) -> u32;
+
+    fn update(&mut self, bit: bool);
+}
+
+// A range coder that carries into the bytes it has already settled on: the byte below the carry
+// is held back, as are the 0xFF bytes after it, until it is known whether a carry reaches them.
+pub(crate) struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: Option<u8>,
+    pending: u64,
+    output: Vec<u8>,
 }
 
-impl BitWriter {
-    fn new() -> BitWriter {
-        BitWriter {
-            data: vec![0],
-            bit_index: 7,
+impl RangeEncoder {
+    pub(crate) fn new() -> RangeEncoder {
+        RangeEncoder {
+            low: 0,
+            range: u32::MAX,
+            cache: None,
+            pending: 0,
+            output: Vec::new(),
         }
     }
 
-    fn write(&mut self, bit: bool) {
-        if self.bit_index == -1 {
-            self.data.push(0);
-            self.bit_index = 7;
+    // The interval from `low` to `high` out of `total`, which can be up to `MAX_FREQUENCY`.
+    pub(crate) fn encode(&mut self, low: usize, high: usize, total: usize) {
+        let unit = self.range / total as u32;
+        self.low += (unit * low as u32) as u64;
+        self.range = unit * (high - low) as u32;
+
+        while self.range < RANGE_TOP {
+            self.range <<= 8;
+            self.shift_low();
         }
-        let len = self.data.len();
-        self.data[len - 1] |= (bit as u8) << self.bit_index;
-        self.bit_index -= 1;
     }
-}
 
-fn encode_following_bit(writer: &mut BitWriter, bits_to_follow: &mut i32, bit: bool) {
-    writer.write(bit);
-    while *bits_to_follow > 0 {
-        writer.write(!bit);
-        *bits_to_follow -= 1;
+    // A bit with the given probability of a one out of 2^16, which has to be neither 0 nor 2^16.
+    pub(crate) fn encode_bit(&mut self, bit: bool, probability: u32) {
+        let split = (self.range >> 16) * probability;
+        if bit {
+            self.range = split;
+        } else {
+            self.low += split as u64;
+            self.range -= split;
+        }
+
+        while self.range < RANGE_TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
     }
-}
 
-fn encode_char(
-    writer: &mut BitWriter,
-    frequencies: &mut Frequencies,
-    low: &mut usize,
-    high: &mut usize,
-    bits_to_follow: &mut i32,
-    char_index: usize,
-) {
-    let range = *high - *low + 1;
-    let total = frequencies.total();
-    let char_low = frequencies.low(char_index);
-    let char_high = frequencies.high(char_index);
-    *high = *low + range * char_high / total - 1;
-    *low += range * char_low / total;
-
-    loop {
-        if *high < CODE_VALUE_HALF {
-            encode_following_bit(writer, bits_to_follow, false);
-        } else if *low >= CODE_VALUE_HALF {
-            encode_following_bit(writer, bits_to_follow, true);
-            *low -= CODE_VALUE_HALF;
-            *high -= CODE_VALUE_HALF;
-        } else if *low >= CODE_VALUE_FIRST_QUARTER && *high < CODE_VALUE_THIRD_QUARTER {
-            *bits_to_follow += 1;
-            *low -= CODE_VALUE_FIRST_QUARTER;
-            *high -= CODE_VALUE_FIRST_QUARTER;
+    // The bytes coded bit by bit, the highest first, after their number as a big-endian `u32`.
+    pub(crate) fn encode_bytes(
+        mut self,
+        data: &[u8],
+        predictor: &mut impl BitPredictor,
+    ) -> Vec<u8> {
+        for byte in data {
+            for i in (0..8).rev() {
+                let bit = (byte >> i) & 1 == 1;
+                self.encode_bit(bit, predictor.p());
+                predictor.update(bit);
+            }
+        }
+
+        let mut encoded = (data.len() as u32).to_be_bytes().to_vec();
+        encoded.extend(self.finish());
+        encoded
+    }
+
+    fn shift_low(&mut self) {
+        if self.low < 0xFF00_0000 || self.low > u32::MAX as u64 {
+            let carry = (self.low >> 32) as u8;
+            if let Some(cache) = self.cache {
+                self.output.push(cache.wrapping_add(carry));
+            }
+            for _ in 0..self.pending {
+                self.output.push(0xFF_u8.wrapping_add(carry));
+            }
+            self.pending = 0;
+            self.cache = Some((self.low >> 24) as u8);
         } else {
-            break;
+            self.pending += 1;
+        }
+        self.low = (self.low << 8) & u32::MAX as u64;
+    }
+
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+
+        // the decoder reads zeros past the end
+        while self.output.last() == Some(&0) {
+            self.output.pop();
         }
-        *low *= 2;
-        *high = 2 * *high + 1;
+        self.output
     }
 }
 
 pub fn encode(data: &[u8]) -> Vec<u8> {
-    let mut writer = BitWriter::new();
-    let mut frequencies = Frequencies::new();
-    let mut low = 0;
-    let mut high = CODE_VALUE_MAX;
-    let mut bits_to_follow = 0;
+    encode_with::<Frequencies>(data)
+}
+
+pub fn encode_with<M: Model>(data: &[u8]) -> Vec<u8> {
+    let mut model = M::new();
+    let mut encoder = RangeEncoder::new();
 
     for i in data {
-        let char_index = frequencies.char_to_index[*i as usize];
-        encode_char(
-            &mut writer,
-            &mut frequencies,
-            &mut low,
-            &mut high,
-            &mut bits_to_follow,
-            char_index,
-        );
-        frequencies.update(char_index);
+        let (low, high) = model.interval(*i as usize);
+        encoder.encode(low, high, model.total());
+        model.update(*i as usize);
     }
 
-    encode_char(
-        &mut writer,
-        &mut frequencies,
-        &mut low,
-        &mut high,
-        &mut bits_to_follow,
-        EOF_SYMBOL,
-    );
-    bits_to_follow += 1;
-
-    encode_following_bit(
-        &mut writer,
-        &mut bits_to_follow,
-        low >= CODE_VALUE_FIRST_QUARTER,
-    );
-    writer.data
+    let (low, high) = model.interval(EOF_SYMBOL);
+    encoder.encode(low, high, model.total());
+    encoder.finish()
 }
@@ -1,16 +1,42 @@
-use super::{MAX_FREQUENCY, NUMBER_OF_CHARS, NUMBER_OF_SYMBOLS};
+use super::{MAX_FREQUENCY, NUMBER_OF_SYMBOLS};
 
+// An adaptive model of the symbols, the bytes and then `EOF_SYMBOL`: every symbol owns an interval
+// of the cumulative frequencies, as wide as the symbol is frequent.
+pub trait Model {
+    fn new() -> Self;
+
+    fn total(&self) -> usize;
+
+    fn interval(&self, symbol: usize) -> (usize, usize);
+
+    // the symbol whose interval holds `cumulative`
+    fn find(&self, cumulative: usize) -> usize;
+
+    fn update(&mut self, symbol: usize);
+}
+
+// Keeps the symbols sorted by frequency, so frequent symbols are found and updated quickly.
 pub struct Frequencies {
-    pub char_to_index: Vec<usize>,
-    pub index_to_char: Vec<i32>,
+    char_to_index: Vec<usize>,
+    index_to_char: Vec<i32>,
     frequencies: Vec<usize>,
     cumulative: Vec<usize>,
 }
 
 impl Frequencies {
-    pub fn new() -> Frequencies {
+    fn low(&self, symbol_index: usize) -> usize {
+        self.cumulative[symbol_index]
+    }
+
+    fn high(&self, symbol_index: usize) -> usize {
+        self.cumulative[symbol_index - 1]
+    }
+}
+
+impl Model for Frequencies {
+    fn new() -> Frequencies {
         let mut model = Frequencies {
-            char_to_index: (0..NUMBER_OF_CHARS).map(|i| i + 1).collect(),
+            char_to_index: (0..NUMBER_OF_SYMBOLS).map(|i| i + 1).collect(),
             index_to_char: (0..NUMBER_OF_SYMBOLS + 1).map(|i| (i as i32) - 1).collect(),
             frequencies: vec![1; NUMBER_OF_SYMBOLS + 1],
             cumulative: (0..NUMBER_OF_SYMBOLS + 1)
@@ -21,24 +47,31 @@ impl Frequencies {
         model
     }
 
-    pub fn total(&self) -> usize {
+    fn total(&self) -> usize {
         self.cumulative[0]
     }
 
-    pub fn low(&self, symbol_index: usize) -> usize {
-        self.cumulative[symbol_index]
+    fn interval(&self, symbol: usize) -> (usize, usize) {
+        let symbol_index = self.char_to_index[symbol];
+        (self.low(symbol_index), self.high(symbol_index))
     }
 
-    pub fn high(&self, symbol_index: usize) -> usize {
-        self.cumulative[symbol_index - 1]
+    fn find(&self, cumulative: usize) -> usize {
+        let mut symbol_index = 1;
+        while self.low(symbol_index) > cumulative {
+            symbol_index += 1;
+        }
+        self.index_to_char[symbol_index] as usize
     }
 
-    pub fn update(&mut self, symbol_index: usize) {
+    fn update(&mut self, symbol: usize) {
+        let symbol_index = self.char_to_index[symbol];
+
         // halve if exceeded
         if self.total() == MAX_FREQUENCY {
             let mut cum = 0;
             for i in (0..=NUMBER_OF_SYMBOLS).rev() {
-                self.frequencies[i] = (self.frequencies[i] + 1) / 2;
+                self.frequencies[i] = self.frequencies[i].div_ceil(2);
                 self.cumulative[i] = cum;
                 cum += self.frequencies[i];
             }
@@ -46,7 +79,7 @@ impl Frequencies {
 
         // update symbol index if needed
         let mut new_symbol_index = symbol_index;
-        while self.frequencies[new_symbol_index] == self.frequencies[(new_symbol_index - 1)] {
+        while self.frequencies[new_symbol_index] == self.frequencies[new_symbol_index - 1] {
             new_symbol_index -= 1;
         }
 
@@ -70,3 +103,124 @@ impl Frequencies {
         }
     }
 }
+
+// A binary indexed tree over the symbols in their natural order: finding and updating a symbol
+// take O(log n) whatever the statistics, where the sorted model degrades to O(n) on flat ones.
+pub struct FenwickFrequencies {
+    frequencies: Vec<usize>,
+    // `tree[i]` sums the frequencies of the `i & i.wrapping_neg()` symbols ending with symbol `i - 1`
+    tree: Vec<usize>,
+    total: usize,
+}
+
+impl FenwickFrequencies {
+    fn rebuild(&mut self) {
+        self.tree[0] = 0;
+        self.tree[1..].copy_from_slice(&self.frequencies);
+        for i in 1..=NUMBER_OF_SYMBOLS {
+            let parent = i + (i & i.wrapping_neg());
+            if parent <= NUMBER_OF_SYMBOLS {
+                self.tree[parent] += self.tree[i];
+            }
+        }
+        self.total = self.frequencies.iter().sum();
+    }
+
+    // the total frequency of the symbols before `symbol`
+    fn prefix(&self, symbol: usize) -> usize {
+        let mut sum = 0;
+        let mut i = symbol;
+        while i > 0 {
+            sum += self.tree[i];
+            i &= i - 1;
+        }
+        sum
+    }
+}
+
+impl Model for FenwickFrequencies {
+    fn new() -> FenwickFrequencies {
+        let mut model = FenwickFrequencies {
+            frequencies: vec![1; NUMBER_OF_SYMBOLS],
+            tree: vec![0; NUMBER_OF_SYMBOLS + 1],
+            total: 0,
+        };
+        model.rebuild();
+        model
+    }
+
+    fn total(&self) -> usize {
+        self.total
+    }
+
+    fn interval(&self, symbol: usize) -> (usize, usize) {
+        let low = self.prefix(symbol);
+        (low, low + self.frequencies[symbol])
+    }
+
+    fn find(&self, cumulative: usize) -> usize {
+        // descends from the largest node, skipping every node that ends at or below `cumulative`
+        let mut symbol = 0;
+        let mut remaining = cumulative;
+        let mut step = 1 << NUMBER_OF_SYMBOLS.ilog2();
+        while step > 0 {
+            let next = symbol + step;
+            if next <= NUMBER_OF_SYMBOLS && self.tree[next] <= remaining {
+                symbol = next;
+                remaining -= self.tree[next];
+            }
+            step >>= 1;
+        }
+        symbol
+    }
+
+    fn update(&mut self, symbol: usize) {
+        // halve if exceeded
+        if self.total == MAX_FREQUENCY {
+            for frequency in self.frequencies.iter_mut() {
+                *frequency = frequency.div_ceil(2);
+            }
+            self.rebuild();
+        }
+
+        self.frequencies[symbol] += 1;
+        self.total += 1;
+        let mut i = symbol + 1;
+        while i <= NUMBER_OF_SYMBOLS {
+            self.tree[i] += 1;
+            i += i & i.wrapping_neg();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn models_agree_test() {
+        let mut sorted = Frequencies::new();
+        let mut fenwick = FenwickFrequencies::new();
+
+        // long enough to halve the frequencies a few times
+        let mut state: u32 = 3;
+        for _ in 0..3 * MAX_FREQUENCY {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let symbol = (state >> 24) as usize % (32 + (state >> 16) as usize % 225);
+            sorted.update(symbol);
+            fenwick.update(symbol);
+        }
+
+        assert_eq!(sorted.total(), fenwick.total());
+        for symbol in 0..NUMBER_OF_SYMBOLS {
+            let (low, high) = sorted.interval(symbol);
+            assert_eq!(sorted.find(low), symbol);
+            assert_eq!(sorted.find(high - 1), symbol);
+
+            let (fenwick_low, fenwick_high) = fenwick.interval(symbol);
+            assert_eq!(high - low, fenwick_high - fenwick_low);
+            assert_eq!(fenwick.find(fenwick_low), symbol);
+            assert_eq!(fenwick.find(fenwick_high - 1), symbol);
+        }
+    }
+}
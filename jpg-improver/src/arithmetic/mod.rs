@@ -1,20 +1,25 @@
+pub mod ctw;
 mod decode;
 mod encode;
 mod frequencies;
+pub mod ppm;
 
-pub use decode::decode;
-pub use encode::encode;
+pub use decode::{decode, decode_with};
+pub use encode::{encode, encode_with};
+pub use frequencies::{FenwickFrequencies, Frequencies, Model};
+
+pub(crate) use decode::RangeDecoder;
+pub(crate) use encode::{BitPredictor, RangeEncoder};
 
 const NUMBER_OF_CHARS: usize = 256;
-const CODE_VALUE_BITS: i32 = 16;
-const CODE_VALUE_MAX: usize = (1 << CODE_VALUE_BITS) - 1;
-const CODE_VALUE_FIRST_QUARTER: usize = CODE_VALUE_MAX / 4 + 1;
-const CODE_VALUE_HALF: usize = 2 * CODE_VALUE_FIRST_QUARTER;
-const CODE_VALUE_THIRD_QUARTER: usize = 3 * CODE_VALUE_FIRST_QUARTER;
-const MAX_FREQUENCY: usize = CODE_VALUE_FIRST_QUARTER - 1;
-const EOF_SYMBOL: usize = NUMBER_OF_CHARS + 1;
+const EOF_SYMBOL: usize = NUMBER_OF_CHARS;
 const NUMBER_OF_SYMBOLS: usize = NUMBER_OF_CHARS + 1;
 
+// The range is kept at 24 bits or more, so the total frequency leaves at least 8 bits of it for
+// every unit of probability.
+const RANGE_TOP: u32 = 1 << 24;
+const MAX_FREQUENCY: usize = 1 << 16;
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -42,4 +47,27 @@ mod test {
             decode(&encode(&input.bytes().collect::<Vec<_>>()))
         );
     }
+
+    #[test]
+    fn reverse_long_test() {
+        // skewed enough for long runs of 0xFF bytes waiting on a carry, and long enough to rescale
+        let mut state: u32 = 1;
+        let input: Vec<u8> = (0..300_000)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                match state >> 24 {
+                    0..=249 => 0,
+                    x => x as u8,
+                }
+            })
+            .collect();
+        let encoded = encode(&input);
+        assert!(encoded.len() < input.len() / 10);
+        assert_eq!(input, decode(&encoded));
+        assert_eq!(Vec::<u8>::new(), decode(&encode(&[])));
+
+        let encoded_fenwick = encode_with::<FenwickFrequencies>(&input);
+        assert!(encoded_fenwick.len().abs_diff(encoded.len()) < encoded.len() / 100);
+        assert_eq!(input, decode_with::<FenwickFrequencies>(&encoded_fenwick));
+    }
 }
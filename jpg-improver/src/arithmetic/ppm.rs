@@ -0,0 +1,405 @@
+use super::{RangeDecoder, RangeEncoder, EOF_SYMBOL, NUMBER_OF_CHARS, NUMBER_OF_SYMBOLS};
+use burrows_wheeler::result::{Error, Result};
+use std::collections::hash_map::{Entry, HashMap};
+use std::str::FromStr;
+
+// Contexts are keyed by their bytes packed into a `u64` along with their order.
+pub const MAX_ORDER: usize = 7;
+const DEFAULT_ORDER: usize = 5;
+
+// Counts are halved once a context has seen this many symbols, which keeps the totals of the
+// intervals within the range coder's precision and lets old statistics fade.
+const MAX_COUNT: u32 = 1 << 13;
+
+// The model starts over once its contexts hold this many nodes, a context or a symbol seen in one
+// counting as one each, which bounds its memory to a few hundred megabytes.
+const MAX_NODES: usize = 1 << 22;
+
+// How much probability a context leaves for the symbols it has not seen yet: PPMC counts an escape
+// for every distinct symbol, PPMD half of one, taking the other half from the symbol's count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Escape {
+    C,
+    D,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    pub max_order: usize,
+    pub escape: Escape,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_order: DEFAULT_ORDER,
+            escape: Escape::D,
+        }
+    }
+}
+
+// `ppmc` or `ppmd`, optionally followed by the maximum order, as in `ppmd:5`.
+impl FromStr for Options {
+    type Err = Error;
+
+    fn from_str(str: &str) -> std::result::Result<Self, Self::Err> {
+        let (method, order) = match str.split_once(':') {
+            Some((method, order)) => match order.parse() {
+                Ok(order) if order <= MAX_ORDER => (method, order),
+                _ => return Err(Error::new("Invalid PPM order")),
+            },
+            None => (str, DEFAULT_ORDER),
+        };
+
+        let escape = match method {
+            "ppmc" => Escape::C,
+            "ppmd" => Escape::D,
+            _ => return Err(Error::new("Unknown PPM method")),
+        };
+        Ok(Options {
+            max_order: order,
+            escape,
+        })
+    }
+}
+
+impl Options {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_order > MAX_ORDER {
+            return Err(Error::new("Invalid PPM order"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Context {
+    symbols: Vec<(u8, u32)>,
+    total: u32,
+}
+
+impl Context {
+    // Whether the symbol is new to the context.
+    fn add(&mut self, symbol: u8) -> bool {
+        let new = match self.symbols.iter_mut().find(|(x, _)| *x == symbol) {
+            Some((_, count)) => {
+                *count += 1;
+                false
+            }
+            None => {
+                self.symbols.push((symbol, 1));
+                true
+            }
+        };
+        self.total += 1;
+
+        if self.total >= MAX_COUNT {
+            self.total = 0;
+            for (_, count) in self.symbols.iter_mut() {
+                *count = count.div_ceil(2);
+                self.total += *count;
+            }
+        }
+        new
+    }
+}
+
+struct Model {
+    options: Options,
+    contexts: HashMap<u64, Context>,
+    nodes: usize,
+    max_nodes: usize,
+    history: u64,
+    length: usize,
+    // symbols excluded while coding the current one are marked with its stamp
+    excluded: Vec<u32>,
+    stamp: u32,
+    // the intervals of the context being coded: symbol, low and high
+    intervals: Vec<(u8, usize, usize)>,
+}
+
+impl Model {
+    fn new(options: Options, max_nodes: usize) -> Model {
+        Model {
+            options,
+            contexts: HashMap::new(),
+            nodes: 0,
+            max_nodes,
+            history: 0,
+            length: 0,
+            excluded: vec![0; NUMBER_OF_CHARS],
+            stamp: 0,
+            intervals: Vec::new(),
+        }
+    }
+
+    fn top_order(&self) -> usize {
+        self.options.max_order.min(self.length)
+    }
+
+    fn key(&self, order: usize) -> u64 {
+        match order {
+            0 => 0,
+            order => (order as u64) << 56 | self.history & ((1 << (8 * order)) - 1),
+        }
+    }
+
+    fn start_symbol(&mut self) {
+        self.stamp += 1;
+    }
+
+    // Fills `intervals` for the context of the given order, leaving out the excluded symbols, and
+    // returns the total with the escape interval at its end. Zero means there is nothing to code.
+    fn intervals(&mut self, order: usize) -> usize {
+        self.intervals.clear();
+        let context = match self.contexts.get(&self.key(order)) {
+            Some(context) => context,
+            None => return 0,
+        };
+
+        let mut low = 0;
+        for (symbol, count) in context.symbols.iter() {
+            if self.excluded[*symbol as usize] == self.stamp {
+                continue;
+            }
+            let frequency = match self.options.escape {
+                Escape::C => *count as usize,
+                Escape::D => 2 * *count as usize - 1,
+            };
+            self.intervals.push((*symbol, low, low + frequency));
+            low += frequency;
+        }
+
+        match self.intervals.len() {
+            0 => 0,
+            distinct => low + distinct,
+        }
+    }
+
+    fn exclude_intervals(&mut self) {
+        for (symbol, _, _) in self.intervals.iter() {
+            self.excluded[*symbol as usize] = self.stamp;
+        }
+    }
+
+    // The symbols no context has excluded, coded when every context escapes.
+    fn remaining(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..NUMBER_OF_SYMBOLS).filter(move |x| *x == EOF_SYMBOL || self.excluded[*x] != self.stamp)
+    }
+
+    // Counts the byte in the context it was found in and in every higher one.
+    fn update(&mut self, symbol: u8, found: Option<usize>) {
+        let from = found.unwrap_or(0);
+        for order in from..=self.top_order() {
+            let context = match self.contexts.entry(self.key(order)) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    self.nodes += 1;
+                    entry.insert(Context::default())
+                }
+            };
+            if context.add(symbol) {
+                self.nodes += 1;
+            }
+        }
+        if self.nodes > self.max_nodes {
+            self.contexts.clear();
+            self.nodes = 0;
+        }
+
+        self.history = (self.history << 8 | symbol as u64) & ((1 << (8 * MAX_ORDER)) - 1);
+        self.length += 1;
+    }
+}
+
+pub fn encode(data: &[u8], options: &Options) -> Result<Vec<u8>> {
+    options.validate()?;
+    Ok(encode_with_model(data, Model::new(*options, MAX_NODES)))
+}
+
+fn encode_with_model(data: &[u8], mut model: Model) -> Vec<u8> {
+    let mut encoder = RangeEncoder::new();
+
+    for symbol in data.iter().map(|x| *x as usize).chain([EOF_SYMBOL]) {
+        let found = encode_symbol(&mut model, &mut encoder, symbol);
+        if symbol != EOF_SYMBOL {
+            model.update(symbol as u8, found);
+        }
+    }
+    encoder.finish()
+}
+
+// Escapes down the orders until a context has seen the symbol, returns the order it was found in.
+fn encode_symbol(model: &mut Model, encoder: &mut RangeEncoder, symbol: usize) -> Option<usize> {
+    model.start_symbol();
+    for order in (0..=model.top_order()).rev() {
+        let total = model.intervals(order);
+        if total == 0 {
+            continue;
+        }
+
+        match model
+            .intervals
+            .iter()
+            .find(|(x, _, _)| *x as usize == symbol)
+        {
+            Some((_, low, high)) => {
+                encoder.encode(*low, *high, total);
+                return Some(order);
+            }
+            None => {
+                let escape = model.intervals.last().map(|(_, _, high)| *high).unwrap();
+                encoder.encode(escape, total, total);
+                model.exclude_intervals();
+            }
+        }
+    }
+
+    let rank = model.remaining().position(|x| x == symbol).unwrap();
+    encoder.encode(rank, rank + 1, model.remaining().count());
+    None
+}
+
+pub fn decode(data: &[u8], options: &Options) -> Result<Vec<u8>> {
+    options.validate()?;
+    Ok(decode_with_model(data, Model::new(*options, MAX_NODES)))
+}
+
+fn decode_with_model(data: &[u8], mut model: Model) -> Vec<u8> {
+    let mut decoder = RangeDecoder::new(data);
+    let mut decoded_data = Vec::new();
+
+    loop {
+        let (symbol, found) = decode_symbol(&mut model, &mut decoder);
+        if symbol == EOF_SYMBOL {
+            break;
+        }
+
+        decoded_data.push(symbol as u8);
+        model.update(symbol as u8, found);
+    }
+    decoded_data
+}
+
+fn decode_symbol(model: &mut Model, decoder: &mut RangeDecoder) -> (usize, Option<usize>) {
+    model.start_symbol();
+    for order in (0..=model.top_order()).rev() {
+        let total = model.intervals(order);
+        if total == 0 {
+            continue;
+        }
+
+        let target = decoder.target(total);
+        match model.intervals.iter().find(|(_, _, high)| target < *high) {
+            Some((symbol, low, high)) => {
+                decoder.consume(*low, *high);
+                return (*symbol as usize, Some(order));
+            }
+            None => {
+                let escape = model.intervals.last().map(|(_, _, high)| *high).unwrap();
+                decoder.consume(escape, total);
+                model.exclude_intervals();
+            }
+        }
+    }
+
+    let target = decoder.target(model.remaining().count());
+    decoder.consume(target, target + 1);
+    (model.remaining().nth(target).unwrap(), None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::corpus;
+
+    #[test]
+    fn options_from_str_test() {
+        assert_eq!("ppmd".parse(), Ok(Options::default()));
+        assert_eq!(
+            "ppmc:2".parse(),
+            Ok(Options {
+                max_order: 2,
+                escape: Escape::C
+            })
+        );
+        assert!("ppmd:8".parse::<Options>().is_err());
+        assert!("ppmx:2".parse::<Options>().is_err());
+        assert!("ppmc:".parse::<Options>().is_err());
+    }
+
+    #[test]
+    fn reverse_test() {
+        for escape in [Escape::C, Escape::D] {
+            for max_order in [0, 1, 3, MAX_ORDER] {
+                let options = Options { max_order, escape };
+                let len = corpus::assert_reverses(
+                    |input| encode(input, &options).unwrap(),
+                    |encoded| decode(encoded, &options),
+                );
+                if max_order >= 3 {
+                    assert!(len < corpus::text().len() / 8);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exclusion_test() {
+        let mut model = Model::new(Options::default(), MAX_NODES);
+        for byte in b"ababa" {
+            model.update(*byte, None);
+        }
+
+        // after `a` only `b` was seen, so once that context escapes `b` is out of order 0
+        model.start_symbol();
+        model.intervals(1);
+        assert_eq!(
+            model.intervals.iter().map(|x| x.0).collect::<Vec<_>>(),
+            b"b"
+        );
+        model.exclude_intervals();
+        let total = model.intervals(0);
+        assert_eq!(
+            model.intervals.iter().map(|x| x.0).collect::<Vec<_>>(),
+            b"a"
+        );
+        // PPMD counts three `a`s as five halves, and half an escape for the one symbol
+        assert_eq!(total, 6);
+        model.exclude_intervals();
+        assert_eq!(model.remaining().filter(|x| *x < 256).count(), 254);
+    }
+
+    #[test]
+    fn validate_test() {
+        let options = Options {
+            max_order: MAX_ORDER + 1,
+            escape: Escape::C,
+        };
+        assert!(encode(b"abc", &options).is_err());
+        assert!(decode(&[], &options).is_err());
+    }
+
+    #[test]
+    fn node_budget_test() {
+        let input = corpus::noise(20_000, 4, 1);
+        let options = Options {
+            max_order: 4,
+            escape: Escape::D,
+        };
+
+        // the model starts over many times, the same way on both sides
+        let encoded = encode_with_model(&input, Model::new(options, 1000));
+        assert_eq!(
+            decode_with_model(&encoded, Model::new(options, 1000)),
+            input
+        );
+
+        let mut model = Model::new(options, 1000);
+        for byte in input.iter() {
+            model.update(*byte, None);
+            assert!(model.nodes <= 1000);
+            assert!(model.contexts.len() <= 1000);
+        }
+    }
+}
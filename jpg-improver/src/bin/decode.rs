@@ -1,3 +1,3 @@
 fn main() {
-    jpg_improver::utils::launch(jpg_improver::decode)
+    jpg_improver::utils::launch_decode(jpg_improver::decode)
 }
@@ -1,3 +1,3 @@
 fn main() {
-    jpg_improver::utils::launch(jpg_improver::encode)
+    jpg_improver::utils::launch(jpg_improver::encode_with_options)
 }
@@ -0,0 +1,159 @@
+// Probabilities are 12-bit, and mixed in the logistic domain, where stretch(p) = ln(p / (1 - p)) is
+// scaled to -2047..=2047 and squash is its inverse.
+const SQUASH: [i32; 33] = [
+    1, 2, 3, 6, 10, 16, 27, 45, 73, 120, 194, 310, 488, 747, 1101, 1546, 2047, 2549, 2994, 3348,
+    3607, 3785, 3901, 3975, 4022, 4050, 4068, 4079, 4085, 4089, 4092, 4093, 4094,
+];
+
+const STRETCH: [i16; 4096] = build_stretch();
+
+pub const fn squash(d: i32) -> i32 {
+    if d > 2047 {
+        return 4095;
+    }
+    if d < -2047 {
+        return 0;
+    }
+    let w = d & 127;
+    let i = ((d >> 7) + 16) as usize;
+    (SQUASH[i] * (128 - w) + SQUASH[i + 1] * w + 64) >> 7
+}
+
+pub fn stretch(p: i32) -> i32 {
+    STRETCH[p as usize] as i32
+}
+
+const fn build_stretch() -> [i16; 4096] {
+    let mut table = [0; 4096];
+    let mut p = 0;
+    let mut d = -2047;
+    while d <= 2047 {
+        let squashed = squash(d);
+        while p <= squashed {
+            table[p as usize] = d as i16;
+            p += 1;
+        }
+        d += 1;
+    }
+    while p < 4096 {
+        table[p as usize] = 2047;
+        p += 1;
+    }
+    table
+}
+
+// A single layer network that weighs the stretched predictions of the models, with a set of weights
+// for every context the caller selects.
+pub struct Mixer {
+    inputs: Vec<i32>,
+    // the number of inputs, which every set of weights has one for
+    size: usize,
+    weights: Vec<i32>,
+    selected: usize,
+    p: i32,
+}
+
+const LEARNING_RATE: i64 = 4;
+
+impl Mixer {
+    pub fn new(inputs: usize, contexts: usize) -> Mixer {
+        Mixer {
+            inputs: Vec::with_capacity(inputs),
+            size: inputs,
+            weights: vec![(1 << 16) / inputs as i32; inputs * contexts],
+            selected: 0,
+            p: 2048,
+        }
+    }
+
+    pub fn add(&mut self, input: i32) {
+        self.inputs.push(input);
+    }
+
+    pub fn mix(&mut self, context: usize) -> i32 {
+        self.selected = context * self.size;
+        let weights = &self.weights[self.selected..];
+        let dot: i64 = self
+            .inputs
+            .iter()
+            .zip(weights)
+            .map(|(input, weight)| *input as i64 * *weight as i64)
+            .sum();
+        self.p = squash((dot >> 16) as i32);
+        self.p
+    }
+
+    pub fn update(&mut self, bit: bool) {
+        let error = ((bit as i64) << 12) - self.p as i64;
+        let weights = &mut self.weights[self.selected..];
+        for (input, weight) in self.inputs.iter().zip(weights.iter_mut()) {
+            *weight += ((*input as i64 * error * LEARNING_RATE) >> 14) as i32;
+        }
+        self.inputs.clear();
+    }
+}
+
+// Adaptive probability map: refines a probability in a context by interpolating between 33
+// buckets over its stretched value, each learning the actual rate of ones.
+pub struct Apm {
+    table: Vec<i32>,
+    index: usize,
+    rate: u32,
+}
+
+impl Apm {
+    pub fn new(contexts: usize, rate: u32) -> Apm {
+        let buckets = (0..33).map(|i| squash((i - 16) * 128) * 16);
+        Apm {
+            table: buckets.cycle().take(contexts * 33).collect(),
+            index: 0,
+            rate,
+        }
+    }
+
+    pub fn refine(&mut self, p: i32, context: usize) -> i32 {
+        let s = stretch(p) + 2048;
+        let (low, w) = ((s >> 7) as usize, s & 127);
+        self.index = low + context * 33;
+        let p = (self.table[self.index] * (128 - w) + self.table[self.index + 1] * w) >> 11;
+        // the nearer bucket learns
+        self.index += (w >> 6) as usize;
+        p
+    }
+
+    pub fn update(&mut self, bit: bool) {
+        let target = (bit as i32) << 16;
+        self.table[self.index] += (target - self.table[self.index]) >> self.rate;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stretch_squash_test() {
+        assert_eq!(squash(0), 2047);
+        assert_eq!(stretch(2047), 0);
+        for d in (-1000..=1000).step_by(7) {
+            assert!((stretch(squash(d)) - d).abs() < 10);
+        }
+        assert!((1..4095).all(|p| squash(stretch(p)) <= p + 30 && squash(stretch(p)) + 30 >= p));
+    }
+
+    #[test]
+    fn mixer_learns_test() {
+        // the second input is right, the first one is noise
+        let mut mixer = Mixer::new(2, 1);
+        for i in 0..2000 {
+            let bit = i % 3 == 0;
+            mixer.add(if i % 2 == 0 { 1000 } else { -1000 });
+            mixer.add(if bit { 1000 } else { -1000 });
+            mixer.mix(0);
+            mixer.update(bit);
+        }
+        mixer.add(1000);
+        mixer.add(-1000);
+        assert!(mixer.mix(0) < 500);
+    }
+}
@@ -0,0 +1,181 @@
+mod mixer;
+mod model;
+
+use crate::arithmetic::{BitPredictor, RangeDecoder, RangeEncoder};
+use burrows_wheeler::result::Result;
+use mixer::{stretch, Apm, Mixer};
+use model::{MatchModel, StateMap};
+
+// A bitwise context mixing codec in the lpaq family: order-1 to order-6 and word models predict
+// every bit from hashed contexts, a match model from the longest recent repetition, a neural
+// mixer combines them, two adaptive probability maps refine the result and the range coder codes
+// the bit with it.
+
+const ORDERS: usize = 6;
+const CONTEXT_MODELS: usize = ORDERS + 1;
+// the models, the match model and a bias
+const INPUTS: usize = CONTEXT_MODELS + 2;
+
+const TABLE_BITS: u32 = 22;
+const MATCH_HASH_BITS: u32 = 20;
+// the mixer weighs the models by the bits of the byte so far and the match length
+const MATCH_LEN_CONTEXTS: usize = 16;
+const STATE_LIMIT: u32 = 127;
+
+struct Predictor {
+    tables: Vec<StateMap>,
+    // the context hashes of the current byte, and the slots they select for the current bit
+    hashes: [u64; CONTEXT_MODELS],
+    slots: [usize; CONTEXT_MODELS],
+    match_model: MatchModel,
+    mixer: Mixer,
+    order_0_apm: Apm,
+    order_1_apm: Apm,
+    // the bits of the current byte after a leading one, how many there are and the bytes before
+    c0: u32,
+    bits: u32,
+    history: u64,
+    word: u64,
+    p: i32,
+}
+
+impl Predictor {
+    fn new() -> Predictor {
+        let mut predictor = Predictor {
+            tables: (0..CONTEXT_MODELS)
+                .map(|_| StateMap::new(1 << TABLE_BITS, STATE_LIMIT))
+                .collect(),
+            hashes: [0; CONTEXT_MODELS],
+            slots: [0; CONTEXT_MODELS],
+            match_model: MatchModel::new(MATCH_HASH_BITS),
+            mixer: Mixer::new(INPUTS, 256 * MATCH_LEN_CONTEXTS),
+            order_0_apm: Apm::new(256, 7),
+            order_1_apm: Apm::new(256 * 256, 7),
+            c0: 1,
+            bits: 0,
+            history: 0,
+            word: 0,
+            p: 2048,
+        };
+        predictor.predict();
+        predictor
+    }
+
+    fn predict(&mut self) {
+        for (i, hash) in self.hashes.iter().enumerate() {
+            let slot = (hash ^ self.c0 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            self.slots[i] = (slot >> (64 - TABLE_BITS)) as usize;
+            self.mixer.add(stretch(self.tables[i].p(self.slots[i])));
+        }
+        self.mixer.add(self.match_model.predict(self.c0, self.bits));
+        self.mixer.add(256);
+
+        let match_len = self.match_model.len().min(MATCH_LEN_CONTEXTS - 1);
+        let p = self.mixer.mix(self.c0 as usize + 256 * match_len);
+        let c1 = (self.history & 0xFF) as usize;
+        let order_0 = self.order_0_apm.refine(p, self.c0 as usize);
+        let order_1 = self.order_1_apm.refine(p, self.c0 as usize | c1 << 8);
+        self.p = (p + order_0 + 2 * order_1 + 2) >> 2;
+    }
+
+    fn update_byte(&mut self, byte: u8) {
+        self.history = self.history << 8 | byte as u64;
+        self.match_model.update_byte(byte);
+
+        // words are case-insensitive runs of letters
+        if byte.is_ascii_alphabetic() {
+            self.word =
+                (self.word ^ byte.to_ascii_lowercase() as u64).wrapping_mul(0x100_0000_01B3);
+        } else {
+            self.word = 0;
+        }
+
+        for order in 1..=ORDERS {
+            let bytes = self.history & (u64::MAX >> (64 - 8 * order));
+            self.hashes[order - 1] = hash(bytes, order as u64);
+        }
+        self.hashes[ORDERS] = hash(self.word, ORDERS as u64 + 1);
+    }
+}
+
+impl BitPredictor for Predictor {
+    fn p(&mut self) -> u32 {
+        (self.p.clamp(1, 4095) as u32) << 4
+    }
+
+    fn update(&mut self, bit: bool) {
+        for (table, slot) in self.tables.iter_mut().zip(self.slots) {
+            table.update(slot, bit);
+        }
+        self.match_model.update(bit);
+        self.mixer.update(bit);
+        self.order_0_apm.update(bit);
+        self.order_1_apm.update(bit);
+
+        self.c0 = self.c0 << 1 | bit as u32;
+        self.bits += 1;
+        if self.bits == 8 {
+            self.update_byte((self.c0 & 0xFF) as u8);
+            self.c0 = 1;
+            self.bits = 0;
+        }
+        self.predict();
+    }
+}
+
+fn hash(value: u64, model: u64) -> u64 {
+    let hash = (value ^ model << 56).wrapping_mul(0xD6E8_FEB8_6659_FD93);
+    hash ^ hash >> 29
+}
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    RangeEncoder::new().encode_bytes(data, &mut Predictor::new())
+}
+
+pub fn decode(data: &[u8]) -> Result<Vec<u8>> {
+    RangeDecoder::decode_bytes(data, &mut Predictor::new())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::corpus;
+
+    #[test]
+    fn reverse_test() {
+        let len = corpus::assert_reverses(encode, decode);
+        assert!(len < corpus::text().len() / 8);
+        assert!(decode(&encode(&corpus::text())[..3]).is_err());
+    }
+
+    #[test]
+    fn match_model_gain_test() {
+        // noise has no statistics to learn, only its repetition can be predicted
+        let noise = corpus::noise(5000, 8, 7);
+        let repeated = [noise.clone(), noise.clone()].concat();
+        let (once, twice) = (encode(&noise).len(), encode(&repeated).len());
+        assert!(once > noise.len());
+        assert!(twice - once < noise.len() / 10);
+
+        // by then the match model follows the whole first copy
+        let mut predictor = Predictor::new();
+        for byte in repeated.iter() {
+            for i in (0..8).rev() {
+                predictor.update((byte >> i) & 1 == 1);
+            }
+        }
+        assert!(predictor.match_model.len() >= noise.len() - 10);
+    }
+
+    #[test]
+    fn predictor_learns_test() {
+        let mut predictor = Predictor::new();
+        for _ in 0..200 {
+            for bit in [false, true, true, false, false, false, false, true] {
+                predictor.update(bit);
+            }
+        }
+        // 'a' starts with a zero
+        assert!(predictor.p < 100);
+    }
+}
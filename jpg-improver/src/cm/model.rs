@@ -0,0 +1,183 @@
+use super::mixer::stretch;
+
+const DELTAS: [i64; 1024] = build_deltas();
+
+const fn build_deltas() -> [i64; 1024] {
+    let mut deltas = [0; 1024];
+    let mut i = 0;
+    while i < 1024 {
+        deltas[i] = 16384 / (i as i64 + i as i64 + 3);
+        i += 1;
+    }
+    deltas
+}
+
+// Maps a context to the probability that the next bit is a one. Each slot keeps a 22-bit
+// probability and a count of the bits it has seen, and moves by 1/(count + 1.5) towards every bit,
+// up to `limit`, after which it keeps adapting at that rate.
+pub struct StateMap {
+    slots: Vec<u32>,
+    limit: u32,
+}
+
+impl StateMap {
+    pub fn new(size: usize, limit: u32) -> StateMap {
+        StateMap {
+            slots: vec![1 << 31; size],
+            limit,
+        }
+    }
+
+    pub fn p(&self, index: usize) -> i32 {
+        (self.slots[index] >> 20) as i32
+    }
+
+    pub fn update(&mut self, index: usize, bit: bool) {
+        let slot = self.slots[index];
+        let (count, p) = (slot & 1023, (slot >> 10) as i64);
+        let slot = if count < self.limit {
+            slot + 1
+        } else {
+            slot & !1023 | self.limit
+        };
+        let delta = ((((bit as i64) << 22) - p) >> 3) * DELTAS[count as usize];
+        self.slots[index] = (slot as i64 + (delta & !1023)) as u32;
+    }
+}
+
+const MIN_MATCH: usize = 6;
+const MAX_MATCH: usize = 63;
+
+// Finds the last occurrence of the latest bytes and predicts that the byte after it comes next, the
+// more confidently the longer the match is.
+pub struct MatchModel {
+    history: Vec<u8>,
+    // the position after the last occurrence of every hash of `MIN_MATCH` bytes
+    positions: Vec<u32>,
+    hash_bits: u32,
+    pointer: usize,
+    len: usize,
+    predicted: Option<bool>,
+    states: StateMap,
+}
+
+impl MatchModel {
+    pub fn new(hash_bits: u32) -> MatchModel {
+        MatchModel {
+            history: Vec::new(),
+            positions: vec![0; 1 << hash_bits],
+            hash_bits,
+            pointer: 0,
+            len: 0,
+            predicted: None,
+            states: StateMap::new(MAX_MATCH + 1, 1023),
+        }
+    }
+
+    // The stretched prediction for the next bit of the byte started with `c0`, which has a leading
+    // one above the `bits` bits seen so far, or 0 without a match.
+    pub fn predict(&mut self, c0: u32, bits: u32) -> i32 {
+        self.predicted = None;
+        if self.len == 0 {
+            return 0;
+        }
+
+        let byte = self.history[self.pointer] as u32 | 256;
+        if byte >> (8 - bits) != c0 {
+            self.len = 0;
+            return 0;
+        }
+        let bit = (byte >> (7 - bits)) & 1 == 1;
+        self.predicted = Some(bit);
+
+        let p = stretch(self.states.p(self.state()));
+        if bit {
+            p
+        } else {
+            -p
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn state(&self) -> usize {
+        self.len.min(MAX_MATCH)
+    }
+
+    pub fn update(&mut self, bit: bool) {
+        if let Some(predicted) = self.predicted {
+            self.states.update(self.state(), predicted == bit);
+        }
+    }
+
+    pub fn update_byte(&mut self, byte: u8) {
+        if self.len > 0 && self.history[self.pointer] == byte {
+            self.len += 1;
+            self.pointer += 1;
+        } else {
+            self.len = 0;
+        }
+        self.history.push(byte);
+
+        let position = self.history.len();
+        if position < MIN_MATCH {
+            return;
+        }
+        let hash = self.history[position - MIN_MATCH..]
+            .iter()
+            .fold(0u32, |hash, x| (hash ^ *x as u32).wrapping_mul(0x2F0B_4A13))
+            >> (32 - self.hash_bits);
+
+        if self.len == 0 {
+            let candidate = self.positions[hash as usize] as usize;
+            if candidate > 0 {
+                self.len = (1..=candidate.min(MAX_MATCH))
+                    .take_while(|i| self.history[candidate - i] == self.history[position - i])
+                    .count();
+                if self.len < MIN_MATCH {
+                    self.len = 0;
+                }
+                self.pointer = candidate;
+            }
+        }
+        self.positions[hash as usize] = position as u32;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn state_map_test() {
+        let mut states = StateMap::new(2, 1023);
+        assert_eq!(states.p(0), 2048);
+        for _ in 0..100 {
+            states.update(0, true);
+            states.update(1, false);
+        }
+        assert!(states.p(0) > 4000);
+        assert!(states.p(1) < 100);
+    }
+
+    #[test]
+    fn match_model_test() {
+        let mut model = MatchModel::new(16);
+        for byte in b"abcdefgh-abcdefg" {
+            model.update_byte(*byte);
+        }
+
+        // predicts the bits of 'h'
+        let (mut c0, mut bits) = (1, 0);
+        for i in (0..8).rev() {
+            let bit = (b'h' >> i) & 1 == 1;
+            model.predict(c0, bits);
+            assert_eq!(model.predicted, Some(bit));
+            model.update(bit);
+            c0 = c0 << 1 | bit as u32;
+            bits += 1;
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::arithmetic::{ctw, ppm};
+use burrows_wheeler::gst::Stage;
+use burrows_wheeler::result::Error;
+use std::str::FromStr;
+
+// The arithmetic coder keeps order-0 statistics of the bytes, PPM ones conditioned on the bytes
+// before them, context tree weighting weighs every tree of contexts up to its depth and context
+// mixing predicts every bit with several models at once.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Coder {
+    #[default]
+    Arithmetic,
+    Ppm(ppm::Options),
+    Ctw(ctw::Options),
+    ContextMixing,
+}
+
+impl FromStr for Coder {
+    type Err = Error;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "arithmetic" => Ok(Coder::Arithmetic),
+            "cm" => Ok(Coder::ContextMixing),
+            _ if str.starts_with("ppm") => str.parse().map(Coder::Ppm),
+            _ if str.starts_with("ctw") => str.parse().map(Coder::Ctw),
+            _ => Err(Error::new("Unknown coder")),
+        }
+    }
+}
+
+// Raw input skips the transformation, the stage and run length encoding and goes to the coder as
+// it is.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    pub stage: Stage,
+    pub raw: bool,
+    pub coder: Coder,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coder_from_str_test() {
+        assert_eq!("arithmetic".parse(), Ok(Coder::Arithmetic));
+        assert_eq!("ppmd".parse(), Ok(Coder::Ppm(ppm::Options::default())));
+        assert!(matches!("ppmc:2".parse(), Ok(Coder::Ppm(_))));
+        assert_eq!("ctw:8".parse(), Ok(Coder::Ctw(ctw::Options { depth: 8 })));
+        assert_eq!("cm".parse(), Ok(Coder::ContextMixing));
+        assert!("ppmc:9".parse::<Coder>().is_err());
+        assert!("huffman".parse::<Coder>().is_err());
+    }
+}
@@ -0,0 +1,36 @@
+// Inputs the coders are tested on.
+use burrows_wheeler::result::Result;
+
+// Text that repeats with variations, followed by every byte value once.
+pub fn text() -> Vec<u8> {
+    let text = "the quick brown fox jumps over the lazy dog, the lazy dog sleeps. ".repeat(50);
+    text.bytes().chain((0..=255).rev()).collect()
+}
+
+// Bytes below 2^bits from a linear congruential generator, the same on every run.
+pub fn noise(len: usize, bits: u32, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> (32 - bits)) as u8
+        })
+        .collect()
+}
+
+// Checks that the text, noise, a single byte and nothing at all come back, and returns the size of
+// the encoded text.
+pub fn assert_reverses<E, D>(encode: E, decode: D) -> usize
+where
+    E: Fn(&[u8]) -> Vec<u8>,
+    D: Fn(&[u8]) -> Result<Vec<u8>>,
+{
+    for input in [noise(3000, 8, 1), vec![0x55], vec![]] {
+        assert_eq!(decode(&encode(&input)), Ok(input));
+    }
+
+    let text = text();
+    let encoded = encode(&text);
+    assert_eq!(decode(&encoded), Ok(text));
+    encoded.len()
+}
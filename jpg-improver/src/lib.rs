@@ -1,27 +1,90 @@
-use std::convert::TryInto;
+use arithmetic::{ctw, ppm};
+use burrows_wheeler::gst::{self, Stage};
+use burrows_wheeler::result::{Error, Result};
+use config::{Coder, Options};
 
 pub mod arithmetic;
+pub mod cm;
+pub mod config;
+#[cfg(test)]
+mod corpus;
 pub mod rle;
+mod stream;
 pub mod utils;
 
-pub fn encode(data: &[u8]) -> Vec<u8> {
+pub fn encode(data: &[u8]) -> Result<Vec<u8>> {
+    encode_with(data, &Stage::default())
+}
+
+pub fn encode_with(data: &[u8], stage: &Stage) -> Result<Vec<u8>> {
+    encode_with_options(
+        data,
+        &Options {
+            stage: stage.clone(),
+            ..Options::default()
+        },
+    )
+}
+
+pub fn encode_with_options(data: &[u8], options: &Options) -> Result<Vec<u8>> {
+    let transformed;
+    let data = if options.raw {
+        data
+    } else {
+        transformed = transform(data, &options.stage);
+        &transformed
+    };
+
+    let mut encoded = stream::encode_header(options);
+    encoded.extend(match &options.coder {
+        Coder::Arithmetic => arithmetic::encode(data),
+        Coder::Ppm(ppm_options) => ppm::encode(data, ppm_options)?,
+        Coder::Ctw(ctw_options) => ctw::encode(data, ctw_options)?,
+        Coder::ContextMixing => cm::encode(data),
+    });
+    Ok(encoded)
+}
+
+fn transform(data: &[u8], stage: &Stage) -> Vec<u8> {
     let (mut data, num) = burrows_wheeler::bwt::apply(data);
     let mut new_data = Vec::from(num.to_be_bytes());
     new_data.append(&mut data);
     let data = new_data;
-    let data = burrows_wheeler::mtf::apply(&data);
-    let data = rle::apply(&data);
-    arithmetic::encode(&data)
+    let data = gst::apply(&data, stage);
+    rle::apply(&data)
 }
 
-pub fn decode(data: &[u8]) -> Vec<u8> {
-    let data = arithmetic::decode(data);
-    let data = rle::reverse(&data);
-    let data = burrows_wheeler::mtf::reverse(&data);
-    burrows_wheeler::bwt::reverse(
-        &data[4..],
-        u32::from_be_bytes(data[..4].try_into().expect("Invalid data")),
-    )
+// The options are read from the stream.
+pub fn decode(data: &[u8]) -> Result<Vec<u8>> {
+    let mut bytes = data.iter();
+    let options = stream::decode_header(&mut (&mut bytes).copied())?;
+    let data = bytes.as_slice();
+
+    let data = match &options.coder {
+        Coder::Arithmetic => arithmetic::decode(data),
+        Coder::Ppm(ppm_options) => ppm::decode(data, ppm_options)?,
+        Coder::Ctw(ctw_options) => ctw::decode(data, ctw_options)?,
+        Coder::ContextMixing => cm::decode(data)?,
+    };
+
+    if options.raw {
+        Ok(data)
+    } else {
+        reverse_transform(&data, &options.stage)
+    }
+}
+
+fn reverse_transform(data: &[u8], stage: &Stage) -> Result<Vec<u8>> {
+    let data = rle::reverse(data)?;
+    let data = gst::reverse(&data, stage)?;
+    let (initial, data) = match data.split_first_chunk() {
+        Some((initial, data)) => (u32::from_be_bytes(*initial), data),
+        None => return Err(Error::new("Unexpected end of data")),
+    };
+    if initial as usize >= data.len().max(1) {
+        return Err(Error::new("Start row out of block"));
+    }
+    Ok(burrows_wheeler::bwt::reverse(data, initial))
 }
 
 #[cfg(test)]
@@ -34,7 +97,7 @@ mod test {
         let bytes = input.bytes().collect::<Vec<_>>();
 
         let encoded = arithmetic::encode(&rle::apply(&bytes));
-        let decoded = rle::reverse(&arithmetic::decode(&encoded));
+        let decoded = rle::reverse(&arithmetic::decode(&encoded)).unwrap();
         assert_eq!(bytes, decoded);
     }
 
@@ -42,20 +105,64 @@ mod test {
     fn reverse_simplies_test() {
         let input = "abcdef";
         let bytes = input.bytes().collect::<Vec<_>>();
-        assert_eq!(bytes, decode(&encode(&bytes)));
+        assert_eq!(decode(&encode(&bytes).unwrap()), Ok(bytes));
     }
 
     #[test]
     fn reverse_simplier_test() {
         let input = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
         let bytes = input.bytes().collect::<Vec<_>>();
-        assert_eq!(bytes, decode(&encode(&bytes)));
+        assert_eq!(decode(&encode(&bytes).unwrap()), Ok(bytes));
+    }
+
+    #[test]
+    fn reverse_stages_test() {
+        let input = String::from("aaaaaaaaaatttttqwojdkqwdoibbbbwjw");
+        let bytes = input.bytes().collect::<Vec<_>>();
+        for stage in ["mtf", "mtf1", "mtf2", "sticky", "wfc", "if", "dc"] {
+            let stage = stage.parse().unwrap();
+            assert_eq!(
+                Ok(bytes.clone()),
+                decode(&encode_with(&bytes, &stage).unwrap())
+            );
+        }
     }
 
     #[test]
     fn reverse_simple_test() {
         let input = String::from("aaaaaaaaaatttttqwojdkqwdoibbbbwjw");
         let bytes = input.bytes().collect::<Vec<_>>();
-        assert_eq!(bytes, decode(&encode(&bytes)));
+        assert_eq!(decode(&encode(&bytes).unwrap()), Ok(bytes));
+    }
+
+    #[test]
+    fn reverse_ppm_test() {
+        let input = String::from("aaaaaaaaaatttttqwojdkqwdoibbbbwjw");
+        let bytes = input.bytes().collect::<Vec<_>>();
+        for raw in [false, true] {
+            for coder in ["ppmc:2", "ppmd", "ctw", "cm"] {
+                let options = Options {
+                    raw,
+                    coder: coder.parse().unwrap(),
+                    ..Options::default()
+                };
+                let encoded = encode_with_options(&bytes, &options).unwrap();
+                assert_eq!(Ok(bytes.clone()), decode(&encoded));
+            }
+        }
+    }
+
+    #[test]
+    fn decode_invalid_test() {
+        let bytes = b"aaaaaaaaaatttttqwojdkqwdoibbbbwjw".to_vec();
+        let encoded = encode(&bytes).unwrap();
+        assert!(decode(&encoded[4..]).is_err());
+        assert!(decode(&encoded[..5]).is_err());
+
+        // a start row past the block
+        let data = rle::apply(&gst::apply(&[0, 0, 0, 9, b'a', b'b'], &Stage::default()));
+        let mut encoded = stream::encode_header(&Options::default());
+        encoded.extend(arithmetic::encode(&data));
+        assert!(decode(&encoded).is_err());
     }
 }
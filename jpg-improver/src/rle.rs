@@ -1,3 +1,5 @@
+use burrows_wheeler::result::{Error, Result};
+
 const MAX_SEQUENCE_SIZE: u8 = u8::MAX;
 
 pub fn apply(data: &[u8]) -> Vec<u8> {
@@ -33,7 +35,7 @@ pub fn apply(data: &[u8]) -> Vec<u8> {
     result
 }
 
-pub fn reverse(data: &[u8]) -> Vec<u8> {
+pub fn reverse(data: &[u8]) -> Result<Vec<u8>> {
     let mut result = Vec::new();
     let mut previous_byte = None;
 
@@ -43,7 +45,11 @@ pub fn reverse(data: &[u8]) -> Vec<u8> {
 
         previous_byte = if Some(data[i]) == previous_byte {
             i += 1;
-            let current_sequence = data[i];
+            let current_sequence = match data.get(i) {
+                Some(len) if *len >= 2 => *len,
+                Some(_) => return Err(Error::new("Invalid run length")),
+                None => return Err(Error::new("Unexpected end of runs")),
+            };
 
             for _ in 0..current_sequence - 2 {
                 result.push(data[i - 1])
@@ -55,7 +61,7 @@ pub fn reverse(data: &[u8]) -> Vec<u8> {
 
         i += 1;
     }
-    result
+    Ok(result)
 }
 
 #[cfg(test)]
@@ -71,27 +77,33 @@ mod test {
     #[test]
     fn reversing_test() {
         let input = vec![97, 97, 97];
-        println!("{:?}", reverse(&input));
+        println!("{:?}", reverse(&input).unwrap());
     }
 
     #[test]
     fn reverse_simpliest_test() {
         let input = "abcdef";
         let bytes = input.bytes().collect::<Vec<_>>();
-        assert_eq!(bytes, reverse(&apply(&bytes)));
+        assert_eq!(reverse(&apply(&bytes)), Ok(bytes));
     }
 
     #[test]
     fn reverse_simplier_test() {
         let input = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
         let bytes = input.bytes().collect::<Vec<_>>();
-        assert_eq!(bytes, reverse(&apply(&bytes)));
+        assert_eq!(reverse(&apply(&bytes)), Ok(bytes));
     }
 
     #[test]
     fn reverse_simple_test() {
         let input = String::from("aaaaaaaaaatttttqwojdkqwdoibbbbwjw");
         let bytes = input.bytes().collect::<Vec<_>>();
-        assert_eq!(bytes, reverse(&apply(&bytes)));
+        assert_eq!(reverse(&apply(&bytes)), Ok(bytes));
+    }
+
+    #[test]
+    fn reverse_invalid_test() {
+        assert!(reverse(&[97, 97]).is_err());
+        assert!(reverse(&[97, 97, 1]).is_err());
     }
 }
@@ -0,0 +1,136 @@
+use crate::arithmetic::ctw;
+use crate::arithmetic::ppm::{self, Escape};
+use crate::config::{Coder, Options};
+use burrows_wheeler::result::{Error, Result};
+use burrows_wheeler::stream::{decode_stage, encode_stage};
+
+const MAGIC: [u8; 4] = *b"JPGI";
+const RAW_FLAG: u8 = 1;
+
+const ARITHMETIC: u8 = 0;
+const PPM: u8 = 1;
+const CTW: u8 = 2;
+const CONTEXT_MIXING: u8 = 3;
+
+// The flags, the stage unless the input is raw and the coder with its parameters, so a stream
+// decodes the way it was encoded.
+pub fn encode_header(options: &Options) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(if options.raw { RAW_FLAG } else { 0 });
+    if !options.raw {
+        encode_stage(&mut header, &options.stage);
+    }
+
+    match options.coder {
+        Coder::Arithmetic => header.push(ARITHMETIC),
+        Coder::Ppm(ppm_options) => {
+            let escape = match ppm_options.escape {
+                Escape::C => 0,
+                Escape::D => 1,
+            };
+            header.extend_from_slice(&[PPM, ppm_options.max_order as u8, escape]);
+        }
+        Coder::Ctw(ctw_options) => header.extend_from_slice(&[CTW, ctw_options.depth as u8]),
+        Coder::ContextMixing => header.push(CONTEXT_MIXING),
+    }
+    header
+}
+
+pub fn decode_header(input_iter: &mut dyn Iterator<Item = u8>) -> Result<Options> {
+    if !input_iter.take(MAGIC.len()).eq(MAGIC) {
+        return Err(Error::new("Unknown stream format"));
+    }
+
+    let flags = next(input_iter)?;
+    if flags & !RAW_FLAG != 0 {
+        return Err(Error::new("Unknown stream flags"));
+    }
+    let raw = flags & RAW_FLAG != 0;
+    let stage = match raw {
+        true => Default::default(),
+        false => decode_stage(input_iter)?,
+    };
+
+    let coder = match next(input_iter)? {
+        ARITHMETIC => Coder::Arithmetic,
+        PPM => {
+            let max_order = next(input_iter)? as usize;
+            let escape = match next(input_iter)? {
+                0 => Escape::C,
+                1 => Escape::D,
+                _ => return Err(Error::new("Unknown PPM method")),
+            };
+            if max_order > ppm::MAX_ORDER {
+                return Err(Error::new("Invalid PPM order"));
+            }
+            Coder::Ppm(ppm::Options { max_order, escape })
+        }
+        CTW => match next(input_iter)? as usize {
+            depth if depth <= ctw::MAX_DEPTH => Coder::Ctw(ctw::Options { depth }),
+            _ => return Err(Error::new("Invalid CTW depth")),
+        },
+        CONTEXT_MIXING => Coder::ContextMixing,
+        _ => return Err(Error::new("Unknown coder")),
+    };
+
+    Ok(Options { stage, raw, coder })
+}
+
+fn next(input_iter: &mut dyn Iterator<Item = u8>) -> Result<u8> {
+    input_iter
+        .next()
+        .ok_or_else(|| Error::new("Unexpected end of stream header"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_encoded_test() {
+        for stage in ["mtf", "sticky:7", "wfc", "dc"] {
+            let options = Options {
+                stage: stage.parse().unwrap(),
+                ..Options::default()
+            };
+            let header = encode_header(&options);
+            assert_eq!(decode_header(&mut header.into_iter()), Ok(options));
+        }
+    }
+
+    #[test]
+    fn decode_encoded_coder_test() {
+        for coder in ["arithmetic", "ppmc:2", "ppmd:7", "ctw:0", "ctw:48", "cm"] {
+            for raw in [false, true] {
+                let options = Options {
+                    stage: "if".parse().unwrap(),
+                    raw,
+                    coder: coder.parse().unwrap(),
+                };
+                let header = encode_header(&options);
+                let decoded = decode_header(&mut header.into_iter()).unwrap();
+                assert_eq!((decoded.raw, decoded.coder), (raw, options.coder));
+                assert_eq!(decoded.stage == options.stage, !raw);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_unknown_test() {
+        assert!(decode_header(&mut b"HBWT".iter().copied()).is_err());
+        assert!(decode_header(&mut b"JPG".iter().copied()).is_err());
+        assert!(decode_header(&mut MAGIC.iter().copied()).is_err());
+
+        let invalid = [
+            [RAW_FLAG, PPM, 8, 0],
+            [RAW_FLAG, PPM, 5, 2],
+            [RAW_FLAG, CTW, 49, 0],
+        ];
+        for header in invalid {
+            let mut header_iter = MAGIC.iter().chain(header.iter()).copied();
+            assert!(decode_header(&mut header_iter).is_err());
+        }
+        let mut header_iter = MAGIC.iter().chain(&[2, ARITHMETIC]).copied();
+        assert!(decode_header(&mut header_iter).is_err());
+    }
+}
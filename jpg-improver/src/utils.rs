@@ -1,18 +1,60 @@
+use crate::config::Options;
+use burrows_wheeler::result::Result;
 use burrows_wheeler::utils;
-use std::io::Read;
+use std::io::{Error as IoError, Read, Result as IoResult};
 
 pub fn launch<F>(f: F)
 where
-    F: Fn(&[u8]) -> Vec<u8>,
+    F: Fn(&[u8], &Options) -> Result<Vec<u8>>,
 {
-    utils::launch(|output_file_path, read| {
-        let bytes = read
-            .bytes()
-            .take_while(|x| x.is_ok())
-            .map(|x| x.unwrap())
-            .collect::<Vec<_>>();
-
-        let transformed = f(&bytes);
-        utils::write_iter(output_file_path, Box::new(transformed.into_iter()))
-    });
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut options = Options::default();
+
+    if let Some(stage) = utils::take_option(&mut args, "--stage") {
+        match stage.parse() {
+            Ok(stage) => options.stage = stage,
+            Err(err) => {
+                println!("Failed: {}", err.message);
+                return;
+            }
+        }
+    }
+
+    if let Some(coder) = utils::take_option(&mut args, "--coder") {
+        match coder.parse() {
+            Ok(coder) => options.coder = coder,
+            Err(err) => {
+                println!("Failed: {}", err.message);
+                return;
+            }
+        }
+    }
+
+    options.raw = utils::take_flag(&mut args, "--raw");
+
+    utils::launch_with(
+        args,
+        "[--stage <mtf, mtf1, mtf2, sticky[:k], wfc, if or dc>] \
+         [--coder <arithmetic, ppmc[:order], ppmd[:order], ctw[:depth] or cm>] [--raw] ",
+        |output_file_path, read| transform_file(output_file_path, read, |bytes| f(bytes, &options)),
+    );
+}
+
+// Decoding takes no options, the stream records them.
+pub fn launch_decode<F>(f: F)
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>>,
+{
+    utils::launch(|output_file_path, read| transform_file(output_file_path, read, f))
+}
+
+fn transform_file<F>(output_file_path: &str, mut read: Box<dyn Read>, f: F) -> IoResult<()>
+where
+    F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+{
+    let mut bytes = Vec::new();
+    read.read_to_end(&mut bytes)?;
+
+    let transformed = f(&bytes).map_err(IoError::from)?;
+    utils::write_iter(output_file_path, Box::new(transformed.into_iter()))
 }